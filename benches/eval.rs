@@ -0,0 +1,75 @@
+#[macro_use]
+extern crate criterion;
+extern crate num_complex;
+extern crate plotany;
+
+use plotany::{expr, expr_parser};
+use criterion::{black_box, Criterion};
+use num_complex::Complex64;
+
+fn my_comp_expr_eval_bench(c: &mut Criterion) {
+    let mut e = expr_parser::ExprParser::new()
+        .parse("cos(1.5*x) + cos(y) - 1")
+        .unwrap()
+        .compile(&expr::Definitions::new())
+        .unwrap()
+        .bind2("x", "y");
+    // the old tree-walking evaluator took 80.9ns +/- 1.0
+    c.bench_function("my_comp_expr_eval", move |b| {
+        b.iter(|| e(black_box(2.0), black_box(3.0)))
+    });
+}
+
+fn my_comp_expr_eval_batch_bench(c: &mut Criterion) {
+    let e = expr_parser::ExprParser::new()
+        .parse("cos(1.5*x) + cos(y) - 1")
+        .unwrap()
+        .compile(&expr::Definitions::new())
+        .unwrap();
+    // one full 257x257 grid, as sampled by `marching_squares`
+    let n = 257 * 257;
+    let xs: Vec<_> = (0..n).map(|i| (i / 257) as f64 * 0.078 - 10.0).collect();
+    let ys: Vec<_> = (0..n).map(|i| (i % 257) as f64 * 0.078 - 10.0).collect();
+    let mut out = vec![0.0; n];
+    c.bench_function("my_comp_expr_eval_batch", move |b| {
+        b.iter(|| e.eval_batch("x", "y", black_box(&xs), black_box(&ys), &mut out))
+    });
+}
+
+fn my_comp_expr_eval_grad_batch_bench(c: &mut Criterion) {
+    let e = expr_parser::ExprParser::new()
+        .parse("cos(1.5*x) + cos(y) - 1")
+        .unwrap()
+        .compile(&expr::Definitions::new())
+        .unwrap()
+        .bind2_batch("x", "y");
+    let n = 257 * 257;
+    let xs: Vec<_> = (0..n).map(|i| (i / 257) as f64 * 0.078 - 10.0).collect();
+    let ys: Vec<_> = (0..n).map(|i| (i % 257) as f64 * 0.078 - 10.0).collect();
+    let mut out = vec![expr::Dual::constant(0.0); n];
+    c.bench_function("my_comp_expr_eval_grad_batch", move |b| {
+        b.iter(|| e.eval_grad_batch(black_box(&xs), black_box(&ys), &mut out))
+    });
+}
+
+fn complex_expr_eval_batch_bench(c: &mut Criterion) {
+    let e = expr_parser::ExprParser::new()
+        .parse("(z^3 - 1) / (z^2 + min(x, y))")
+        .unwrap()
+        .compile_complex(&expr::Definitions::new())
+        .unwrap();
+    // one row of a domain colouring
+    let zs: Vec<_> = (0..1000).map(|i| Complex64::new(i as f64 * 0.004 - 2.0, 0.5)).collect();
+    let mut out = vec![Complex64::new(0.0, 0.0); zs.len()];
+    // the old tree-walking evaluator took 145us
+    c.bench_function("complex_expr_eval_batch", move |b| {
+        b.iter(|| e.eval_batch(black_box(&zs), &mut out))
+    });
+}
+
+criterion_group!(benches,
+                 my_comp_expr_eval_bench,
+                 my_comp_expr_eval_batch_bench,
+                 my_comp_expr_eval_grad_batch_bench,
+                 complex_expr_eval_batch_bench);
+criterion_main!(benches);
@@ -0,0 +1,390 @@
+use fields;
+use marching_squares::{self, Point, Polyline};
+use serde_json;
+use session::Session;
+use std::borrow::Cow;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use zero_sets::{self, ZeroSets};
+use {compile_complex, compile_curve, compile_field_of_kind, trace_view, trajectory_label};
+
+/// What kind of file to export curves' geometry to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataFormat {
+    Csv,
+    Json,
+    GeoJson,
+}
+
+impl DataFormat {
+    /// Recognizes a format by its usual file extension
+    pub fn from_extension(ext: &str) -> Option<DataFormat> {
+        match &*ext.to_lowercase() {
+            "csv" => Some(DataFormat::Csv),
+            "json" => Some(DataFormat::Json),
+            "geojson" => Some(DataFormat::GeoJson),
+            _ => None,
+        }
+    }
+    /// Picks the format to export to from `path`'s extension
+    pub fn from_path(path: &Path) -> Result<DataFormat, String> {
+        path.extension().and_then(|ext| ext.to_str()).and_then(DataFormat::from_extension)
+            .ok_or_else(|| "the file name should end in .csv, .json or .geojson".to_owned())
+    }
+}
+
+/// A traced curve to be exported
+pub struct CurveData<'a> {
+    pub equation: &'a str,
+    pub polylines: &'a [Polyline],
+    /// points that stand on their own, such as the roots of a function
+    pub points: &'a [Point],
+}
+
+/// The zero sets of the real and imaginary parts of a function of `z`, and
+/// its roots, ready to export as three curves
+pub struct ZeroSetData {
+    labels: [String; 3],
+    re: Vec<Polyline>,
+    im: Vec<Polyline>,
+    roots: Vec<Point>,
+}
+
+impl ZeroSetData {
+    /// The zero sets `sets` of the function with source `function`
+    pub fn new(function: &str, sets: &ZeroSets) -> ZeroSetData {
+        ZeroSetData {
+            labels: [format!("Re({}) = 0", function),
+                     format!("Im({}) = 0", function),
+                     format!("roots of {}", function)],
+            re: marching_squares::polylines(&sets.re),
+            im: marching_squares::polylines(&sets.im),
+            roots: sets.roots.clone(),
+        }
+    }
+    pub fn curves<'a>(&'a self) -> Vec<CurveData<'a>> {
+        vec![CurveData { equation: &self.labels[0], polylines: &self.re, points: &[] },
+             CurveData { equation: &self.labels[1], polylines: &self.im, points: &[] },
+             CurveData { equation: &self.labels[2], polylines: &[], points: &self.roots }]
+    }
+}
+
+/// Writes the polylines and points of `curves`, in plot coordinates, to
+/// `path`. Curves are identified by their index in `curves`, and polylines
+/// by their index within their curve. Points that stand on their own have
+/// no polyline.
+pub fn export_data(path: &Path, format: DataFormat, curves: &[CurveData]) -> Result<(), Box<Error>> {
+    let mut file = BufWriter::new(File::create(path)?);
+    match format {
+        DataFormat::Csv => {
+            writeln!(file, "curve,equation,polyline,closed,point,x,y")?;
+            for (c, curve) in curves.iter().enumerate() {
+                let equation = csv_field(curve.equation);
+                for (l, line) in curve.polylines.iter().enumerate() {
+                    for (p, point) in line.points.iter().enumerate() {
+                        writeln!(file, "{},{},{},{},{},{},{}", c, equation, l, line.closed, p, point.0, point.1)?;
+                    }
+                }
+                for (p, point) in curve.points.iter().enumerate() {
+                    writeln!(file, "{},{},,,{},{},{}", c, equation, p, point.0, point.1)?;
+                }
+            }
+        }
+        DataFormat::Json => {
+            let curves: Vec<_> = curves.iter().enumerate().map(|(c, curve)| JsonCurve {
+                id: c,
+                equation: curve.equation,
+                polylines: curve.polylines.iter().enumerate().map(|(l, line)| JsonPolyline {
+                    id: l,
+                    closed: line.closed,
+                    points: &line.points,
+                }).collect(),
+                points: curve.points,
+            }).collect();
+            serde_json::to_writer_pretty(&mut file, &curves)?;
+        }
+        DataFormat::GeoJson => {
+            let mut features = Vec::new();
+            for (c, curve) in curves.iter().enumerate() {
+                for (l, line) in curve.polylines.iter().enumerate() {
+                    let mut coordinates = line.points.clone();
+                    // GeoJSON closes a line string by repeating its first point
+                    if line.closed {
+                        coordinates.push(line.points[0]);
+                    }
+                    features.push(Feature {
+                        kind: "Feature",
+                        geometry: Geometry { kind: "LineString", coordinates: coordinates },
+                        properties: Properties {
+                            curve: c,
+                            equation: curve.equation,
+                            polyline: Some(l),
+                            closed: Some(line.closed),
+                        },
+                    });
+                }
+                if !curve.points.is_empty() {
+                    features.push(Feature {
+                        kind: "Feature",
+                        geometry: Geometry { kind: "MultiPoint", coordinates: curve.points.to_vec() },
+                        properties: Properties {
+                            curve: c,
+                            equation: curve.equation,
+                            polyline: None,
+                            closed: None,
+                        },
+                    });
+                }
+            }
+            let collection = FeatureCollection { kind: "FeatureCollection", features: features };
+            serde_json::to_writer_pretty(&mut file, &collection)?;
+        }
+    }
+    Ok(file.flush()?)
+}
+
+/// `field` quoted for CSV if it has to be
+fn csv_field<'a>(field: &'a str) -> Cow<'a, str> {
+    if field.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(field)
+    }
+}
+
+/// Traces the curves of `session`, and the trajectories of its field, over
+/// its view and writes their geometry to `path`. If its first equation is a
+/// function of `z`, that's traced as its zero sets and roots, and there's
+/// nothing to export if those aren't wanted.
+pub fn export_session(session: &Session, path: &Path, format: DataFormat) -> Result<(), Box<Error>> {
+    let (xr, yr) = (&session.x_range, &session.y_range);
+    let mut zeros = None;
+    let mut equations = &session.curves[..];
+    if session.complex || session.zero_sets {
+        let function = match session.curves.first() {
+            Some(curve) if session.zero_sets => &curve.equation,
+            _ => return Err("the session only colours the plane by a function of `z`, so there are no curves to export".into()),
+        };
+        let f = compile_complex(function, &session.definitions).map_err(|e| format!("`{}`: {}", function, e))?;
+        zeros = Some(ZeroSetData::new(function, &zero_sets::trace_view(&f, xr, yr)));
+        equations = &session.curves[1..];
+    }
+    let mut traced = Vec::new();
+    for curve in equations {
+        let eq = compile_curve(&curve.equation, &session.definitions)
+            .map_err(|e| format!("`{}`: {}", curve.equation, e))?;
+        traced.push(trace_view(&eq, xr, yr));
+    }
+    let mut curves = zeros.as_ref().map_or_else(Vec::new, ZeroSetData::curves);
+    curves.extend(equations.iter().zip(&traced).map(|(curve, lines)| CurveData {
+        equation: &curve.equation,
+        polylines: lines,
+        points: &[],
+    }));
+    let mut trajectories = Vec::new();
+    if let Some(ref saved) = session.field {
+        let field = compile_field_of_kind(saved.kind, &saved.p, &saved.q, &session.definitions)
+            .map_err(|e| format!("the field: {}", e))?;
+        for &start in &saved.trajectories {
+            let lines = vec![fields::trajectory(&field, start, xr, yr)];
+            trajectories.push((trajectory_label(saved.kind, &saved.p, &saved.q, start), lines));
+        }
+    }
+    curves.extend(trajectories.iter().map(|&(ref label, ref lines)| CurveData {
+        equation: label,
+        polylines: lines,
+        points: &[],
+    }));
+    export_data(path, format, &curves)
+}
+
+#[derive(Serialize)]
+struct JsonCurve<'a> {
+    id: usize,
+    equation: &'a str,
+    polylines: Vec<JsonPolyline<'a>>,
+    #[serde(skip_serializing_if = "no_points")]
+    points: &'a [Point],
+}
+
+fn no_points(points: &&[Point]) -> bool {
+    points.is_empty()
+}
+
+#[derive(Serialize)]
+struct JsonPolyline<'a> {
+    id: usize,
+    closed: bool,
+    points: &'a [Point],
+}
+
+#[derive(Serialize)]
+struct FeatureCollection<'a> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    features: Vec<Feature<'a>>,
+}
+
+#[derive(Serialize)]
+struct Feature<'a> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    geometry: Geometry,
+    properties: Properties<'a>,
+}
+
+/// A line string or, for points on their own, a multipoint, both of which
+/// are just a list of points
+#[derive(Serialize)]
+struct Geometry {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    coordinates: Vec<Point>,
+}
+
+#[derive(Serialize)]
+struct Properties<'a> {
+    curve: usize,
+    equation: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    polyline: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    closed: Option<bool>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+    use session::SavedCurve;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    /// Exports `curves` to a scratch file in `format`, and reads it back
+    fn exported(name: &str, format: DataFormat, curves: &[CurveData]) -> String {
+        let path = env::temp_dir().join(format!("plotany-{}-{}", name, process::id()));
+        let result = export_data(&path, format, curves);
+        let text = fs::read_to_string(&path);
+        fs::remove_file(&path).unwrap();
+        result.unwrap();
+        text.unwrap()
+    }
+
+    fn square() -> Vec<Polyline> {
+        vec![Polyline { points: vec![(0.0, 0.0), (1.0, 0.0), (1.0, 1.5)], closed: true },
+             Polyline { points: vec![(-2.0, 0.25), (-1.0, 0.5)], closed: false }]
+    }
+
+    #[test]
+    fn formats_come_from_extensions() {
+        assert_eq!(DataFormat::from_path(Path::new("a/b.CSV")), Ok(DataFormat::Csv));
+        assert_eq!(DataFormat::from_path(Path::new("b.json")), Ok(DataFormat::Json));
+        assert_eq!(DataFormat::from_path(Path::new("b.geojson")), Ok(DataFormat::GeoJson));
+        assert!(DataFormat::from_path(Path::new("b.txt")).is_err());
+        assert!(DataFormat::from_path(Path::new("b")).is_err());
+    }
+
+    #[test]
+    fn csv_has_a_row_per_point() {
+        let lines = square();
+        let curves = [CurveData { equation: "y = max(x, 1)", polylines: &lines, points: &[] },
+                      CurveData { equation: "roots of \"f\"", polylines: &[], points: &[(3.0, -0.5)] }];
+        assert_eq!(exported("csv", DataFormat::Csv, &curves),
+                   "curve,equation,polyline,closed,point,x,y\n\
+                    0,\"y = max(x, 1)\",0,true,0,0,0\n\
+                    0,\"y = max(x, 1)\",0,true,1,1,0\n\
+                    0,\"y = max(x, 1)\",0,true,2,1,1.5\n\
+                    0,\"y = max(x, 1)\",1,false,0,-2,0.25\n\
+                    0,\"y = max(x, 1)\",1,false,1,-1,0.5\n\
+                    1,\"roots of \"\"f\"\"\",,,0,3,-0.5\n");
+    }
+
+    #[test]
+    fn json_keeps_polylines_and_points_apart() {
+        let lines = square();
+        let curves = [CurveData { equation: "y = x", polylines: &lines, points: &[] },
+                      CurveData { equation: "roots of z", polylines: &[], points: &[(0.0, 0.0)] }];
+        let json: Value = serde_json::from_str(&exported("json", DataFormat::Json, &curves)).unwrap();
+        let expected = r#"[
+            {"id": 0, "equation": "y = x", "polylines": [
+                {"id": 0, "closed": true, "points": [[0.0, 0.0], [1.0, 0.0], [1.0, 1.5]]},
+                {"id": 1, "closed": false, "points": [[-2.0, 0.25], [-1.0, 0.5]]}
+            ]},
+            {"id": 1, "equation": "roots of z", "polylines": [], "points": [[0.0, 0.0]]}
+        ]"#;
+        assert_eq!(json, serde_json::from_str::<Value>(expected).unwrap());
+    }
+
+    #[test]
+    fn geojson_closes_rings_and_gathers_points() {
+        let lines = square();
+        let curves = [CurveData { equation: "y = x", polylines: &lines[..1], points: &[(2.0, 1.0), (3.0, 1.0)] }];
+        let json: Value = serde_json::from_str(&exported("geojson", DataFormat::GeoJson, &curves)).unwrap();
+        let expected = r#"{"type": "FeatureCollection", "features": [
+            {"type": "Feature",
+             "geometry": {"type": "LineString", "coordinates": [[0.0, 0.0], [1.0, 0.0], [1.0, 1.5], [0.0, 0.0]]},
+             "properties": {"curve": 0, "equation": "y = x", "polyline": 0, "closed": true}},
+            {"type": "Feature",
+             "geometry": {"type": "MultiPoint", "coordinates": [[2.0, 1.0], [3.0, 1.0]]},
+             "properties": {"curve": 0, "equation": "y = x"}}
+        ]}"#;
+        assert_eq!(json, serde_json::from_str::<Value>(expected).unwrap());
+    }
+
+    fn session(equations: &[&str], complex: bool, zero_sets: bool) -> Session {
+        Session {
+            curves: equations.iter().map(|e| SavedCurve { equation: e.to_string(), style: Default::default() }).collect(),
+            definitions: String::new(),
+            x_range: -2.0..2.0,
+            y_range: -2.0..2.0,
+            live: false,
+            complex: complex,
+            zero_sets: zero_sets,
+            field: None,
+        }
+    }
+
+    fn export_json(name: &str, session: &Session) -> Result<Value, String> {
+        let path = env::temp_dir().join(format!("plotany-{}-{}.json", name, process::id()));
+        let result = export_session(session, &path, DataFormat::Json).map_err(|e| e.to_string());
+        let text = fs::read_to_string(&path);
+        let _ = fs::remove_file(&path);
+        result.map(|_| serde_json::from_str(&text.unwrap()).unwrap())
+    }
+
+    #[test]
+    fn sessions_are_traced_over_their_view() {
+        let json = export_json("session", &session(&["x^2 + y^2 = 1"], false, false)).unwrap();
+        assert_eq!(json[0]["equation"], "x^2 + y^2 = 1");
+        let lines = json[0]["polylines"].as_array().unwrap();
+        assert!(!lines.is_empty());
+        for line in lines {
+            for p in line["points"].as_array().unwrap() {
+                let (x, y) = (p[0].as_f64().unwrap(), p[1].as_f64().unwrap());
+                assert!((x.hypot(y) - 1.0).abs() < 1e-3, "({}, {}) is off the circle", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn zero_sets_are_exported_with_the_roots() {
+        let json = export_json("zeros", &session(&["z^2 - 1", "y = x"], true, true)).unwrap();
+        let equations: Vec<_> = json.as_array().unwrap().iter().map(|c| c["equation"].clone()).collect();
+        assert_eq!(equations, ["Re(z^2 - 1) = 0", "Im(z^2 - 1) = 0", "roots of z^2 - 1", "y = x"]);
+        let mut roots: Vec<_> = json[2]["points"].as_array().unwrap().iter()
+            .map(|p| (p[0].as_f64().unwrap(), p[1].as_f64().unwrap()))
+            .collect();
+        roots.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(roots.len(), 2, "found {:?}", roots);
+        for (&(x, y), &ex) in roots.iter().zip(&[-1.0, 1.0]) {
+            assert!((x - ex).abs() < 1e-9 && y.abs() < 1e-9, "found ({}, {})", x, y);
+        }
+
+        assert_eq!(export_json("colours", &session(&["z^2 - 1"], true, false)),
+                   Err("the session only colours the plane by a function of `z`, so there are no curves to export"
+                       .to_owned()));
+    }
+}
@@ -0,0 +1,133 @@
+#[cfg(feature = "render")]
+use cairo;
+use expr::ComplexExpr;
+#[cfg(feature = "render")]
+use marching_squares;
+use num_complex::Complex64;
+use std::f64::consts;
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+/// Evaluates `f` at the middle of each of `width` by `height` pixels over
+/// `xr` by `yr`, row by row from the top. Gives up, returning `None`, once
+/// `cancelled` is set.
+pub fn sample(f: &ComplexExpr,
+              xr: &Range<f64>,
+              width: usize,
+              yr: &Range<f64>,
+              height: usize,
+              cancelled: &AtomicBool)
+              -> Option<Vec<Complex64>> {
+    let mut values = vec![Complex64::new(0.0, 0.0); width * height];
+    if values.is_empty() {
+        return Some(values);
+    }
+    // split the rows into one band per core
+    let threads = thread::available_parallelism().map_or(1, |n| n.get());
+    let band = (height + threads - 1) / threads;
+    thread::scope(|s| {
+        for (b, rows) in values.chunks_mut(band * width).enumerate() {
+            s.spawn(move || {
+                let mut zs = Vec::with_capacity(width);
+                for (r, row) in rows.chunks_mut(width).enumerate() {
+                    if cancelled.load(Ordering::Relaxed) {
+                        return;
+                    }
+                    let j = b * band + r;
+                    let y = yr.end - (yr.end - yr.start) * (j as f64 + 0.5) / height as f64;
+                    zs.clear();
+                    zs.extend((0..width).map(|i| {
+                        Complex64::new(xr.start + (xr.end - xr.start) * (i as f64 + 0.5) / width as f64, y)
+                    }));
+                    f.eval_batch(&zs, row);
+                }
+            });
+        }
+    });
+    if cancelled.load(Ordering::Relaxed) {
+        return None;
+    }
+    Some(values)
+}
+
+/// The colours of `values`, as pixels in cairo's 24-bit RGB format
+pub fn pixels(values: &[Complex64]) -> Vec<u32> {
+    let channel = |c: f64| (c * 255.0).round().max(0.0).min(255.0) as u32;
+    values.iter().map(|&w| {
+        let (r, g, b) = colour(w);
+        channel(r) << 16 | channel(g) << 8 | channel(b)
+    }).collect()
+}
+
+/// The colour of `w`, as red, green and blue from 0 to 1. The hue follows
+/// its argument, starting from red for positive reals, and the brightness
+/// its modulus, from black at zeros through full colour at 1 to white at
+/// poles. Undefined values are grey.
+pub fn colour(w: Complex64) -> (f64, f64, f64) {
+    if w.is_nan() {
+        return (0.5, 0.5, 0.5);
+    }
+    let hue = (w.arg() / (2.0 * consts::PI)).rem_euclid(1.0);
+    let lightness = w.norm().atan() * 2.0 / consts::PI;
+    // HSL with full saturation
+    let chroma = 1.0 - (2.0 * lightness - 1.0).abs();
+    let channel = |offset: f64| {
+        let k = (offset + hue * 12.0) % 12.0;
+        lightness - chroma / 2.0 * (k - 3.0).min(9.0 - k).min(1.0).max(-1.0)
+    };
+    (channel(0.0), channel(8.0), channel(4.0))
+}
+
+/// A `width` by `height` image of `pixels`, row by row from the top
+#[cfg(feature = "render")]
+pub fn image(pixels: &[u32], width: usize, height: usize) -> Result<cairo::ImageSurface, cairo::Error> {
+    let mut image = cairo::ImageSurface::create(cairo::Format::Rgb24, width as i32, height as i32)?;
+    let stride = image.stride() as usize;
+    {
+        let mut data = image.data().expect("nothing else refers to a new image");
+        for (j, row) in pixels.chunks(width).enumerate() {
+            for (i, pixel) in row.iter().enumerate() {
+                let at = j * stride + 4 * i;
+                data[at..at + 4].copy_from_slice(&pixel.to_ne_bytes());
+            }
+        }
+    }
+    Ok(image)
+}
+
+/// Paints `image`, a colouring of `image_xr` by `image_yr`, where that lies
+/// in a plot of `xr` by `yr` filling the clip region
+#[cfg(feature = "render")]
+pub fn paint(ctx: &cairo::Context,
+             image: &cairo::ImageSurface,
+             image_xr: &Range<f64>,
+             image_yr: &Range<f64>,
+             xr: &Range<f64>,
+             yr: &Range<f64>)
+             -> Result<(), cairo::Error> {
+    ctx.save()?;
+    marching_squares::to_plot(ctx, xr, yr);
+    // the image's top left corner, with its rows going down
+    ctx.translate(image_xr.start, image_yr.end);
+    ctx.scale((image_xr.end - image_xr.start) / f64::from(image.width()),
+              (image_yr.start - image_yr.end) / f64::from(image.height()));
+    ctx.set_source_surface(image, 0.0, 0.0)?;
+    ctx.paint()?;
+    ctx.restore()
+}
+
+/// Paints the whole clip region with the colours of `f` over `xr` by `yr`,
+/// at one sample per device pixel
+#[cfg(feature = "render")]
+pub fn draw(ctx: &cairo::Context, f: &ComplexExpr, xr: &Range<f64>, yr: &Range<f64>) -> Result<(), cairo::Error> {
+    let (ox, oy, ex, ey) = ctx.clip_extents()?;
+    let (dw, dh) = ctx.user_to_device_distance(ex - ox, ey - oy)?;
+    let (width, height) = (dw.abs().ceil() as usize, dh.abs().ceil() as usize);
+    if width == 0 || height == 0 {
+        return Ok(());
+    }
+    let never = AtomicBool::new(false);
+    let values = sample(f, xr, width, yr, height, &never).expect("never cancelled");
+    paint(ctx, &image(&pixels(&values), width, height)?, xr, yr, xr, yr)
+}
@@ -0,0 +1,135 @@
+use cairo;
+use domain_colouring;
+use expr::ComplexExpr;
+use fields;
+use intersections;
+use marching_squares::{self, Point, Polyline, Segment, Style};
+use std::error::Error;
+use std::fs::File;
+use std::ops::Range;
+use std::path::Path;
+use zero_sets::{self, ZeroSets};
+
+/// What kind of file to export a plot to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Png,
+    Svg,
+    Pdf,
+}
+
+impl Format {
+    /// Recognizes a format by its usual file extension
+    pub fn from_extension(ext: &str) -> Option<Format> {
+        match &*ext.to_lowercase() {
+            "png" => Some(Format::Png),
+            "svg" => Some(Format::Svg),
+            "pdf" => Some(Format::Pdf),
+            _ => None,
+        }
+    }
+    pub fn extension(&self) -> &'static str {
+        match *self {
+            Format::Png => "png",
+            Format::Svg => "svg",
+            Format::Pdf => "pdf",
+        }
+    }
+}
+
+/// How big a plot to export, and to what kind of file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Options {
+    pub format: Format,
+    /// the size in pixels, as on screen
+    pub width: f64,
+    pub height: f64,
+    /// the resolution of a PNG, where 96 DPI is one image pixel per pixel
+    pub dpi: f64,
+}
+
+/// What's drawn in a plot over `x_range` by `y_range` besides the axes,
+/// from the bottom up
+pub struct Layers<'a> {
+    pub x_range: Range<f64>,
+    pub y_range: Range<f64>,
+    /// the function to colour the plane by, if it's coloured
+    pub colouring: Option<&'a ComplexExpr>,
+    /// the field's glyphs, if there's a field
+    pub glyphs: Option<&'a fields::Glyphs>,
+    pub trajectories: &'a [Polyline],
+    /// the curve's segments, and how it's drawn
+    pub segments: &'a [Segment],
+    pub style: &'a Style,
+    /// the segments of the curve to intersect it with
+    pub other_segments: &'a [Segment],
+    pub zeros: Option<&'a ZeroSets>,
+    /// where the two curves meet
+    pub meeting_points: &'a [Point],
+}
+
+/// Renders a `width` by `height` pixel plot of `layers` into a new image at
+/// `dpi`, where 96 DPI is one image pixel per pixel
+pub fn render_image(layers: &Layers, width: f64, height: f64, dpi: f64) -> Result<cairo::ImageSurface, cairo::Error> {
+    let scale = dpi / 96.0;
+    let surface = cairo::ImageSurface::create(cairo::Format::ARgb32,
+                                              (width * scale).round() as i32,
+                                              (height * scale).round() as i32)?;
+    let ctx = cairo::Context::new(&surface)?;
+    ctx.scale(scale, scale);
+    draw(&ctx, layers)?;
+    drop(ctx);
+    Ok(surface)
+}
+
+/// Writes a plot of `layers` to `path` as `options` says. Vector formats
+/// keep the curves as paths, and ignore the DPI.
+pub fn export(path: &Path, layers: &Layers, options: &Options) -> Result<(), Box<Error>> {
+    let (width, height) = (options.width, options.height);
+    // vector surfaces measure in points, of which there are 72 per inch
+    let points = 72.0 / 96.0;
+    match options.format {
+        Format::Png => {
+            let surface = render_image(layers, width, height, options.dpi)?;
+            surface.write_to_png(&mut File::create(path)?)?;
+        }
+        Format::Svg => {
+            let surface = cairo::SvgSurface::new(width * points, height * points, Some(path))?;
+            draw_scaled(&surface, points, layers)?;
+            surface.finish();
+        }
+        Format::Pdf => {
+            let surface = cairo::PdfSurface::new(width * points, height * points, path)?;
+            draw_scaled(&surface, points, layers)?;
+            surface.finish();
+        }
+    }
+    Ok(())
+}
+
+fn draw_scaled(surface: &cairo::Surface, scale: f64, layers: &Layers) -> Result<(), cairo::Error> {
+    let ctx = cairo::Context::new(surface)?;
+    ctx.scale(scale, scale);
+    draw(&ctx, layers)
+}
+
+/// Draws the axes, then `layers` from the bottom up, the way they're drawn
+/// on screen
+fn draw(ctx: &cairo::Context, layers: &Layers) -> Result<(), cairo::Error> {
+    let (xr, yr) = (&layers.x_range, &layers.y_range);
+    marching_squares::draw(ctx, &[], layers.style, xr, yr);
+    if let Some(f) = layers.colouring {
+        domain_colouring::draw(ctx, f, xr, yr)?;
+    }
+    if let Some(glyphs) = layers.glyphs {
+        fields::draw(ctx, glyphs, xr, yr)?;
+    }
+    let steps: Vec<_> = layers.trajectories.iter().flat_map(Polyline::segments).collect();
+    marching_squares::stroke(ctx, &steps, &fields::TRAJECTORY_STYLE, xr, yr);
+    marching_squares::stroke(ctx, layers.segments, layers.style, xr, yr);
+    marching_squares::stroke(ctx, layers.other_segments, &intersections::OTHER_STYLE, xr, yr);
+    if let Some(sets) = layers.zeros {
+        zero_sets::draw(ctx, sets, xr, yr)?;
+    }
+    intersections::draw(ctx, layers.meeting_points, xr, yr)
+}
@@ -1,16 +1,29 @@
 use std::error;
+use std::f64::consts;
 use std::fmt;
 use std::hash::BuildHasher;
 use std::fmt::{Debug, Display, Formatter};
 use std::collections::HashMap;
+use std::mem;
+use std::ops::{Add, Div, Mul, Sub};
 
 use fnv::FnvHashMap;
+use num_complex::Complex64;
 
 pub struct Equation(pub Expr, pub Expr);
 
+/// An equation that is only plotted where all of its restrictions hold, as
+/// in `x^2 + y^2 = 1 {x > 0, y < 0.5}`
 #[derive(Debug)]
+pub struct Curve {
+    pub eqn: Equation,
+    pub restrictions: Vec<Expr>,
+}
+
+#[derive(Debug, Clone)]
 pub struct Expr(BaseExpr<String>);
 
+#[derive(Clone)]
 enum BaseExpr<V> {
     Add(Box<BaseExpr<V>>, Box<BaseExpr<V>>),
     Sub(Box<BaseExpr<V>>, Box<BaseExpr<V>>),
@@ -18,17 +31,130 @@ enum BaseExpr<V> {
     Div(Box<BaseExpr<V>>, Box<BaseExpr<V>>),
     Pow(Box<BaseExpr<V>>, Box<BaseExpr<V>>),
     Func(KnownFunc, Box<BaseExpr<V>>),
+    Call(MultiFunc, Vec<BaseExpr<V>>),
+    /// a call to a user definition; these are inlined by `Expr::compile`
+    Apply(String, Vec<BaseExpr<V>>),
+    // truth values are 1.0 and 0.0, and NaN means "undefined"
+    Cmp(Comparison, Box<BaseExpr<V>>, Box<BaseExpr<V>>),
+    And(Box<BaseExpr<V>>, Box<BaseExpr<V>>),
+    Or(Box<BaseExpr<V>>, Box<BaseExpr<V>>),
+    Not(Box<BaseExpr<V>>),
+    /// `If(c, a, b)` is `a` where `c` holds and `b` elsewhere
+    If(Box<BaseExpr<V>>, Box<BaseExpr<V>>, Box<BaseExpr<V>>),
     Lit(f64),
     Var(V),
+    /// the imaginary unit, which is undefined when evaluating over the reals
+    Imag,
+}
+
+/// A user definition such as `r = sqrt(x^2 + y^2)` or `f(t) = t^3 - t`
+#[derive(Debug)]
+pub struct Definition {
+    pub name: String,
+    pub params: Vec<String>,
+    pub body: Expr,
 }
 
-/// invariant: all BaseExpr::Var(usize) in `expr` and values in `varmap`
-/// are valid indices into `varstore`
+/// The symbol table of user definitions, in the order they were given
+#[derive(Debug, Default)]
+pub struct Definitions(Vec<Definition>);
+
+/// An expression lowered to straight-line code over a register file.
+///
+/// invariant: all registers named in `code`, `args` and `result`, and all
+/// values in `varmap`, are valid indices into `regs`
 #[derive(Debug)]
 pub struct CompiledExpr {
-    expr: BaseExpr<usize>,
+    code: Vec<Instr>,
+    /// operands of `Op::Call`s
+    args: Vec<usize>,
+    /// the variables come first, then constants and intermediate results
+    regs: Vec<f64>,
+    result: usize,
+    varmap: FnvHashMap<String, usize>,
+}
+
+/// A `CompiledExpr` with two of its variables bound to the coordinates of
+/// each point it is evaluated at. Evaluating it never mutates it, so it can
+/// be shared between threads.
+#[derive(Debug)]
+pub struct Bound2 {
+    expr: CompiledExpr,
+    v1: Option<usize>,
+    v2: Option<usize>,
+}
+
+/// An expression lowered to the same code as a `CompiledExpr`, to be
+/// evaluated over the complex numbers at points `z = x + i y`. Evaluating it
+/// never mutates it, so it can be shared between threads.
+///
+/// invariant: as for `CompiledExpr`, and `z`, `x` and `y` are the registers
+/// of those variables, if they're used
+#[derive(Debug)]
+pub struct ComplexExpr {
+    code: Vec<Instr>,
+    args: Vec<usize>,
+    /// the variables come first, then constants and intermediate results
+    regs: Vec<Complex64>,
+    result: usize,
     varmap: FnvHashMap<String, usize>,
-    varstore: Vec<f64>,
+    z: Option<usize>,
+    x: Option<usize>,
+    y: Option<usize>,
+}
+
+/// Registers for evaluating a compiled expression at one point at a time,
+/// kept between calls so that each point doesn't need its own allocation
+#[derive(Debug)]
+pub struct Scratch<R = f64> {
+    regs: Vec<R>,
+    /// gathers the arguments of `Op::Call`s
+    args: Vec<R>,
+}
+
+/// A value together with its partial derivatives with respect to the two
+/// variables of a `Bound2`, for forward-mode automatic differentiation
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Dual {
+    pub v: f64,
+    pub dx: f64,
+    pub dy: f64,
+}
+
+/// The closed range of reals from `lo` to `hi`, which is empty if `lo > hi`
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Interval {
+    pub lo: f64,
+    pub hi: f64,
+}
+
+/// How many points `CompiledExpr::eval_batch` works on at a time
+const LANES: usize = 256;
+
+/// Computes `op` and stores it in register `dst`
+#[derive(Debug, Copy, Clone)]
+struct Instr {
+    op: Op,
+    dst: usize,
+}
+
+/// Operands are register numbers
+#[derive(Debug, Copy, Clone)]
+enum Op {
+    Add(usize, usize),
+    Sub(usize, usize),
+    Mul(usize, usize),
+    Div(usize, usize),
+    Pow(usize, usize),
+    Func(KnownFunc, usize),
+    /// the operands are `args[start..start + len]`
+    Call(MultiFunc, usize, usize),
+    Cmp(Comparison, usize, usize),
+    And(usize, usize),
+    Or(usize, usize),
+    Not(usize),
+    /// both branches are always computed, so there are no jumps
+    Select(usize, usize, usize),
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -50,11 +176,133 @@ pub enum KnownFunc {
     ArcSecant,
     ArcCosecant,
     ArcCotangent,
+    SquareRoot,
+    CubeRoot,
+    CommonLogarithm,
+    BinaryLogarithm,
+    HyperbolicSine,
+    HyperbolicCosine,
+    HyperbolicTangent,
+    InverseHyperbolicSine,
+    InverseHyperbolicCosine,
+    InverseHyperbolicTangent,
+    Sign,
+    Round,
+    Truncate,
+    Gamma,
+    Digamma,
+    ErrorFunction,
+    Sinc,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum MultiFunc {
+    ArcTangent2,
+    Minimum,
+    Maximum,
+    Hypotenuse,
+    Modulo,
+    Logarithm,
+    Root,
+    Clamp,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum Comparison {
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
+
+/// A built-in function was called with the wrong number of arguments
+#[derive(Debug, Copy, Clone)]
+pub struct ArityError {
+    pub name: &'static str,
+    /// the minimum and (if any) maximum number of arguments
+    pub arity: (usize, Option<usize>),
+    pub got: usize,
+}
+
+/// The functions of one argument, which the parser looks up by `name`
+pub const KNOWN_FUNCS: &[KnownFunc] = &[
+    KnownFunc::AbsoluteValue,
+    KnownFunc::Floor,
+    KnownFunc::Ceiling,
+    KnownFunc::Exponential,
+    KnownFunc::NaturalLogarithm,
+    KnownFunc::Sine,
+    KnownFunc::Cosine,
+    KnownFunc::Tangent,
+    KnownFunc::Secant,
+    KnownFunc::Cosecant,
+    KnownFunc::Cotangent,
+    KnownFunc::ArcSine,
+    KnownFunc::ArcCosine,
+    KnownFunc::ArcTangent,
+    KnownFunc::ArcSecant,
+    KnownFunc::ArcCosecant,
+    KnownFunc::ArcCotangent,
+    KnownFunc::SquareRoot,
+    KnownFunc::CubeRoot,
+    KnownFunc::CommonLogarithm,
+    KnownFunc::BinaryLogarithm,
+    KnownFunc::HyperbolicSine,
+    KnownFunc::HyperbolicCosine,
+    KnownFunc::HyperbolicTangent,
+    KnownFunc::InverseHyperbolicSine,
+    KnownFunc::InverseHyperbolicCosine,
+    KnownFunc::InverseHyperbolicTangent,
+    KnownFunc::Sign,
+    KnownFunc::Round,
+    KnownFunc::Truncate,
+    KnownFunc::Gamma,
+    KnownFunc::Digamma,
+    KnownFunc::ErrorFunction,
+    KnownFunc::Sinc,
+];
+
+/// The functions of several arguments, which the parser looks up by `name`
+pub const MULTI_FUNCS: &[MultiFunc] = &[
+    MultiFunc::ArcTangent2,
+    MultiFunc::Minimum,
+    MultiFunc::Maximum,
+    MultiFunc::Hypotenuse,
+    MultiFunc::Modulo,
+    MultiFunc::Logarithm,
+    MultiFunc::Root,
+    MultiFunc::Clamp,
+];
+
+/// Named constants understood by the parser.
+///
+/// These are folded into literals while parsing, so they never show up as
+/// free variables of an expression.
+pub const CONSTANTS: &[(&str, f64)] = &[
+    ("pi", consts::PI),
+    ("π", consts::PI),
+    ("tau", consts::TAU),
+    ("τ", consts::TAU),
+    ("e", consts::E),
+    ("phi", 1.618_033_988_749_895),
+];
+
+pub fn constant(name: &str) -> Option<f64> {
+    CONSTANTS.iter().find(|&&(n, _)| n == name).map(|&(_, v)| v)
+}
+
+/// Whether `name` is a constant or a function that the parser knows
+pub fn is_builtin(name: &str) -> bool {
+    name == "if" || constant(name).is_some() || KnownFunc::from_name(name).is_some() ||
+    MultiFunc::from_name(name).is_some()
 }
 
 // do constant-folding at construction time
 pub mod helper {
     use expr::*;
+
+    // things like `ln(-1)` and `1 / 0` are left unevaluated, so checked
+    // evaluation can say where they are
     pub fn add(e1: Expr, e2: Expr) -> Expr {
         match (e1.0, e2.0) {
             (BaseExpr::Lit(l1), BaseExpr::Lit(l2)) => Expr(BaseExpr::Lit(l1 + l2)),
@@ -75,13 +323,15 @@ pub mod helper {
     }
     pub fn div(e1: Expr, e2: Expr) -> Expr {
         match (e1.0, e2.0) {
-            (BaseExpr::Lit(l1), BaseExpr::Lit(l2)) => Expr(BaseExpr::Lit(l1 / l2)),
+            (BaseExpr::Lit(l1), BaseExpr::Lit(l2)) if l2 != 0.0 => Expr(BaseExpr::Lit(l1 / l2)),
             (e1, e2) => Expr(BaseExpr::Div(Box::new(e1), Box::new(e2))),
         }
     }
     pub fn pow(e1: Expr, e2: Expr) -> Expr {
         match (e1.0, e2.0) {
-            (BaseExpr::Lit(l1), BaseExpr::Lit(l2)) => Expr(BaseExpr::Lit(l1.powf(l2))),
+            (BaseExpr::Lit(l1), BaseExpr::Lit(l2)) if pow_defined(l1, l2) => {
+                Expr(BaseExpr::Lit(l1.powf(l2)))
+            }
             (e1, e2) => Expr(BaseExpr::Pow(Box::new(e1), Box::new(e2))),
         }
     }
@@ -90,21 +340,198 @@ pub mod helper {
     }
     pub fn func(f: KnownFunc, e: Expr) -> Expr {
         match e.0 {
-            BaseExpr::Lit(v) => Expr(BaseExpr::Lit(f.eval(v))),
+            BaseExpr::Lit(v) if f.defined_at(v) => Expr(BaseExpr::Lit(f.eval(v))),
             e => Expr(BaseExpr::Func(f, Box::new(e))),
         }
     }
+    pub fn call(f: MultiFunc, es: Vec<Expr>) -> Result<Expr, ArityError> {
+        ArityError::check(f.name(), f.arity(), es.len())?;
+        let es: Vec<_> = es.into_iter().map(|e| e.0).collect();
+        let lits: Option<Vec<_>> = es.iter()
+            .map(|e| if let BaseExpr::Lit(v) = *e { Some(v) } else { None })
+            .collect();
+        Ok(Expr(match lits {
+            Some(ref vs) if f.defined_at(vs) => BaseExpr::Lit(f.eval(vs)),
+            _ => BaseExpr::Call(f, es),
+        }))
+    }
+    pub fn cmp(c: Comparison, e1: Expr, e2: Expr) -> Expr {
+        match (e1.0, e2.0) {
+            (BaseExpr::Lit(l1), BaseExpr::Lit(l2)) => Expr(BaseExpr::Lit(c.eval(l1, l2))),
+            (e1, e2) => Expr(BaseExpr::Cmp(c, Box::new(e1), Box::new(e2))),
+        }
+    }
+    pub fn and(e1: Expr, e2: Expr) -> Expr {
+        match (e1.0, e2.0) {
+            (BaseExpr::Lit(l1), BaseExpr::Lit(l2)) => Expr(BaseExpr::Lit(logic_and(l1, l2))),
+            (e1, e2) => Expr(BaseExpr::And(Box::new(e1), Box::new(e2))),
+        }
+    }
+    pub fn or(e1: Expr, e2: Expr) -> Expr {
+        match (e1.0, e2.0) {
+            (BaseExpr::Lit(l1), BaseExpr::Lit(l2)) => Expr(BaseExpr::Lit(logic_or(l1, l2))),
+            (e1, e2) => Expr(BaseExpr::Or(Box::new(e1), Box::new(e2))),
+        }
+    }
+    pub fn not(e: Expr) -> Expr {
+        match e.0 {
+            BaseExpr::Lit(v) => Expr(BaseExpr::Lit(logic_not(v))),
+            e => Expr(BaseExpr::Not(Box::new(e))),
+        }
+    }
+    /// `if(c, a, b)`; leaving out `b` makes the expression undefined
+    /// wherever `c` is false
+    pub fn cond(c: Expr, e1: Expr, e2: Option<Expr>) -> Expr {
+        let e2 = e2.unwrap_or_else(|| lit(::std::f64::NAN));
+        match c.0 {
+            BaseExpr::Lit(v) if v.is_nan() => lit(v),
+            BaseExpr::Lit(v) => if v != 0.0 { e1 } else { e2 },
+            c => Expr(BaseExpr::If(Box::new(c), Box::new(e1.0), Box::new(e2.0))),
+        }
+    }
     pub fn lit(v: f64) -> Expr {
         Expr(BaseExpr::Lit(v))
     }
     pub fn var<S: Into<String>>(v: S) -> Expr {
         Expr(BaseExpr::Var(v.into()))
     }
+    pub fn apply<S: Into<String>>(f: S, es: Vec<Expr>) -> Expr {
+        Expr(BaseExpr::Apply(f.into(), es.into_iter().map(|e| e.0).collect()))
+    }
+    /// A constant if there's one called `name`, and a variable otherwise
+    pub fn named(name: &str) -> Expr {
+        constant(name).map_or_else(|| var(name), lit)
+    }
+    /// A call to the built-in function called `f` if there is one, and to a
+    /// definition otherwise
+    pub fn call_named(f: &str, mut es: Vec<Expr>) -> Result<Expr, ArityError> {
+        if let Some(f) = KnownFunc::from_name(f) {
+            ArityError::check(f.name(), (1, Some(1)), es.len())?;
+            Ok(func(f, es.remove(0)))
+        } else if let Some(f) = MultiFunc::from_name(f) {
+            call(f, es)
+        } else if f == "if" {
+            ArityError::check("if", (2, Some(3)), es.len())?;
+            let e2 = if es.len() == 3 { es.pop() } else { None };
+            let e1 = es.pop().unwrap();
+            Ok(cond(es.pop().unwrap(), e1, e2))
+        } else {
+            Ok(apply(f, es))
+        }
+    }
+}
+
+// like `helper`, but also drops identities such as `x * 1` and `x + 0`.
+// these assume everything is finite, so `0 * x` is `0` even though `x` might
+// be infinite or undefined
+mod simple {
+    use expr::*;
+    use expr::helper as eh;
+
+    fn lit_of(e: &Expr) -> Option<f64> {
+        if let BaseExpr::Lit(v) = e.0 { Some(v) } else { None }
+    }
+
+    /// `Ok(e)` if `n` is `neg(e)`, and `Err(n)` otherwise
+    fn negated(n: Expr) -> Result<Expr, Expr> {
+        match n.0 {
+            BaseExpr::Mul(a, b) => match *b {
+                BaseExpr::Lit(l) if l == -1.0 => Ok(Expr(*a)),
+                b => Err(Expr(BaseExpr::Mul(a, Box::new(b)))),
+            },
+            n => Err(Expr(n)),
+        }
+    }
+
+    pub fn add(e1: Expr, e2: Expr) -> Expr {
+        match (lit_of(&e1), lit_of(&e2)) {
+            (Some(l), _) if l == 0.0 => e2,
+            (_, Some(l)) if l == 0.0 => e1,
+            _ => match negated(e2) {
+                Ok(e2) => eh::sub(e1, e2),
+                Err(e2) => eh::add(e1, e2),
+            },
+        }
+    }
+    pub fn sub(e1: Expr, e2: Expr) -> Expr {
+        match (lit_of(&e1), lit_of(&e2)) {
+            (_, Some(l)) if l == 0.0 => e1,
+            (Some(l), _) if l == 0.0 => neg(e2),
+            _ => match negated(e2) {
+                Ok(e2) => eh::add(e1, e2),
+                Err(e2) => eh::sub(e1, e2),
+            },
+        }
+    }
+    pub fn mul(e1: Expr, e2: Expr) -> Expr {
+        match (lit_of(&e1), lit_of(&e2)) {
+            (Some(l), _) if l == 0.0 => e1,
+            (_, Some(l)) if l == 0.0 => e2,
+            (Some(l), _) if l == 1.0 => e2,
+            (_, Some(l)) if l == 1.0 => e1,
+            // keep a single coefficient, on the right like `neg` puts it
+            (Some(l), None) => mul(e2, eh::lit(l)),
+            (None, Some(l2)) => match e1.0 {
+                BaseExpr::Mul(a, b) => match *b {
+                    BaseExpr::Lit(l1) => mul(Expr(*a), eh::lit(l1 * l2)),
+                    b => eh::mul(Expr(BaseExpr::Mul(a, Box::new(b))), e2),
+                },
+                e1 => eh::mul(Expr(e1), e2),
+            },
+            _ => eh::mul(e1, e2),
+        }
+    }
+    pub fn div(e1: Expr, e2: Expr) -> Expr {
+        match (lit_of(&e1), lit_of(&e2)) {
+            (Some(l), _) if l == 0.0 => e1,
+            (_, Some(l)) if l == 1.0 => e1,
+            _ => eh::div(e1, e2),
+        }
+    }
+    pub fn pow(e1: Expr, e2: Expr) -> Expr {
+        match (lit_of(&e1), lit_of(&e2)) {
+            (_, Some(l)) if l == 0.0 => eh::lit(1.0),
+            (_, Some(l)) if l == 1.0 => e1,
+            (Some(l), _) if l == 1.0 => e1,
+            _ => eh::pow(e1, e2),
+        }
+    }
+    pub fn neg(e: Expr) -> Expr {
+        mul(e, eh::lit(-1.0))
+    }
 }
 
 #[derive(Debug)]
 pub enum EvalError<'a> {
     UnknownVar(&'a str),
+    UnknownFunc(&'a str),
+    /// `expr` was applied outside its domain, to `args`, or if there are no
+    /// `args`, is a constant that isn't real
+    Undefined { expr: String, args: Vec<f64> },
+    /// the divisor of `expr` was zero
+    DivideByZero(String),
+}
+
+/// An expression couldn't be differentiated
+#[derive(Debug)]
+pub enum DerivativeError {
+    /// a function whose derivative isn't known
+    NoRule(KnownFunc),
+    UnknownFunc(String),
+}
+
+#[derive(Debug)]
+pub enum DefinitionError {
+    Duplicate(String),
+    /// the chain of definitions that refer back to the first one
+    Cycle(Vec<String>),
+    Arity { name: String, expected: usize, got: usize },
+    UnknownFunc(String),
+    /// a definition of one of the plot's coordinates
+    Reserved(String),
+    /// a definition that would never be used, because the parser always
+    /// takes the name to mean a constant or function of its own
+    Builtin(String),
 }
 
 impl Equation {
@@ -122,9 +549,63 @@ impl Equation {
     }
 }
 
+impl Curve {
+    /// Splits into the difference of the two sides of the equation and the
+    /// conjunction of the restrictions
+    pub fn split(self) -> (Expr, Expr) {
+        let domain = self.restrictions.into_iter().fold(helper::lit(1.0), helper::and);
+        (self.eqn.to_diff(), domain)
+    }
+}
+
 impl Expr {
     pub fn eval<S: BuildHasher>(&self, env: &HashMap<&str, f64, S>) -> Result<f64, EvalError> {
-        self.0.eval(env)
+        self.0.eval0(env, false)
+    }
+    /// Like `eval`, but reports the first subexpression that's applied
+    /// outside its domain, like `ln(-1)` or `1 / 0`, instead of carrying on
+    /// with NaN or infinity. Undefined values that come from elsewhere, like
+    /// an `if` without an `else`, are still just NaN.
+    pub fn eval_checked<S: BuildHasher>(&self, env: &HashMap<&str, f64, S>) -> Result<f64, EvalError> {
+        self.0.eval0(env, true)
+    }
+
+    /// Folds constants and drops identities like `x * 1` and `x ^ 1`,
+    /// assuming nothing is infinite or undefined
+    pub fn simplify(self) -> Expr {
+        use self::BaseExpr::*;
+        let s = |e: Box<BaseExpr<String>>| Expr(*e).simplify();
+        match self.0 {
+            Add(a, b) => simple::add(s(a), s(b)),
+            Sub(a, b) => simple::sub(s(a), s(b)),
+            Mul(a, b) => simple::mul(s(a), s(b)),
+            Div(a, b) => simple::div(s(a), s(b)),
+            Pow(a, b) => simple::pow(s(a), s(b)),
+            Func(f, a) => helper::func(f, s(a)),
+            Call(f, es) => {
+                let es = es.into_iter().map(|e| Expr(e).simplify()).collect();
+                helper::call(f, es).expect("arity was checked when the call was made")
+            }
+            Apply(f, es) => helper::apply(f, es.into_iter().map(|e| Expr(e).simplify()).collect()),
+            Cmp(c, a, b) => helper::cmp(c, s(a), s(b)),
+            And(a, b) => helper::and(s(a), s(b)),
+            Or(a, b) => helper::or(s(a), s(b)),
+            Not(a) => helper::not(s(a)),
+            If(c, a, b) => helper::cond(s(c), s(a), Some(s(b))),
+            e @ Lit(_) | e @ Var(_) | e @ Imag => Expr(e),
+        }
+    }
+
+    /// The derivative with respect to `var`, already simplified. Functions
+    /// that are piecewise constant, like `floor` and comparisons, have a
+    /// derivative of zero.
+    pub fn derivative(&self, var: &str) -> Result<Expr, DerivativeError> {
+        self.0.derivative(var)
+    }
+
+    /// Writes `self` out as LaTeX
+    pub fn to_latex(&self) -> String {
+        Latex(&self.0).to_string()
     }
     // pub fn vars(&self) -> HashMap<&str, f64> {
     //     use self::BaseExpr::*;
@@ -148,17 +629,63 @@ impl Expr {
     //     }
     // }
 
-    pub fn compile(self) -> CompiledExpr {
+    /// Inlines every use of `defs`
+    pub fn expand(&self, defs: &Definitions) -> Result<Expr, DefinitionError> {
+        Ok(Expr(defs.inline(&self.0, &FnvHashMap::default(), &mut Vec::new())?))
+    }
+
+    /// Inlines every use of `defs`, ready to evaluate over the complex
+    /// numbers. Any `i` left over, that isn't a definition or a parameter,
+    /// is the imaginary unit.
+    pub fn compile_complex(self, defs: &Definitions) -> Result<ComplexExpr, DefinitionError> {
+        let e = self.expand(defs)?;
+        let unit = [("i", BaseExpr::Imag)].iter().cloned().collect();
+        let e = Expr(Definitions::new().inline(&e.0, &unit, &mut Vec::new())?);
         let mut map = FnvHashMap::default();
-        let mut store = Vec::new();
-        let e = self.vecvar0(&mut map, &mut store);
+        let mut vars = Vec::new();
+        let e = e.vecvar0(&mut map, &mut vars);
+        // variables other than `z`, `x` and `y` are undefined
+        let nan = ::std::f64::NAN;
+        let mut regs = vec![Complex64::new(nan, nan); vars.len()];
+        let mut code = Vec::new();
+        let mut args = Vec::new();
+        let result = e.lower(&mut code, &mut args, &mut regs);
         map.shrink_to_fit();
-        store.shrink_to_fit();
-        CompiledExpr {
-            expr: e,
+        code.shrink_to_fit();
+        args.shrink_to_fit();
+        regs.shrink_to_fit();
+        Ok(ComplexExpr {
+            code: code,
+            args: args,
+            regs: regs,
+            result: result,
+            z: map.get("z").cloned(),
+            x: map.get("x").cloned(),
+            y: map.get("y").cloned(),
             varmap: map,
-            varstore: store,
-        }
+        })
+    }
+
+    /// Inlines every use of `defs` and compiles the result
+    pub fn compile(self, defs: &Definitions) -> Result<CompiledExpr, DefinitionError> {
+        let e = self.expand(defs)?;
+        let mut map = FnvHashMap::default();
+        let mut regs = Vec::new();
+        let e = e.vecvar0(&mut map, &mut regs);
+        let mut code = Vec::new();
+        let mut args = Vec::new();
+        let result = e.lower(&mut code, &mut args, &mut regs);
+        map.shrink_to_fit();
+        code.shrink_to_fit();
+        args.shrink_to_fit();
+        regs.shrink_to_fit();
+        Ok(CompiledExpr {
+            code: code,
+            args: args,
+            regs: regs,
+            result: result,
+            varmap: map,
+        })
     }
 
     fn vecvar0(self,
@@ -196,7 +723,38 @@ impl Expr {
                 let a = Expr(*a).vecvar0(varmap, varstore);
                 Func(f, Box::new(a))
             }
+            Call(f, es) => {
+                let es = es.into_iter().map(|e| Expr(e).vecvar0(varmap, varstore)).collect();
+                Call(f, es)
+            }
+            Apply(f, _) => unreachable!("call to `{}` was not inlined", f),
+            Cmp(c, a, b) => {
+                let a = Expr(*a).vecvar0(varmap, varstore);
+                let b = Expr(*b).vecvar0(varmap, varstore);
+                Cmp(c, Box::new(a), Box::new(b))
+            }
+            And(a, b) => {
+                let a = Expr(*a).vecvar0(varmap, varstore);
+                let b = Expr(*b).vecvar0(varmap, varstore);
+                And(Box::new(a), Box::new(b))
+            }
+            Or(a, b) => {
+                let a = Expr(*a).vecvar0(varmap, varstore);
+                let b = Expr(*b).vecvar0(varmap, varstore);
+                Or(Box::new(a), Box::new(b))
+            }
+            Not(a) => {
+                let a = Expr(*a).vecvar0(varmap, varstore);
+                Not(Box::new(a))
+            }
+            If(c, a, b) => {
+                let c = Expr(*c).vecvar0(varmap, varstore);
+                let a = Expr(*a).vecvar0(varmap, varstore);
+                let b = Expr(*b).vecvar0(varmap, varstore);
+                If(Box::new(c), Box::new(a), Box::new(b))
+            }
             Lit(l) => Lit(l),
+            Imag => Imag,
             Var(a) => {
                 use std::collections::hash_map::Entry;
                 Var(match varmap.entry(a) {
@@ -213,112 +771,1901 @@ impl Expr {
     }
 }
 
+/// What registers hold: real numbers when compiling for `CompiledExpr`, or
+/// complex ones for `ComplexExpr`
+trait Register: Copy {
+    fn real(v: f64) -> Self;
+    /// the imaginary unit, which is undefined over the reals
+    fn imag() -> Self;
+}
+
+impl Register for f64 {
+    fn real(v: f64) -> f64 {
+        v
+    }
+    fn imag() -> f64 {
+        ::std::f64::NAN
+    }
+}
+
+impl Register for Complex64 {
+    fn real(v: f64) -> Complex64 {
+        real(v)
+    }
+    fn imag() -> Complex64 {
+        Complex64::i()
+    }
+}
+
 impl BaseExpr<usize> {
-    fn eval(&self, env: &[f64]) -> f64 {
+    /// Appends code computing `self` and returns the register holding the
+    /// result. Constants are stored straight into `regs`.
+    fn lower<R: Register>(&self, code: &mut Vec<Instr>, args: &mut Vec<usize>, regs: &mut Vec<R>) -> usize {
         use self::BaseExpr::*;
-        match *self {
-            Add(ref a, ref b) => a.eval(env) + b.eval(env),
-            Sub(ref a, ref b) => a.eval(env) - b.eval(env),
-            Mul(ref a, ref b) => a.eval(env) * b.eval(env),
-            Div(ref a, ref b) => a.eval(env) / b.eval(env),
-            Pow(ref a, ref b) => a.eval(env).powf(b.eval(env)),
-            Func(ref f, ref a) => f.eval(a.eval(env)),
-            Lit(a) => a,
-            Var(a) => env[a],
+        let op = match *self {
+            Add(ref a, ref b) => Op::Add(a.lower(code, args, regs), b.lower(code, args, regs)),
+            Sub(ref a, ref b) => Op::Sub(a.lower(code, args, regs), b.lower(code, args, regs)),
+            Mul(ref a, ref b) => Op::Mul(a.lower(code, args, regs), b.lower(code, args, regs)),
+            Div(ref a, ref b) => Op::Div(a.lower(code, args, regs), b.lower(code, args, regs)),
+            Pow(ref a, ref b) => Op::Pow(a.lower(code, args, regs), b.lower(code, args, regs)),
+            Func(f, ref a) => Op::Func(f, a.lower(code, args, regs)),
+            Call(f, ref es) => {
+                let es: Vec<_> = es.iter().map(|e| e.lower(code, args, regs)).collect();
+                let start = args.len();
+                args.extend(es);
+                Op::Call(f, start, args.len() - start)
+            }
+            Apply(..) => unreachable!(),
+            Cmp(c, ref a, ref b) => Op::Cmp(c, a.lower(code, args, regs), b.lower(code, args, regs)),
+            And(ref a, ref b) => Op::And(a.lower(code, args, regs), b.lower(code, args, regs)),
+            Or(ref a, ref b) => Op::Or(a.lower(code, args, regs), b.lower(code, args, regs)),
+            Not(ref a) => Op::Not(a.lower(code, args, regs)),
+            If(ref c, ref a, ref b) => {
+                let c = c.lower(code, args, regs);
+                let a = a.lower(code, args, regs);
+                let b = b.lower(code, args, regs);
+                Op::Select(c, a, b)
+            }
+            Lit(a) => {
+                regs.push(R::real(a));
+                return regs.len() - 1;
+            }
+            Var(a) => return a,
+            Imag => {
+                regs.push(R::imag());
+                return regs.len() - 1;
+            }
+        };
+        regs.push(R::real(0.0));
+        code.push(Instr { op: op, dst: regs.len() - 1 });
+        regs.len() - 1
+    }
+}
+
+impl ComplexExpr {
+    pub fn vars(&self) -> Vec<&str> {
+        self.varmap.keys().map(|s| s.as_str()).collect()
+    }
+    /// Evaluates at `z`, with `x` and `y` set to its real and imaginary
+    /// parts. Any other variables are undefined.
+    pub fn eval(&self, z: Complex64) -> Complex64 {
+        self.eval_with(&mut Scratch::new(), z)
+    }
+    /// Like `eval`, but reuses `scratch` rather than allocating registers
+    pub fn eval_with(&self, scratch: &mut Scratch<Complex64>, z: Complex64) -> Complex64 {
+        let (regs, args) = scratch.load(&self.regs);
+        self.eval0(z, regs, args)
+    }
+    /// Evaluates at each of `zs`, writing the results into `out`
+    pub fn eval_batch(&self, zs: &[Complex64], out: &mut [Complex64]) {
+        assert_eq!(zs.len(), out.len());
+        // this call's own registers, so `self` is never mutated
+        let mut regs = self.regs.clone();
+        let mut scratch = Vec::new();
+        for (&z, out) in zs.iter().zip(out) {
+            *out = self.eval0(z, &mut regs, &mut scratch);
         }
     }
+    /// Every instruction overwrites its own register, so only the variables
+    /// need setting between points
+    fn eval0(&self, z: Complex64, regs: &mut [Complex64], scratch: &mut Vec<Complex64>) -> Complex64 {
+        self.z.map(|v| regs[v] = z);
+        self.x.map(|v| regs[v] = real(z.re));
+        self.y.map(|v| regs[v] = real(z.im));
+        run_complex(&self.code, &self.args, regs, scratch);
+        regs[self.result]
+    }
+}
+
+/// `a^b`, keeping integer powers exact and `0^b` zero
+fn pow_complex(a: Complex64, b: Complex64) -> Complex64 {
+    if b.im == 0.0 && b.re.fract() == 0.0 && b.re.abs() <= f64::from(i32::max_value()) {
+        a.powi(b.re as i32)
+    } else if a.norm() == 0.0 && b.re > 0.0 {
+        a
+    } else {
+        a.powc(b)
+    }
 }
 
 impl BaseExpr<String> {
-    pub fn eval<S: BuildHasher>(&self, env: &HashMap<&str, f64, S>) -> Result<f64, EvalError> {
+    /// With `checked` set, a function applied outside its domain is an
+    /// error rather than NaN or infinity
+    fn eval0<S: BuildHasher>(&self, env: &HashMap<&str, f64, S>, checked: bool) -> Result<f64, EvalError> {
         use self::BaseExpr::*;
         match *self {
-            Add(ref a, ref b) => Ok(a.eval(env)? + b.eval(env)?),
-            Sub(ref a, ref b) => Ok(a.eval(env)? - b.eval(env)?),
-            Mul(ref a, ref b) => Ok(a.eval(env)? * b.eval(env)?),
-            Div(ref a, ref b) => Ok(a.eval(env)? / b.eval(env)?),
-            Pow(ref a, ref b) => Ok(a.eval(env)?.powf(b.eval(env)?)),
-            Func(ref f, ref a) => Ok(f.eval(a.eval(env)?)),
+            Add(ref a, ref b) => Ok(a.eval0(env, checked)? + b.eval0(env, checked)?),
+            Sub(ref a, ref b) => Ok(a.eval0(env, checked)? - b.eval0(env, checked)?),
+            Mul(ref a, ref b) => Ok(a.eval0(env, checked)? * b.eval0(env, checked)?),
+            Div(ref a, ref b) => {
+                let (a, b) = (a.eval0(env, checked)?, b.eval0(env, checked)?);
+                if checked && b == 0.0 && !a.is_nan() {
+                    return Err(EvalError::DivideByZero(Expr(self.clone()).to_string()));
+                }
+                Ok(a / b)
+            }
+            Pow(ref a, ref b) => {
+                let (a, b) = (a.eval0(env, checked)?, b.eval0(env, checked)?);
+                if checked && !a.is_nan() && !b.is_nan() && !pow_defined(a, b) {
+                    return Err(self.undefined(vec![a, b]));
+                }
+                Ok(a.powf(b))
+            }
+            Func(ref f, ref a) => {
+                let a = a.eval0(env, checked)?;
+                if checked && !a.is_nan() && !f.defined_at(a) {
+                    return Err(self.undefined(vec![a]));
+                }
+                Ok(f.eval(a))
+            }
+            Call(ref f, ref es) => {
+                let vs = es.iter().map(|e| e.eval0(env, checked)).collect::<Result<Vec<_>, _>>()?;
+                if checked && vs.iter().all(|v| !v.is_nan()) && !f.defined_at(&vs) {
+                    return Err(self.undefined(vs));
+                }
+                Ok(f.eval(&vs))
+            }
+            // without definitions, `a(b)` can only mean `a * b`
+            Apply(ref f, ref es) if es.len() == 1 => {
+                let a = env.get(f.as_str()).cloned().ok_or_else(|| EvalError::UnknownVar(f))?;
+                Ok(a * es[0].eval0(env, checked)?)
+            }
+            Apply(ref f, _) => Err(EvalError::UnknownFunc(f)),
+            Cmp(ref c, ref a, ref b) => Ok(c.eval(a.eval0(env, checked)?, b.eval0(env, checked)?)),
+            And(ref a, ref b) => Ok(logic_and(a.eval0(env, checked)?, b.eval0(env, checked)?)),
+            Or(ref a, ref b) => Ok(logic_or(a.eval0(env, checked)?, b.eval0(env, checked)?)),
+            Not(ref a) => Ok(logic_not(a.eval0(env, checked)?)),
+            If(ref c, ref a, ref b) => {
+                let c = c.eval0(env, checked)?;
+                if c.is_nan() {
+                    Ok(c)
+                } else if c != 0.0 {
+                    a.eval0(env, checked)
+                } else {
+                    b.eval0(env, checked)
+                }
+            }
             Lit(a) => Ok(a),
             Var(ref a) => env.get(a.as_str()).cloned().ok_or_else(|| EvalError::UnknownVar(a)),
+            Imag if checked => Err(self.undefined(Vec::new())),
+            Imag => Ok(::std::f64::NAN),
         }
     }
-}
 
-impl CompiledExpr {
-    pub fn vars(&self) -> Vec<&str>{
-        self.varmap.keys().map(|s| s.as_str()).collect::<Vec<_>>()
-    }
-    pub fn set_var(&mut self, name: &str, value: f64) {
-        self.varmap.get(name).cloned().map(|i| self.varstore[i] = value);
-    }
-    pub fn eval(&self) -> f64 {
-        self.expr.eval(&self.varstore)
+    fn undefined<'a>(&self, args: Vec<f64>) -> EvalError<'a> {
+        EvalError::Undefined { expr: Expr(self.clone()).to_string(), args: args }
     }
-    pub fn bind2(self, v1: &str, v2: &str) -> Box<FnMut(f64, f64) -> f64> {
-        let CompiledExpr { expr, varmap, mut varstore } = self;
-        let v1 = varmap.get(v1).cloned();
-        let v2 = varmap.get(v2).cloned();
-        Box::new(move |x, y| {
-            v1.map(|v1| varstore[v1] = x);
-            v2.map(|v2| varstore[v2] = y);
-            expr.eval(&varstore)
+
+    fn derivative(&self, var: &str) -> Result<Expr, DerivativeError> {
+        use self::BaseExpr::*;
+        use self::simple::*;
+        use self::helper::{func, lit};
+        let c = |e: &BaseExpr<String>| Expr(e.clone());
+        let d = |e: &BaseExpr<String>| e.derivative(var);
+        let sq = |e: Expr| pow(e, lit(2.0));
+        Ok(match *self {
+            Add(ref a, ref b) => add(d(a)?, d(b)?),
+            Sub(ref a, ref b) => sub(d(a)?, d(b)?),
+            Mul(ref a, ref b) => add(mul(d(a)?, c(b)), mul(c(a), d(b)?)),
+            Div(ref a, ref b) => div(sub(mul(d(a)?, c(b)), mul(c(a), d(b)?)), sq(c(b))),
+            Pow(ref a, ref b) => match **b {
+                Lit(n) => mul(mul(pow(c(a), lit(n - 1.0)), d(a)?), lit(n)),
+                // a^b * (b' ln(a) + b a' / a)
+                _ => {
+                    let db = mul(d(b)?, func(KnownFunc::NaturalLogarithm, c(a)));
+                    let da = div(mul(c(b), d(a)?), c(a));
+                    mul(c(self), add(db, da))
+                }
+            },
+            Func(f, ref a) => mul(f.derivative(c(a))?, d(a)?),
+            Call(f, ref es) => {
+                use self::MultiFunc::*;
+                match f {
+                    ArcTangent2 => {
+                        let (y, x) = (&es[0], &es[1]);
+                        div(sub(mul(c(x), d(y)?), mul(c(y), d(x)?)), add(sq(c(x)), sq(c(y))))
+                    }
+                    Minimum | Maximum => {
+                        // peel off the last argument: max(a, b, c) = max(max(a, b), c)
+                        let (last, init) = es.split_last().expect("arity was checked");
+                        if init.is_empty() {
+                            d(last)?
+                        } else {
+                            let rest = match *init {
+                                [ref a] => a.clone(),
+                                _ => Call(f, init.to_vec()),
+                            };
+                            let cmp = match f {
+                                Minimum => Comparison::LessEqual,
+                                _ => Comparison::GreaterEqual,
+                            };
+                            helper::cond(helper::cmp(cmp, c(&rest), c(last)), d(&rest)?, Some(d(last)?))
+                        }
+                    }
+                    Hypotenuse => div(add(mul(c(&es[0]), d(&es[0])?), mul(c(&es[1]), d(&es[1])?)), c(self)),
+                    // a - b floor(a / b), and floor is flat
+                    Modulo => {
+                        let q = func(KnownFunc::Floor, div(c(&es[0]), c(&es[1])));
+                        sub(d(&es[0])?, mul(d(&es[1])?, q))
+                    }
+                    Logarithm => {
+                        let ln = |e: &BaseExpr<String>| func(KnownFunc::NaturalLogarithm, c(e));
+                        helper::div(ln(&es[1]), ln(&es[0])).0.derivative(var)?
+                    }
+                    // root(n, x) (x' / (n x) - ln|x| n' / n^2)
+                    Root => {
+                        let (n, x) = (&es[0], &es[1]);
+                        let ln_abs = func(KnownFunc::NaturalLogarithm, func(KnownFunc::AbsoluteValue, c(x)));
+                        let dx = div(d(x)?, mul(c(n), c(x)));
+                        let dn = div(mul(ln_abs, d(n)?), sq(c(n)));
+                        mul(c(self), sub(dx, dn))
+                    }
+                    Clamp => {
+                        let (a, lo, hi) = (&es[0], &es[1], &es[2]);
+                        let above = helper::cond(helper::cmp(Comparison::Greater, c(a), c(hi)),
+                                                 d(hi)?,
+                                                 Some(d(a)?));
+                        helper::cond(helper::cmp(Comparison::Less, c(a), c(lo)), d(lo)?, Some(above))
+                    }
+                }
+            }
+            // as in `eval`, `a(b)` is `a * b`
+            Apply(ref f, ref es) if es.len() == 1 => {
+                Mul(Box::new(Var(f.clone())), Box::new(es[0].clone())).derivative(var)?
+            }
+            Apply(ref f, _) => return Err(DerivativeError::UnknownFunc(f.clone())),
+            Cmp(..) | And(..) | Or(..) | Not(_) => lit(0.0),
+            If(ref cnd, ref a, ref b) => helper::cond(c(cnd), d(a)?, Some(d(b)?)),
+            // stay undefined where the expression is
+            Lit(v) => lit(if v.is_nan() { v } else { 0.0 }),
+            Var(ref v) => lit(if v == var { 1.0 } else { 0.0 }),
+            Imag => lit(0.0),
         })
     }
-}
 
-impl KnownFunc {
-    fn eval(&self, a: f64) -> f64 {
-        use self::KnownFunc::*;
+    /// Writes `self`, parenthesized if it binds less tightly than `prec`
+    fn fmt_prec(&self, fmt: &mut Formatter, prec: u8) -> Result<(), fmt::Error> {
+        use self::BaseExpr::*;
+        let own = self.prec();
+        if own < prec {
+            write!(fmt, "(")?;
+        }
         match *self {
-            AbsoluteValue => a.abs(),
-            Floor => a.floor(),
-            Ceiling => a.ceil(),
-            Exponential => a.exp(),
-            NaturalLogarithm => a.ln(),
-            Sine => a.sin(),
-            Cosine => a.cos(),
-            Tangent => a.tan(),
-            Secant => a.cos().recip(),
-            Cosecant => a.sin().recip(),
-            Cotangent => a.tan().recip(),
-            ArcSine => a.asin(),
-            ArcCosine => a.acos(),
-            ArcTangent => a.atan(),
-            ArcSecant => a.recip().acos(),
-            ArcCosecant => a.recip().asin(),
-            ArcCotangent => a.recip().atan(),
+            Add(ref a, ref b) => {
+                a.fmt_prec(fmt, PREC_ADD)?;
+                write!(fmt, " + ")?;
+                b.fmt_prec(fmt, PREC_MUL)?;
+            }
+            Sub(ref a, ref b) => {
+                a.fmt_prec(fmt, PREC_ADD)?;
+                write!(fmt, " - ")?;
+                b.fmt_prec(fmt, PREC_MUL)?;
+            }
+            Mul(ref a, ref b) => match **b {
+                Lit(l) if l == -1.0 => {
+                    write!(fmt, "-")?;
+                    a.fmt_prec(fmt, PREC_POW)?;
+                }
+                _ => {
+                    a.fmt_prec(fmt, PREC_MUL)?;
+                    write!(fmt, " * ")?;
+                    b.fmt_prec(fmt, PREC_NEG)?;
+                }
+            },
+            Div(ref a, ref b) => {
+                a.fmt_prec(fmt, PREC_MUL)?;
+                write!(fmt, " / ")?;
+                b.fmt_prec(fmt, PREC_NEG)?;
+            }
+            Pow(ref a, ref b) => {
+                a.fmt_prec(fmt, PREC_POW)?;
+                write!(fmt, "^")?;
+                b.fmt_prec(fmt, PREC_ATOM)?;
+            }
+            Func(f, ref a) => {
+                write!(fmt, "{}(", f.name())?;
+                a.fmt_prec(fmt, 0)?;
+                write!(fmt, ")")?;
+            }
+            Call(f, ref es) => {
+                write!(fmt, "{}", f.name())?;
+                fmt_args(fmt, es)?;
+            }
+            Apply(ref f, ref es) => {
+                write!(fmt, "{}", f)?;
+                fmt_args(fmt, es)?;
+            }
+            Cmp(c, ref a, ref b) => {
+                a.fmt_prec(fmt, PREC_ADD)?;
+                write!(fmt, " {} ", c.name())?;
+                b.fmt_prec(fmt, PREC_ADD)?;
+            }
+            And(ref a, ref b) => {
+                a.fmt_prec(fmt, PREC_AND)?;
+                write!(fmt, " && ")?;
+                b.fmt_prec(fmt, PREC_CMP)?;
+            }
+            Or(ref a, ref b) => {
+                a.fmt_prec(fmt, PREC_OR)?;
+                write!(fmt, " || ")?;
+                b.fmt_prec(fmt, PREC_AND)?;
+            }
+            Not(ref a) => {
+                write!(fmt, "!")?;
+                a.fmt_prec(fmt, PREC_CMP)?;
+            }
+            If(ref c, ref a, ref b) => {
+                write!(fmt, "if(")?;
+                c.fmt_prec(fmt, 0)?;
+                write!(fmt, ", ")?;
+                a.fmt_prec(fmt, 0)?;
+                // a missing `else` is undefined
+                match **b {
+                    Lit(l) if l.is_nan() => {}
+                    _ => {
+                        write!(fmt, ", ")?;
+                        b.fmt_prec(fmt, 0)?;
+                    }
+                }
+                write!(fmt, ")")?;
+            }
+            Lit(l) => write!(fmt, "{}", l)?,
+            Var(ref v) => write!(fmt, "{}", v)?,
+            Imag => write!(fmt, "i")?,
         }
+        if own < prec {
+            write!(fmt, ")")?;
+        }
+        Ok(())
     }
-}
 
-impl Debug for Equation {
-    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
-        let Equation(ref l, ref r) = *self;
-        write!(fmt, "{:?} = {:?}", l, r)
+    /// How tightly `self` binds when written out, following the grammar
+    fn prec(&self) -> u8 {
+        use self::BaseExpr::*;
+        match *self {
+            Or(..) => PREC_OR,
+            And(..) => PREC_AND,
+            Cmp(..) | Not(_) => PREC_CMP,
+            Add(..) | Sub(..) => PREC_ADD,
+            Mul(_, ref b) => match **b {
+                Lit(l) if l == -1.0 => PREC_NEG,
+                _ => PREC_MUL,
+            },
+            Div(..) => PREC_MUL,
+            Lit(l) if l < 0.0 => PREC_NEG,
+            Pow(..) => PREC_POW,
+            Func(..) | Call(..) | Apply(..) | If(..) | Lit(_) | Var(_) | Imag => PREC_ATOM,
+        }
     }
-}
 
-impl<V: Debug> Debug for BaseExpr<V> {
-    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+    /// Like `fmt_prec`, but as LaTeX
+    fn fmt_latex(&self, fmt: &mut Formatter, prec: u8) -> Result<(), fmt::Error> {
         use self::BaseExpr::*;
+        // fractions and powers group their operands themselves
+        let own = match *self {
+            Div(..) => PREC_ATOM,
+            _ => self.prec(),
+        };
+        if own < prec {
+            write!(fmt, "\\left(")?;
+        }
         match *self {
-            Add(ref a, ref b) => write!(fmt, "({:?} + {:?})", a, b),
-            Sub(ref a, ref b) => write!(fmt, "({:?} - {:?})", a, b),
-            Mul(ref a, ref b) => write!(fmt, "({:?} * {:?})", a, b),
-            Div(ref a, ref b) => write!(fmt, "({:?} / {:?})", a, b),
-            Pow(ref a, ref b) => write!(fmt, "({:?} ^ {:?})", a, b),
-            Func(ref f, ref a) => write!(fmt, "({:?}({:?}))", f, a),
-            Lit(a) => write!(fmt, "{:?}", a),
-            Var(ref a) => write!(fmt, "Var({:?})", a),
+            Add(ref a, ref b) => {
+                a.fmt_latex(fmt, PREC_ADD)?;
+                write!(fmt, " + ")?;
+                b.fmt_latex(fmt, PREC_MUL)?;
+            }
+            Sub(ref a, ref b) => {
+                a.fmt_latex(fmt, PREC_ADD)?;
+                write!(fmt, " - ")?;
+                b.fmt_latex(fmt, PREC_MUL)?;
+            }
+            Mul(ref a, ref b) => match **b {
+                Lit(l) if l == -1.0 => {
+                    write!(fmt, "-")?;
+                    a.fmt_latex(fmt, PREC_POW)?;
+                }
+                _ => {
+                    a.fmt_latex(fmt, PREC_MUL)?;
+                    write!(fmt, " \\cdot ")?;
+                    b.fmt_latex(fmt, PREC_NEG)?;
+                }
+            },
+            Div(ref a, ref b) => {
+                write!(fmt, "\\frac{{")?;
+                a.fmt_latex(fmt, 0)?;
+                write!(fmt, "}}{{")?;
+                b.fmt_latex(fmt, 0)?;
+                write!(fmt, "}}")?;
+            }
+            Pow(ref a, ref b) => {
+                a.fmt_latex(fmt, PREC_ATOM)?;
+                write!(fmt, "^{{")?;
+                b.fmt_latex(fmt, 0)?;
+                write!(fmt, "}}")?;
+            }
+            Func(f, ref a) => {
+                let (before, after) = f.latex();
+                write!(fmt, "{}", before)?;
+                a.fmt_latex(fmt, 0)?;
+                write!(fmt, "{}", after)?;
+            }
+            Call(MultiFunc::Logarithm, ref es) => {
+                write!(fmt, "\\log_{{")?;
+                es[0].fmt_latex(fmt, 0)?;
+                write!(fmt, "}}\\left(")?;
+                es[1].fmt_latex(fmt, 0)?;
+                write!(fmt, "\\right)")?;
+            }
+            Call(MultiFunc::Root, ref es) => {
+                write!(fmt, "\\sqrt[")?;
+                es[0].fmt_latex(fmt, 0)?;
+                write!(fmt, "]{{")?;
+                es[1].fmt_latex(fmt, 0)?;
+                write!(fmt, "}}")?;
+            }
+            Call(f, ref es) => {
+                write!(fmt, "{}", f.latex())?;
+                latex_args(fmt, es)?;
+            }
+            Apply(ref f, ref es) => {
+                write!(fmt, "{}", latex_name(f, "operatorname"))?;
+                latex_args(fmt, es)?;
+            }
+            Cmp(c, ref a, ref b) => {
+                a.fmt_latex(fmt, PREC_ADD)?;
+                write!(fmt, " {} ", c.latex())?;
+                b.fmt_latex(fmt, PREC_ADD)?;
+            }
+            And(ref a, ref b) => {
+                a.fmt_latex(fmt, PREC_AND)?;
+                write!(fmt, " \\land ")?;
+                b.fmt_latex(fmt, PREC_CMP)?;
+            }
+            Or(ref a, ref b) => {
+                a.fmt_latex(fmt, PREC_OR)?;
+                write!(fmt, " \\lor ")?;
+                b.fmt_latex(fmt, PREC_AND)?;
+            }
+            Not(ref a) => {
+                write!(fmt, "\\lnot ")?;
+                a.fmt_latex(fmt, PREC_CMP)?;
+            }
+            If(ref c, ref a, ref b) => {
+                write!(fmt, "\\begin{{cases}} ")?;
+                a.fmt_latex(fmt, 0)?;
+                write!(fmt, " & ")?;
+                c.fmt_latex(fmt, 0)?;
+                match **b {
+                    Lit(l) if l.is_nan() => {}
+                    _ => {
+                        write!(fmt, " \\\\ ")?;
+                        b.fmt_latex(fmt, 0)?;
+                        write!(fmt, " & \\text{{otherwise}}")?;
+                    }
+                }
+                write!(fmt, " \\end{{cases}}")?;
+            }
+            Lit(l) if l.is_nan() => write!(fmt, "\\text{{undefined}}")?,
+            Lit(l) if l.is_infinite() => write!(fmt, "{}\\infty", if l < 0.0 { "-" } else { "" })?,
+            Lit(l) => write!(fmt, "{}", l)?,
+            Var(ref v) => write!(fmt, "{}", latex_name(v, "mathit"))?,
+            Imag => write!(fmt, "i")?,
         }
+        if own < prec {
+            write!(fmt, "\\right)")?;
+        }
+        Ok(())
     }
 }
 
-impl<'a> error::Error for EvalError<'a> {
-    fn description(&self) -> &str {
-        "unknown variable"
-    }
+const PREC_OR: u8 = 0;
+const PREC_AND: u8 = 1;
+const PREC_CMP: u8 = 2;
+const PREC_ADD: u8 = 3;
+const PREC_MUL: u8 = 4;
+const PREC_NEG: u8 = 5;
+const PREC_POW: u8 = 6;
+const PREC_ATOM: u8 = 7;
+
+fn fmt_args(fmt: &mut Formatter, es: &[BaseExpr<String>]) -> Result<(), fmt::Error> {
+    write!(fmt, "(")?;
+    for (i, e) in es.iter().enumerate() {
+        if i != 0 {
+            write!(fmt, ", ")?;
+        }
+        e.fmt_prec(fmt, 0)?;
+    }
+    write!(fmt, ")")
+}
+
+fn latex_args(fmt: &mut Formatter, es: &[BaseExpr<String>]) -> Result<(), fmt::Error> {
+    write!(fmt, "\\left(")?;
+    for (i, e) in es.iter().enumerate() {
+        if i != 0 {
+            write!(fmt, ", ")?;
+        }
+        e.fmt_latex(fmt, 0)?;
+    }
+    write!(fmt, "\\right)")
+}
+
+/// A user's name for a variable or function, set in `style` if it's longer
+/// than a letter so it isn't read as a product
+fn latex_name(name: &str, style: &str) -> String {
+    if name.chars().count() == 1 {
+        name.to_owned()
+    } else {
+        format!("\\{}{{{}}}", style, name.replace('_', "\\_"))
+    }
+}
+
+impl Definitions {
+    pub fn new() -> Definitions {
+        Definitions::default()
+    }
+
+    pub fn insert(&mut self, def: Definition) -> Result<(), DefinitionError> {
+        if self.get(&def.name).is_some() {
+            return Err(DefinitionError::Duplicate(def.name));
+        }
+        if ["x", "y", "z"].contains(&def.name.as_str()) {
+            return Err(DefinitionError::Reserved(def.name));
+        }
+        // a bare name is only a constant, and a call is only a function,
+        // if there is one by that name
+        let shadowed = |n: &str, call: bool| {
+            constant(n).is_some() || (call && is_builtin(n))
+        };
+        if shadowed(&def.name, !def.params.is_empty()) {
+            return Err(DefinitionError::Builtin(def.name));
+        }
+        if let Some(p) = def.params.iter().find(|p| shadowed(p, false)) {
+            return Err(DefinitionError::Builtin(p.clone()));
+        }
+        self.0.push(def);
+        Ok(())
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.0.iter().map(|d| d.name.as_str()).collect()
+    }
+
+    /// Expands every definition once, so that cycles and bad calls are
+    /// reported even if the definition is never used
+    pub fn check(&self) -> Result<(), DefinitionError> {
+        for def in &self.0 {
+            let params = def.params.iter().map(|p| BaseExpr::Var(p.clone())).collect();
+            self.expand(def, params, &mut Vec::new())?;
+        }
+        Ok(())
+    }
+
+    fn get(&self, name: &str) -> Option<&Definition> {
+        self.0.iter().find(|d| d.name == name)
+    }
+
+    /// Substitutes `args` for the parameters of `def` in its body
+    fn expand<'a>(&'a self,
+                  def: &'a Definition,
+                  args: Vec<BaseExpr<String>>,
+                  stack: &mut Vec<&'a str>)
+                  -> Result<BaseExpr<String>, DefinitionError> {
+        if let Some(i) = stack.iter().position(|&n| n == def.name) {
+            let mut cycle: Vec<_> = stack[i..].iter().map(|&n| n.to_owned()).collect();
+            cycle.push(def.name.clone());
+            return Err(DefinitionError::Cycle(cycle));
+        }
+        let scope = def.params.iter().map(|p| p.as_str()).zip(args).collect();
+        stack.push(&def.name);
+        let r = self.inline(&def.body.0, &scope, stack);
+        stack.pop();
+        r
+    }
+
+    /// Replaces uses of definitions in `e`. `scope` holds the arguments of
+    /// the function being expanded, which shadow any definitions.
+    fn inline<'a>(&'a self,
+                  e: &BaseExpr<String>,
+                  scope: &FnvHashMap<&str, BaseExpr<String>>,
+                  stack: &mut Vec<&'a str>)
+                  -> Result<BaseExpr<String>, DefinitionError> {
+        use self::BaseExpr::*;
+        let mut go = |e: &BaseExpr<String>| self.inline(e, scope, stack).map(Expr);
+        Ok(match *e {
+            Add(ref a, ref b) => helper::add(go(a)?, go(b)?).0,
+            Sub(ref a, ref b) => helper::sub(go(a)?, go(b)?).0,
+            Mul(ref a, ref b) => helper::mul(go(a)?, go(b)?).0,
+            Div(ref a, ref b) => helper::div(go(a)?, go(b)?).0,
+            Pow(ref a, ref b) => helper::pow(go(a)?, go(b)?).0,
+            Func(f, ref a) => helper::func(f, go(a)?).0,
+            Call(f, ref es) => {
+                let es = es.iter().map(go).collect::<Result<_, _>>()?;
+                helper::call(f, es).expect("arity is checked when parsing").0
+            }
+            Cmp(c, ref a, ref b) => helper::cmp(c, go(a)?, go(b)?).0,
+            And(ref a, ref b) => helper::and(go(a)?, go(b)?).0,
+            Or(ref a, ref b) => helper::or(go(a)?, go(b)?).0,
+            Not(ref a) => helper::not(go(a)?).0,
+            If(ref c, ref a, ref b) => helper::cond(go(c)?, go(a)?, Some(go(b)?)).0,
+            Lit(l) => Lit(l),
+            Imag => Imag,
+            Var(ref v) => match (scope.get(v.as_str()), self.get(v)) {
+                (Some(arg), _) => arg.clone(),
+                (None, Some(def)) if def.params.is_empty() => self.expand(def, Vec::new(), stack)?,
+                (None, Some(def)) => {
+                    return Err(DefinitionError::Arity {
+                        name: v.clone(),
+                        expected: def.params.len(),
+                        got: 0,
+                    })
+                }
+                (None, None) => Var(v.clone()),
+            },
+            Apply(ref f, ref es) => {
+                let mut es = es.iter().map(|e| self.inline(e, scope, stack)).collect::<Result<Vec<_>, _>>()?;
+                match self.get(f) {
+                    Some(def) if !def.params.is_empty() && !scope.contains_key(f.as_str()) => {
+                        if def.params.len() != es.len() {
+                            return Err(DefinitionError::Arity {
+                                name: f.clone(),
+                                expected: def.params.len(),
+                                got: es.len(),
+                            });
+                        }
+                        self.expand(def, es, stack)?
+                    }
+                    // not a function, so `a(b)` is `a * b`
+                    _ if es.len() == 1 => {
+                        let a = self.inline(&Var(f.clone()), scope, stack)?;
+                        helper::mul(Expr(a), Expr(es.remove(0))).0
+                    }
+                    _ => return Err(DefinitionError::UnknownFunc(f.clone())),
+                }
+            }
+        })
+    }
+}
+
+impl CompiledExpr {
+    pub fn vars(&self) -> Vec<&str>{
+        self.varmap.keys().map(|s| s.as_str()).collect::<Vec<_>>()
+    }
+    pub fn set_var(&mut self, name: &str, value: f64) {
+        self.varmap.get(name).cloned().map(|i| self.regs[i] = value);
+    }
+    pub fn eval(&self) -> f64 {
+        self.eval_with(&mut Scratch::new())
+    }
+    /// Like `eval`, but reuses `scratch` rather than allocating registers
+    pub fn eval_with(&self, scratch: &mut Scratch) -> f64 {
+        let (regs, args) = scratch.load(&self.regs);
+        run(&self.code, &self.args, regs, args);
+        regs[self.result]
+    }
+    pub fn bind2(self, v1: &str, v2: &str) -> Box<FnMut(f64, f64) -> f64> {
+        let CompiledExpr { code, args, mut regs, result, varmap } = self;
+        let v1 = varmap.get(v1).cloned();
+        let v2 = varmap.get(v2).cloned();
+        let mut scratch = Vec::new();
+        Box::new(move |x, y| {
+            v1.map(|v1| regs[v1] = x);
+            v2.map(|v2| regs[v2] = y);
+            run(&code, &args, &mut regs, &mut scratch);
+            regs[result]
+        })
+    }
+    /// Like `bind2`, but the result can be shared between threads
+    pub fn bind2_batch(self, v1: &str, v2: &str) -> Bound2 {
+        Bound2 {
+            v1: self.varmap.get(v1).cloned(),
+            v2: self.varmap.get(v2).cloned(),
+            expr: self,
+        }
+    }
+    /// Evaluates with `v1` and `v2` set to each `(xs[i], ys[i])` in turn,
+    /// writing the results into `out`. Each operation is run across a whole
+    /// slice of points at once.
+    pub fn eval_batch(&self, v1: &str, v2: &str, xs: &[f64], ys: &[f64], out: &mut [f64]) {
+        self.eval_batch0(self.varmap.get(v1).cloned(), self.varmap.get(v2).cloned(), xs, ys, out)
+    }
+    fn eval_batch0(&self, v1: Option<usize>, v2: Option<usize>, xs: &[f64], ys: &[f64], out: &mut [f64]) {
+        assert_eq!(xs.len(), ys.len());
+        assert_eq!(xs.len(), out.len());
+        // this call's own scratch registers, so `self` is never mutated
+        let mut cols: Vec<_> = self.regs.iter().map(|&r| vec![r; LANES]).collect();
+        let mut scratch = Vec::new();
+        for ((xs, ys), out) in xs.chunks(LANES).zip(ys.chunks(LANES)).zip(out.chunks_mut(LANES)) {
+            let n = xs.len();
+            v1.map(|v1| cols[v1][..n].copy_from_slice(xs));
+            v2.map(|v2| cols[v2][..n].copy_from_slice(ys));
+            run_batch(&self.code, &self.args, &mut cols, n, &mut scratch);
+            out.copy_from_slice(&cols[self.result][..n]);
+        }
+    }
+}
+
+impl Bound2 {
+    /// Evaluates at the single point `(x, y)`
+    pub fn eval(&self, x: f64, y: f64) -> f64 {
+        self.eval_with(&mut Scratch::new(), x, y)
+    }
+    /// Like `eval`, but reuses `scratch` rather than allocating registers,
+    /// for callers that evaluate at many points one at a time
+    pub fn eval_with(&self, scratch: &mut Scratch, x: f64, y: f64) -> f64 {
+        let (regs, args) = scratch.load(&self.expr.regs);
+        self.v1.map(|v1| regs[v1] = x);
+        self.v2.map(|v2| regs[v2] = y);
+        run(&self.expr.code, &self.expr.args, regs, args);
+        regs[self.expr.result]
+    }
+    /// Evaluates at each `(xs[i], ys[i])`, writing the results into `out`
+    pub fn eval_batch(&self, xs: &[f64], ys: &[f64], out: &mut [f64]) {
+        self.expr.eval_batch0(self.v1, self.v2, xs, ys, out)
+    }
+    /// Evaluates, along with the gradient, at the single point `(x, y)`
+    pub fn eval_grad(&self, x: f64, y: f64) -> Dual {
+        self.eval_grad_with(&mut Scratch::new(), x, y)
+    }
+    /// Like `eval_grad`, but reuses `scratch` rather than allocating
+    /// registers
+    pub fn eval_grad_with(&self, scratch: &mut Scratch<Dual>, x: f64, y: f64) -> Dual {
+        let Scratch { ref mut regs, ref mut args } = *scratch;
+        self.eval_grad0(x, y, regs, args)
+    }
+    /// Evaluates, along with the gradient, at each `(xs[i], ys[i])`, writing
+    /// the results into `out`
+    pub fn eval_grad_batch(&self, xs: &[f64], ys: &[f64], out: &mut [Dual]) {
+        assert_eq!(xs.len(), ys.len());
+        assert_eq!(xs.len(), out.len());
+        let mut regs = Vec::new();
+        let mut scratch = Vec::new();
+        for ((&x, &y), out) in xs.iter().zip(ys).zip(out) {
+            *out = self.eval_grad0(x, y, &mut regs, &mut scratch);
+        }
+    }
+    fn eval_grad0(&self, x: f64, y: f64, regs: &mut Vec<Dual>, scratch: &mut Vec<Dual>) -> Dual {
+        regs.clear();
+        regs.extend(self.expr.regs.iter().map(|&r| Dual::constant(r)));
+        self.v1.map(|v1| regs[v1] = Dual { v: x, dx: 1.0, dy: 0.0 });
+        self.v2.map(|v2| regs[v2] = Dual { v: y, dx: 0.0, dy: 1.0 });
+        run_dual(&self.expr.code, &self.expr.args, regs, scratch);
+        regs[self.expr.result]
+    }
+}
+
+impl<R: Copy> Scratch<R> {
+    pub fn new() -> Scratch<R> {
+        Scratch { regs: Vec::new(), args: Vec::new() }
+    }
+    /// Starts again from `regs`, which keeps the allocations
+    fn load(&mut self, regs: &[R]) -> (&mut [R], &mut Vec<R>) {
+        self.regs.clear();
+        self.regs.extend_from_slice(regs);
+        (&mut self.regs, &mut self.args)
+    }
+}
+
+impl<R: Copy> Default for Scratch<R> {
+    fn default() -> Scratch<R> {
+        Scratch::new()
+    }
+}
+
+impl Dual {
+    pub fn constant(v: f64) -> Dual {
+        Dual { v: v, dx: 0.0, dy: 0.0 }
+    }
+    /// The result of a function with value `v` and derivative `slope` at
+    /// `self`, by the chain rule
+    fn chain(self, v: f64, slope: f64) -> Dual {
+        Dual { v: v, dx: slope * self.dx, dy: slope * self.dy }
+    }
+    fn is_constant(&self) -> bool {
+        self.dx == 0.0 && self.dy == 0.0
+    }
+    fn powf(self, b: Dual) -> Dual {
+        let v = self.v.powf(b.v);
+        if b.is_constant() {
+            // this way negative bases work too
+            let slope = if b.v == 0.0 { 0.0 } else { b.v * self.v.powf(b.v - 1.0) };
+            self.chain(v, slope)
+        } else {
+            // a^b (b' ln(a) + b a' / a)
+            let ln = self.v.ln();
+            Dual {
+                v: v,
+                dx: v * (b.dx * ln + b.v * self.dx / self.v),
+                dy: v * (b.dy * ln + b.v * self.dy / self.v),
+            }
+        }
+    }
+}
+
+impl Add for Dual {
+    type Output = Dual;
+    fn add(self, b: Dual) -> Dual {
+        Dual { v: self.v + b.v, dx: self.dx + b.dx, dy: self.dy + b.dy }
+    }
+}
+
+impl Sub for Dual {
+    type Output = Dual;
+    fn sub(self, b: Dual) -> Dual {
+        Dual { v: self.v - b.v, dx: self.dx - b.dx, dy: self.dy - b.dy }
+    }
+}
+
+impl Mul for Dual {
+    type Output = Dual;
+    fn mul(self, b: Dual) -> Dual {
+        Dual {
+            v: self.v * b.v,
+            dx: self.dx * b.v + self.v * b.dx,
+            dy: self.dy * b.v + self.v * b.dy,
+        }
+    }
+}
+
+impl Div for Dual {
+    type Output = Dual;
+    fn div(self, b: Dual) -> Dual {
+        let b2 = b.v * b.v;
+        Dual {
+            v: self.v / b.v,
+            dx: (self.dx * b.v - self.v * b.dx) / b2,
+            dy: (self.dy * b.v - self.v * b.dy) / b2,
+        }
+    }
+}
+
+impl Interval {
+    pub fn new(lo: f64, hi: f64) -> Interval {
+        Interval { lo: lo, hi: hi }
+    }
+    pub fn entire() -> Interval {
+        Interval::new(::std::f64::NEG_INFINITY, ::std::f64::INFINITY)
+    }
+    pub fn empty() -> Interval {
+        Interval::new(::std::f64::INFINITY, ::std::f64::NEG_INFINITY)
+    }
+    pub fn is_empty(&self) -> bool {
+        !(self.lo <= self.hi)
+    }
+    pub fn contains(&self, v: f64) -> bool {
+        self.lo <= v && v <= self.hi
+    }
+    /// The smallest interval holding both `self` and `b`
+    pub fn hull(self, b: Interval) -> Interval {
+        if self.is_empty() {
+            b
+        } else if b.is_empty() {
+            self
+        } else {
+            Interval::new(self.lo.min(b.lo), self.hi.max(b.hi))
+        }
+    }
+    /// The part of `self` from `lo` to `hi`
+    fn clip(self, lo: f64, hi: f64) -> Interval {
+        Interval::new(self.lo.max(lo), self.hi.min(hi))
+    }
+    /// Whether `self` holds some `offset + k period` for an integer `k`
+    fn meets(&self, offset: f64, period: f64) -> bool {
+        ((self.lo - offset) / period).ceil() <= (self.hi - offset) / period
+    }
+}
+
+/// Executes `code`, leaving results in `regs`. `scratch` is reused to
+/// gather the arguments of `Op::Call`s.
+fn run(code: &[Instr], args: &[usize], regs: &mut [f64], scratch: &mut Vec<f64>) {
+    for ins in code {
+        let v = match ins.op {
+            Op::Add(a, b) => regs[a] + regs[b],
+            Op::Sub(a, b) => regs[a] - regs[b],
+            Op::Mul(a, b) => regs[a] * regs[b],
+            Op::Div(a, b) => regs[a] / regs[b],
+            Op::Pow(a, b) => regs[a].powf(regs[b]),
+            Op::Func(f, a) => f.eval(regs[a]),
+            Op::Call(f, start, len) => {
+                scratch.clear();
+                scratch.extend(args[start..start + len].iter().map(|&a| regs[a]));
+                f.eval(scratch)
+            }
+            Op::Cmp(c, a, b) => c.eval(regs[a], regs[b]),
+            Op::And(a, b) => logic_and(regs[a], regs[b]),
+            Op::Or(a, b) => logic_or(regs[a], regs[b]),
+            Op::Not(a) => logic_not(regs[a]),
+            Op::Select(c, a, b) => select(regs[c], regs[a], regs[b]),
+        };
+        regs[ins.dst] = v;
+    }
+}
+
+/// Like `run`, but each register holds a column of values and only the
+/// first `n` of each are computed
+fn run_batch(code: &[Instr], args: &[usize], cols: &mut [Vec<f64>], n: usize, scratch: &mut Vec<f64>) {
+    for ins in code {
+        // `dst` is never an operand of its own instruction
+        let mut dst = mem::replace(&mut cols[ins.dst], Vec::new());
+        {
+            let dst = &mut dst[..n];
+            let col = |r: usize| &cols[r][..n];
+            match ins.op {
+                Op::Add(a, b) => map2(dst, col(a), col(b), |a, b| a + b),
+                Op::Sub(a, b) => map2(dst, col(a), col(b), |a, b| a - b),
+                Op::Mul(a, b) => map2(dst, col(a), col(b), |a, b| a * b),
+                Op::Div(a, b) => map2(dst, col(a), col(b), |a, b| a / b),
+                Op::Pow(a, b) => map2(dst, col(a), col(b), f64::powf),
+                Op::Func(f, a) => map1(dst, col(a), |a| f.eval(a)),
+                Op::Call(f, start, len) => {
+                    for (i, d) in dst.iter_mut().enumerate() {
+                        scratch.clear();
+                        scratch.extend(args[start..start + len].iter().map(|&a| cols[a][i]));
+                        *d = f.eval(scratch);
+                    }
+                }
+                Op::Cmp(c, a, b) => map2(dst, col(a), col(b), |a, b| c.eval(a, b)),
+                Op::And(a, b) => map2(dst, col(a), col(b), logic_and),
+                Op::Or(a, b) => map2(dst, col(a), col(b), logic_or),
+                Op::Not(a) => map1(dst, col(a), logic_not),
+                Op::Select(c, a, b) => {
+                    for (((d, &c), &a), &b) in dst.iter_mut().zip(col(c)).zip(col(a)).zip(col(b)) {
+                        *d = select(c, a, b);
+                    }
+                }
+            }
+        }
+        cols[ins.dst] = dst;
+    }
+}
+
+/// Like `run`, but carrying derivatives along with every value. Comparisons
+/// and logic are piecewise constant, so their derivatives are zero.
+fn run_dual(code: &[Instr], args: &[usize], regs: &mut [Dual], scratch: &mut Vec<Dual>) {
+    for ins in code {
+        let v = match ins.op {
+            Op::Add(a, b) => regs[a] + regs[b],
+            Op::Sub(a, b) => regs[a] - regs[b],
+            Op::Mul(a, b) => regs[a] * regs[b],
+            Op::Div(a, b) => regs[a] / regs[b],
+            Op::Pow(a, b) => regs[a].powf(regs[b]),
+            Op::Func(f, a) => regs[a].chain(f.eval(regs[a].v), f.slope(regs[a].v)),
+            Op::Call(f, start, len) => {
+                scratch.clear();
+                scratch.extend(args[start..start + len].iter().map(|&a| regs[a]));
+                f.eval_dual(scratch)
+            }
+            Op::Cmp(c, a, b) => Dual::constant(c.eval(regs[a].v, regs[b].v)),
+            Op::And(a, b) => Dual::constant(logic_and(regs[a].v, regs[b].v)),
+            Op::Or(a, b) => Dual::constant(logic_or(regs[a].v, regs[b].v)),
+            Op::Not(a) => Dual::constant(logic_not(regs[a].v)),
+            Op::Select(c, a, b) => {
+                let c = regs[c].v;
+                if c.is_nan() {
+                    Dual { v: c, dx: c, dy: c }
+                } else if c != 0.0 {
+                    regs[a]
+                } else {
+                    regs[b]
+                }
+            }
+        };
+        regs[ins.dst] = v;
+    }
+}
+
+/// Like `run`, but over the complex numbers. Comparisons and logic go by real
+/// parts, and give real truth values.
+fn run_complex(code: &[Instr], args: &[usize], regs: &mut [Complex64], scratch: &mut Vec<Complex64>) {
+    let truth = |v: f64| real(v);
+    for ins in code {
+        let v = match ins.op {
+            Op::Add(a, b) => regs[a] + regs[b],
+            Op::Sub(a, b) => regs[a] - regs[b],
+            Op::Mul(a, b) => regs[a] * regs[b],
+            Op::Div(a, b) => regs[a] / regs[b],
+            Op::Pow(a, b) => pow_complex(regs[a], regs[b]),
+            Op::Func(f, a) => f.eval_complex(regs[a]),
+            Op::Call(f, start, len) => {
+                scratch.clear();
+                scratch.extend(args[start..start + len].iter().map(|&a| regs[a]));
+                f.eval_complex(scratch)
+            }
+            Op::Cmp(c, a, b) => truth(c.eval(regs[a].re, regs[b].re)),
+            Op::And(a, b) => truth(logic_and(regs[a].re, regs[b].re)),
+            Op::Or(a, b) => truth(logic_or(regs[a].re, regs[b].re)),
+            Op::Not(a) => truth(logic_not(regs[a].re)),
+            Op::Select(c, a, b) => {
+                let c = regs[c].re;
+                if c.is_nan() {
+                    Complex64::new(c, c)
+                } else if c != 0.0 {
+                    regs[a]
+                } else {
+                    regs[b]
+                }
+            }
+        };
+        regs[ins.dst] = v;
+    }
+}
+
+/// Whether `a^b` is defined: there's a pole at zero, and negative numbers
+/// have no real roots
+fn pow_defined(a: f64, b: f64) -> bool {
+    !(a == 0.0 && b < 0.0) && !a.powf(b).is_nan()
+}
+
+fn map1<F: Fn(f64) -> f64>(dst: &mut [f64], a: &[f64], f: F) {
+    for (d, &a) in dst.iter_mut().zip(a) {
+        *d = f(a);
+    }
+}
+
+fn map2<F: Fn(f64, f64) -> f64>(dst: &mut [f64], a: &[f64], b: &[f64], f: F) {
+    for ((d, &a), &b) in dst.iter_mut().zip(a).zip(b) {
+        *d = f(a, b);
+    }
+}
+
+impl KnownFunc {
+    fn eval(&self, a: f64) -> f64 {
+        use self::KnownFunc::*;
+        match *self {
+            AbsoluteValue => a.abs(),
+            Floor => a.floor(),
+            Ceiling => a.ceil(),
+            Exponential => a.exp(),
+            NaturalLogarithm => a.ln(),
+            Sine => a.sin(),
+            Cosine => a.cos(),
+            Tangent => a.tan(),
+            Secant => a.cos().recip(),
+            Cosecant => a.sin().recip(),
+            Cotangent => a.tan().recip(),
+            ArcSine => a.asin(),
+            ArcCosine => a.acos(),
+            ArcTangent => a.atan(),
+            ArcSecant => a.recip().acos(),
+            ArcCosecant => a.recip().asin(),
+            ArcCotangent => a.recip().atan(),
+            SquareRoot => a.sqrt(),
+            CubeRoot => a.cbrt(),
+            CommonLogarithm => a.log10(),
+            BinaryLogarithm => a.log2(),
+            HyperbolicSine => a.sinh(),
+            HyperbolicCosine => a.cosh(),
+            HyperbolicTangent => a.tanh(),
+            InverseHyperbolicSine => a.asinh(),
+            InverseHyperbolicCosine => a.acosh(),
+            InverseHyperbolicTangent => a.atanh(),
+            Sign => if a == 0.0 { 0.0 } else { a.signum() },
+            Round => a.round(),
+            Truncate => a.trunc(),
+            Gamma => gamma(a),
+            Digamma => digamma(a),
+            ErrorFunction => erf(a),
+            Sinc => if a == 0.0 { 1.0 } else { a.sin() / a },
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<KnownFunc> {
+        KNOWN_FUNCS.iter().cloned().find(|f| f.name() == name)
+    }
+
+    pub fn name(&self) -> &'static str {
+        use self::KnownFunc::*;
+        match *self {
+            AbsoluteValue => "abs",
+            Floor => "floor",
+            Ceiling => "ceil",
+            Exponential => "exp",
+            NaturalLogarithm => "ln",
+            Sine => "sin",
+            Cosine => "cos",
+            Tangent => "tan",
+            Secant => "sec",
+            Cosecant => "csc",
+            Cotangent => "cot",
+            ArcSine => "arcsin",
+            ArcCosine => "arccos",
+            ArcTangent => "arctan",
+            ArcSecant => "arcsec",
+            ArcCosecant => "arccsc",
+            ArcCotangent => "arccot",
+            SquareRoot => "sqrt",
+            CubeRoot => "cbrt",
+            CommonLogarithm => "log10",
+            BinaryLogarithm => "log2",
+            HyperbolicSine => "sinh",
+            HyperbolicCosine => "cosh",
+            HyperbolicTangent => "tanh",
+            InverseHyperbolicSine => "arcsinh",
+            InverseHyperbolicCosine => "arccosh",
+            InverseHyperbolicTangent => "arctanh",
+            Sign => "sign",
+            Round => "round",
+            Truncate => "trunc",
+            Gamma => "gamma",
+            Digamma => "digamma",
+            ErrorFunction => "erf",
+            Sinc => "sinc",
+        }
+    }
+
+    /// How `f(a)` is written in LaTeX: what goes before and after `a`
+    pub fn latex(&self) -> (&'static str, &'static str) {
+        use self::KnownFunc::*;
+        let paren = "\\right)";
+        match *self {
+            AbsoluteValue => ("\\left|", "\\right|"),
+            Floor => ("\\left\\lfloor ", "\\right\\rfloor"),
+            Ceiling => ("\\left\\lceil ", "\\right\\rceil"),
+            Exponential => ("\\exp\\left(", paren),
+            NaturalLogarithm => ("\\ln\\left(", paren),
+            Sine => ("\\sin\\left(", paren),
+            Cosine => ("\\cos\\left(", paren),
+            Tangent => ("\\tan\\left(", paren),
+            Secant => ("\\sec\\left(", paren),
+            Cosecant => ("\\csc\\left(", paren),
+            Cotangent => ("\\cot\\left(", paren),
+            ArcSine => ("\\arcsin\\left(", paren),
+            ArcCosine => ("\\arccos\\left(", paren),
+            ArcTangent => ("\\arctan\\left(", paren),
+            ArcSecant => ("\\operatorname{arcsec}\\left(", paren),
+            ArcCosecant => ("\\operatorname{arccsc}\\left(", paren),
+            ArcCotangent => ("\\operatorname{arccot}\\left(", paren),
+            SquareRoot => ("\\sqrt{", "}"),
+            CubeRoot => ("\\sqrt[3]{", "}"),
+            CommonLogarithm => ("\\log_{10}\\left(", paren),
+            BinaryLogarithm => ("\\log_{2}\\left(", paren),
+            HyperbolicSine => ("\\sinh\\left(", paren),
+            HyperbolicCosine => ("\\cosh\\left(", paren),
+            HyperbolicTangent => ("\\tanh\\left(", paren),
+            InverseHyperbolicSine => ("\\operatorname{arsinh}\\left(", paren),
+            InverseHyperbolicCosine => ("\\operatorname{arcosh}\\left(", paren),
+            InverseHyperbolicTangent => ("\\operatorname{artanh}\\left(", paren),
+            Sign => ("\\operatorname{sgn}\\left(", paren),
+            Round => ("\\operatorname{round}\\left(", paren),
+            Truncate => ("\\operatorname{trunc}\\left(", paren),
+            Gamma => ("\\Gamma\\left(", paren),
+            Digamma => ("\\psi\\left(", paren),
+            ErrorFunction => ("\\operatorname{erf}\\left(", paren),
+            Sinc => ("\\operatorname{sinc}\\left(", paren),
+        }
+    }
+
+    fn eval_complex(&self, a: Complex64) -> Complex64 {
+        use self::KnownFunc::*;
+        let each = |f: fn(f64) -> f64| Complex64::new(f(a.re), f(a.im));
+        match *self {
+            AbsoluteValue => real(a.norm()),
+            Floor => each(f64::floor),
+            Ceiling => each(f64::ceil),
+            Exponential => a.exp(),
+            NaturalLogarithm => a.ln(),
+            Sine => a.sin(),
+            Cosine => a.cos(),
+            Tangent => a.tan(),
+            Secant => a.cos().inv(),
+            Cosecant => a.sin().inv(),
+            Cotangent => a.tan().inv(),
+            ArcSine => a.asin(),
+            ArcCosine => a.acos(),
+            ArcTangent => a.atan(),
+            ArcSecant => a.inv().acos(),
+            ArcCosecant => a.inv().asin(),
+            ArcCotangent => a.inv().atan(),
+            SquareRoot => a.sqrt(),
+            CubeRoot => a.cbrt(),
+            CommonLogarithm => a.ln() / consts::LN_10,
+            BinaryLogarithm => a.ln() / consts::LN_2,
+            HyperbolicSine => a.sinh(),
+            HyperbolicCosine => a.cosh(),
+            HyperbolicTangent => a.tanh(),
+            InverseHyperbolicSine => a.asinh(),
+            InverseHyperbolicCosine => a.acosh(),
+            InverseHyperbolicTangent => a.atanh(),
+            // the nearest point on the unit circle
+            Sign => if a.norm() == 0.0 { a } else { a / a.norm() },
+            Round => each(f64::round),
+            Truncate => each(f64::trunc),
+            Gamma => gamma_complex(a),
+            Digamma => digamma_complex(a),
+            ErrorFunction => erf_complex(a),
+            Sinc => if a.norm() == 0.0 { real(1.0) } else { a.sin() / a },
+        }
+    }
+
+    /// The derivative at `a`
+    fn slope(&self, a: f64) -> f64 {
+        use self::KnownFunc::*;
+        match *self {
+            AbsoluteValue => Sign.eval(a),
+            Floor | Ceiling | Sign | Round | Truncate => 0.0,
+            Exponential => a.exp(),
+            NaturalLogarithm => a.recip(),
+            Sine => a.cos(),
+            Cosine => -a.sin(),
+            Tangent => a.cos().powi(2).recip(),
+            Secant => a.sin() / a.cos().powi(2),
+            Cosecant => -a.cos() / a.sin().powi(2),
+            Cotangent => -a.sin().powi(2).recip(),
+            ArcSine => (1.0 - a * a).sqrt().recip(),
+            ArcCosine => -(1.0 - a * a).sqrt().recip(),
+            ArcTangent => (1.0 + a * a).recip(),
+            ArcSecant => (a.abs() * (a * a - 1.0).sqrt()).recip(),
+            ArcCosecant => -(a.abs() * (a * a - 1.0).sqrt()).recip(),
+            ArcCotangent => -(1.0 + a * a).recip(),
+            SquareRoot => 0.5 / a.sqrt(),
+            CubeRoot => (3.0 * a.cbrt().powi(2)).recip(),
+            CommonLogarithm => (a * consts::LN_10).recip(),
+            BinaryLogarithm => (a * consts::LN_2).recip(),
+            HyperbolicSine => a.cosh(),
+            HyperbolicCosine => a.sinh(),
+            HyperbolicTangent => 1.0 - a.tanh().powi(2),
+            InverseHyperbolicSine => (a * a + 1.0).sqrt().recip(),
+            InverseHyperbolicCosine => (a * a - 1.0).sqrt().recip(),
+            InverseHyperbolicTangent => (1.0 - a * a).recip(),
+            Gamma => gamma(a) * digamma(a),
+            Digamma => trigamma(a),
+            ErrorFunction => 2.0 / consts::PI.sqrt() * (-a * a).exp(),
+            Sinc => if a == 0.0 { 0.0 } else { (a.cos() - a.sin() / a) / a },
+        }
+    }
+
+    /// Whether `a` is in the domain, not counting overflow
+    fn defined_at(&self, a: f64) -> bool {
+        use self::KnownFunc::*;
+        match *self {
+            NaturalLogarithm | CommonLogarithm | BinaryLogarithm => a > 0.0,
+            InverseHyperbolicTangent => a.abs() < 1.0,
+            // the poles
+            Gamma | Digamma => !(a <= 0.0 && a.fract() == 0.0),
+            Cosecant | Cotangent => a.sin() != 0.0,
+            _ => !self.eval(a).is_nan(),
+        }
+    }
+
+    /// `f'(a)`, for the chain rule
+    fn derivative(&self, a: Expr) -> Result<Expr, DerivativeError> {
+        use self::KnownFunc::*;
+        use self::simple::*;
+        use self::helper::{func, lit};
+        let sq = |e: Expr| pow(e, lit(2.0));
+        let recip = |e: Expr| div(lit(1.0), e);
+        Ok(match *self {
+            AbsoluteValue => func(Sign, a),
+            Floor | Ceiling | Sign | Round | Truncate => lit(0.0),
+            Exponential => func(Exponential, a),
+            NaturalLogarithm => recip(a),
+            Sine => func(Cosine, a),
+            Cosine => neg(func(Sine, a)),
+            Tangent => sq(func(Secant, a)),
+            Secant => mul(func(Secant, a.clone()), func(Tangent, a)),
+            Cosecant => neg(mul(func(Cosecant, a.clone()), func(Cotangent, a))),
+            Cotangent => neg(sq(func(Cosecant, a))),
+            ArcSine => recip(func(SquareRoot, sub(lit(1.0), sq(a)))),
+            ArcCosine => neg(recip(func(SquareRoot, sub(lit(1.0), sq(a))))),
+            ArcTangent => recip(add(sq(a), lit(1.0))),
+            ArcSecant => recip(mul(func(AbsoluteValue, a.clone()), func(SquareRoot, sub(sq(a), lit(1.0))))),
+            ArcCosecant => neg(recip(mul(func(AbsoluteValue, a.clone()), func(SquareRoot, sub(sq(a), lit(1.0)))))),
+            ArcCotangent => neg(recip(add(sq(a), lit(1.0)))),
+            SquareRoot => recip(mul(func(SquareRoot, a), lit(2.0))),
+            CubeRoot => recip(mul(sq(func(CubeRoot, a)), lit(3.0))),
+            CommonLogarithm => recip(mul(a, lit(consts::LN_10))),
+            BinaryLogarithm => recip(mul(a, lit(consts::LN_2))),
+            HyperbolicSine => func(HyperbolicCosine, a),
+            HyperbolicCosine => func(HyperbolicSine, a),
+            HyperbolicTangent => sub(lit(1.0), sq(func(HyperbolicTangent, a))),
+            InverseHyperbolicSine => recip(func(SquareRoot, add(sq(a), lit(1.0)))),
+            InverseHyperbolicCosine => recip(func(SquareRoot, sub(sq(a), lit(1.0)))),
+            InverseHyperbolicTangent => recip(sub(lit(1.0), sq(a))),
+            Gamma => mul(func(Gamma, a.clone()), func(Digamma, a)),
+            // there's no trigamma function to write it with
+            Digamma => return Err(DerivativeError::NoRule(*self)),
+            ErrorFunction => mul(func(Exponential, neg(sq(a))), lit(2.0 / consts::PI.sqrt())),
+            Sinc => div(sub(func(Cosine, a.clone()), func(Sinc, a.clone())), a),
+        })
+    }
+
+    /// An interval holding `f(v)` for every `v` in `a` where `f` is
+    /// defined, up to rounding. It's empty if `f` is defined nowhere in `a`.
+    pub fn enclose(&self, a: Interval) -> Interval {
+        use self::KnownFunc::*;
+        let (pi, inf) = (consts::PI, ::std::f64::INFINITY);
+        if a.is_empty() {
+            return a;
+        }
+        // the image of `a` under `self`, if it's monotonic there
+        let up = |a: Interval| if a.is_empty() { a } else { Interval::new(self.eval(a.lo), self.eval(a.hi)) };
+        let down = |a: Interval| if a.is_empty() { a } else { Interval::new(self.eval(a.hi), self.eval(a.lo)) };
+        // the image of `a` under `self`, if it's continuous there and only
+        // turns at `offset + k period`
+        let turning = |offset: f64, period: f64| {
+            let mut r = Interval::new(self.eval(a.lo), self.eval(a.lo)).hull(Interval::new(self.eval(a.hi), self.eval(a.hi)));
+            let mut k = ((a.lo - offset) / period).ceil();
+            while offset + k * period <= a.hi {
+                let v = self.eval(offset + k * period);
+                r = r.hull(Interval::new(v, v));
+                k += 1.0;
+            }
+            r
+        };
+        match *self {
+            Floor | Ceiling | Sign | Round | Truncate | Exponential | CubeRoot | ArcTangent |
+            HyperbolicSine | HyperbolicTangent | InverseHyperbolicSine | ErrorFunction => up(a),
+            NaturalLogarithm | CommonLogarithm | BinaryLogarithm | SquareRoot => up(a.clip(0.0, inf)),
+            InverseHyperbolicCosine => up(a.clip(1.0, inf)),
+            ArcSine | InverseHyperbolicTangent => up(a.clip(-1.0, 1.0)),
+            ArcCosine => down(a.clip(-1.0, 1.0)),
+            // increasing on each side of the gap around zero
+            ArcSecant => up(a.clip(-inf, -1.0)).hull(up(a.clip(1.0, inf))),
+            ArcCosecant => down(a.clip(-inf, -1.0)).hull(down(a.clip(1.0, inf))),
+            ArcCotangent if a.lo < 0.0 && a.hi > 0.0 => Interval::new(-pi / 2.0, pi / 2.0),
+            ArcCotangent => down(a),
+            AbsoluteValue | HyperbolicCosine if a.contains(0.0) => {
+                Interval::new(self.eval(0.0), self.eval(a.lo).max(self.eval(a.hi)))
+            }
+            AbsoluteValue | HyperbolicCosine if a.hi < 0.0 => down(a),
+            AbsoluteValue | HyperbolicCosine => up(a),
+            Sine | Cosine if a.hi - a.lo >= 2.0 * pi => Interval::new(-1.0, 1.0),
+            Sine => turning(pi / 2.0, pi),
+            Cosine => turning(0.0, pi),
+            // the poles are `pi / 2` from the turning points
+            Tangent | Secant if a.meets(pi / 2.0, pi) => Interval::entire(),
+            Cosecant | Cotangent if a.meets(0.0, pi) => Interval::entire(),
+            Tangent => up(a),
+            Cotangent => down(a),
+            Secant => turning(0.0, pi),
+            Cosecant => turning(pi / 2.0, pi),
+            // between the poles at the negative integers, the sign alternates
+            Gamma if a.lo <= 0.0 => Interval::entire(),
+            // increasing between each of its poles
+            Digamma if a.clip(::std::f64::NEG_INFINITY, 0.0).meets(0.0, 1.0) => Interval::entire(),
+            Digamma => up(a),
+            Gamma => {
+                // the one minimum over the positive reals
+                let least = 1.461_632_144_968_362_3;
+                if a.contains(least) {
+                    Interval::new(gamma(least), gamma(a.lo).max(gamma(a.hi)))
+                } else if a.hi < least {
+                    down(a)
+                } else {
+                    up(a)
+                }
+            }
+            // bounded by its global extrema, and by `1 / |v|`
+            Sinc => {
+                let r = Interval::new(-0.217_233_628_211_221_7, 1.0);
+                if a.contains(0.0) {
+                    r
+                } else {
+                    let m = a.lo.abs().min(a.hi.abs()).recip();
+                    r.clip(-m, m)
+                }
+            }
+        }
+    }
+}
+
+const LANCZOS_G: f64 = 7.0;
+const LANCZOS_COEFFS: [f64; 9] = [
+    0.999_999_999_999_809_93,
+    676.520_368_121_885_1,
+    -1_259.139_216_722_402_8,
+    771.323_428_777_653_13,
+    -176.615_029_162_140_59,
+    12.507_343_278_686_905,
+    -0.138_571_095_265_720_12,
+    9.984_369_578_019_571_6e-6,
+    1.505_632_735_149_311_6e-7,
+];
+
+/// Lanczos approximation (g = 7, n = 9), with the reflection formula for
+/// `x < 0.5`
+fn gamma(x: f64) -> f64 {
+    if x < 0.5 {
+        consts::PI / ((consts::PI * x).sin() * gamma(1.0 - x))
+    } else {
+        let x = x - 1.0;
+        let t = x + LANCZOS_G + 0.5;
+        let sum = LANCZOS_COEFFS[1..].iter().enumerate()
+            .fold(LANCZOS_COEFFS[0], |acc, (i, &c)| acc + c / (x + (i + 1) as f64));
+        (2.0 * consts::PI).sqrt() * t.powf(x + 0.5) * (-t).exp() * sum
+    }
+}
+
+/// `gamma`, over the complex numbers
+fn gamma_complex(z: Complex64) -> Complex64 {
+    let pi = consts::PI;
+    if z.re < 0.5 {
+        pi / ((z * pi).sin() * gamma_complex(1.0 - z))
+    } else {
+        let z = z - 1.0;
+        let t = z + LANCZOS_G + 0.5;
+        let sum = LANCZOS_COEFFS[1..].iter().enumerate()
+            .fold(real(LANCZOS_COEFFS[0]), |acc, (i, &c)| acc + c / (z + (i + 1) as f64));
+        (2.0 * pi).sqrt() * t.powc(z + 0.5) * (-t).exp() * sum
+    }
+}
+
+/// `erf`, over the complex numbers: the Maclaurin series near zero, and the
+/// asymptotic series of `erfc` further out
+fn erf_complex(z: Complex64) -> Complex64 {
+    if z.re < 0.0 {
+        return -erf_complex(-z);
+    }
+    let z2 = z * z;
+    if z.norm() < 3.0 {
+        let (mut term, mut sum) = (z, z);
+        for n in 1..100 {
+            term = term * -z2 / n as f64;
+            let t = term / (2 * n + 1) as f64;
+            sum = sum + t;
+            if t.norm() < 1e-17 * sum.norm() {
+                break;
+            }
+        }
+        sum * (2.0 / consts::PI.sqrt())
+    } else {
+        let (mut term, mut sum) = (real(1.0), real(1.0));
+        for n in 1..30 {
+            let next = term * -((2 * n - 1) as f64) / (2.0 * z2);
+            // the series diverges eventually, so stop at the smallest term
+            if next.norm() >= term.norm() {
+                break;
+            }
+            term = next;
+            sum = sum + term;
+        }
+        1.0 - (-z2).exp() / (z * consts::PI.sqrt()) * sum
+    }
+}
+
+fn real(v: f64) -> Complex64 {
+    Complex64::new(v, 0.0)
+}
+
+/// The logarithmic derivative of `gamma`: the reflection formula for
+/// `x < 0.5`, then the recurrence up to where the asymptotic series is good
+fn digamma(x: f64) -> f64 {
+    if x < 0.5 {
+        return digamma(1.0 - x) - consts::PI / (consts::PI * x).tan();
+    }
+    let mut x = x;
+    let mut acc = 0.0;
+    while x < 10.0 {
+        acc -= x.recip();
+        x += 1.0;
+    }
+    let x2 = (x * x).recip();
+    acc + x.ln() - 0.5 / x - x2 * (1.0 / 12.0 - x2 * (1.0 / 120.0 - x2 * (1.0 / 252.0 - x2 * (1.0 / 240.0 - x2 / 132.0))))
+}
+
+/// `digamma`, over the complex numbers
+fn digamma_complex(z: Complex64) -> Complex64 {
+    let pi = consts::PI;
+    if z.re < 0.5 {
+        // `tan` is NaN rather than infinite at its poles
+        return digamma_complex(1.0 - z) - pi * (z * pi).cos() / (z * pi).sin();
+    }
+    let mut z = z;
+    let mut acc = real(0.0);
+    while z.norm() < 10.0 {
+        acc = acc - z.inv();
+        z = z + 1.0;
+    }
+    let z2 = (z * z).inv();
+    acc + z.ln() - 0.5 / z - z2 * (1.0 / 12.0 - z2 * (1.0 / 120.0 - z2 * (1.0 / 252.0 - z2 * (1.0 / 240.0 - z2 / 132.0))))
+}
+
+/// The derivative of `digamma`, in the same way
+fn trigamma(x: f64) -> f64 {
+    if x < 0.5 {
+        return (consts::PI / (consts::PI * x).sin()).powi(2) - trigamma(1.0 - x);
+    }
+    let mut x = x;
+    let mut acc = 0.0;
+    while x < 10.0 {
+        acc += (x * x).recip();
+        x += 1.0;
+    }
+    let x2 = (x * x).recip();
+    acc + x.recip() + x2 / 2.0 + x2 / x * (1.0 / 6.0 - x2 * (1.0 / 30.0 - x2 * (1.0 / 42.0 - x2 * (1.0 / 30.0 - x2 * 5.0 / 66.0))))
+}
+
+/// A series with only positive terms near zero, and the continued fraction
+/// for `erfc` further out; accurate to a few ulps
+fn erf(x: f64) -> f64 {
+    let z = x.abs();
+    let r = if z < 2.5 {
+        // erf(z) = 2/sqrt(pi) exp(-z^2) sum 2^n z^(2n+1) / (1 3 5 ... (2n+1))
+        let (mut term, mut sum) = (z, z);
+        let mut n = 0;
+        while term > 1e-17 * sum {
+            n += 1;
+            term *= 2.0 * z * z / f64::from(2 * n + 1);
+            sum += term;
+        }
+        2.0 / consts::PI.sqrt() * (-z * z).exp() * sum
+    } else {
+        // erfc(z) = exp(-z^2) / sqrt(pi) / (z + (1/2) / (z + 1 / (z + (3/2) / (z + ...))))
+        let f = (1..61).rev().fold(z, |f, n| z + f64::from(n) / 2.0 / f);
+        1.0 - (-z * z).exp() / (consts::PI.sqrt() * f)
+    };
+    r.copysign(x)
+}
+
+impl Comparison {
+    pub fn name(&self) -> &'static str {
+        use self::Comparison::*;
+        match *self {
+            Less => "<",
+            LessEqual => "<=",
+            Greater => ">",
+            GreaterEqual => ">=",
+        }
+    }
+
+    pub fn latex(&self) -> &'static str {
+        use self::Comparison::*;
+        match *self {
+            Less => "<",
+            LessEqual => "\\le",
+            Greater => ">",
+            GreaterEqual => "\\ge",
+        }
+    }
+
+    fn eval(&self, a: f64, b: f64) -> f64 {
+        use self::Comparison::*;
+        if a.is_nan() || b.is_nan() {
+            return ::std::f64::NAN;
+        }
+        let r = match *self {
+            Less => a < b,
+            LessEqual => a <= b,
+            Greater => a > b,
+            GreaterEqual => a >= b,
+        };
+        if r { 1.0 } else { 0.0 }
+    }
+}
+
+// false wins over undefined, so `x > 0 && ln(x) < 1` is false rather than
+// undefined for negative `x`
+fn logic_and(a: f64, b: f64) -> f64 {
+    if a == 0.0 || b == 0.0 {
+        0.0
+    } else if a.is_nan() || b.is_nan() {
+        ::std::f64::NAN
+    } else {
+        1.0
+    }
+}
+
+// likewise, true wins over undefined
+fn logic_or(a: f64, b: f64) -> f64 {
+    if (a != 0.0 && !a.is_nan()) || (b != 0.0 && !b.is_nan()) {
+        1.0
+    } else if a.is_nan() || b.is_nan() {
+        ::std::f64::NAN
+    } else {
+        0.0
+    }
+}
+
+fn select(c: f64, a: f64, b: f64) -> f64 {
+    if c.is_nan() {
+        c
+    } else if c != 0.0 {
+        a
+    } else {
+        b
+    }
+}
+
+fn logic_not(a: f64) -> f64 {
+    if a.is_nan() {
+        a
+    } else if a == 0.0 {
+        1.0
+    } else {
+        0.0
+    }
+}
+
+impl MultiFunc {
+    /// The minimum and (if any) maximum number of arguments
+    pub fn arity(&self) -> (usize, Option<usize>) {
+        use self::MultiFunc::*;
+        match *self {
+            Minimum | Maximum => (1, None),
+            ArcTangent2 | Hypotenuse | Modulo | Logarithm | Root => (2, Some(2)),
+            Clamp => (3, Some(3)),
+        }
+    }
+
+    /// How the name is written in LaTeX, except for `log` and `root`, which
+    /// take their first argument as a subscript and an index
+    pub fn latex(&self) -> &'static str {
+        use self::MultiFunc::*;
+        match *self {
+            ArcTangent2 => "\\operatorname{atan2}",
+            Minimum => "\\min",
+            Maximum => "\\max",
+            Hypotenuse => "\\operatorname{hypot}",
+            Modulo => "\\operatorname{mod}",
+            Logarithm => "\\log",
+            Root => "\\sqrt",
+            Clamp => "\\operatorname{clamp}",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<MultiFunc> {
+        MULTI_FUNCS.iter().cloned().find(|f| f.name() == name)
+    }
+
+    pub fn name(&self) -> &'static str {
+        use self::MultiFunc::*;
+        match *self {
+            ArcTangent2 => "atan2",
+            Minimum => "min",
+            Maximum => "max",
+            Hypotenuse => "hypot",
+            Modulo => "mod",
+            Logarithm => "log",
+            Root => "root",
+            Clamp => "clamp",
+        }
+    }
+
+    /// Like `eval`, but over the complex numbers. Comparisons, as in `min`,
+    /// go by real parts.
+    fn eval_complex(&self, a: &[Complex64]) -> Complex64 {
+        use self::MultiFunc::*;
+        let floor = |a: Complex64| Complex64::new(a.re.floor(), a.im.floor());
+        match *self {
+            // the angle of `x + i y`, which for real arguments is the usual one
+            ArcTangent2 => {
+                let (y, x) = (a[0], a[1]);
+                let w = x + Complex64::i() * y;
+                -Complex64::i() * (w / (x * x + y * y).sqrt()).ln()
+            }
+            Minimum => a.iter().cloned().fold(real(::std::f64::INFINITY), |m, a| if a.re < m.re { a } else { m }),
+            Maximum => a.iter().cloned().fold(real(::std::f64::NEG_INFINITY), |m, a| if a.re > m.re { a } else { m }),
+            Hypotenuse => (a[0] * a[0] + a[1] * a[1]).sqrt(),
+            Modulo => a[0] - a[1] * floor(a[0] / a[1]),
+            Logarithm => a[1].ln() / a[0].ln(),
+            Root => {
+                let (n, x) = (a[0], a[1]);
+                // keep odd roots of negative numbers real, like `eval` does
+                if n.im == 0.0 && x.im == 0.0 && x.re < 0.0 && n.re.fract() == 0.0 && n.re % 2.0 != 0.0 {
+                    real(self.eval(&[n.re, x.re]))
+                } else {
+                    pow_complex(x, n.inv())
+                }
+            }
+            Clamp => {
+                let m = if a[0].re > a[1].re { a[0] } else { a[1] };
+                if m.re < a[2].re { m } else { a[2] }
+            }
+        }
+    }
+
+    /// Like `eval`, but carrying derivatives along
+    fn eval_dual(&self, a: &[Dual]) -> Dual {
+        use self::MultiFunc::*;
+        let vs: Vec<_> = a.iter().map(|a| a.v).collect();
+        let v = self.eval(&vs);
+        match *self {
+            ArcTangent2 => {
+                let (y, x) = (a[0], a[1]);
+                let r2 = x.v * x.v + y.v * y.v;
+                Dual { v: v, dx: (x.v * y.dx - y.v * x.dx) / r2, dy: (x.v * y.dy - y.v * x.dy) / r2 }
+            }
+            // whichever argument was picked
+            Minimum | Maximum | Clamp => {
+                let picked = match *self {
+                    Clamp => if v == a[2].v { a[2] } else if v == a[1].v { a[1] } else { a[0] },
+                    _ => a.iter().find(|a| a.v == v).cloned().unwrap_or(Dual::constant(v)),
+                };
+                Dual { v: v, ..picked }
+            }
+            Hypotenuse => {
+                let (p, q) = (a[0], a[1]);
+                Dual { v: v, dx: (p.v * p.dx + q.v * q.dx) / v, dy: (p.v * p.dy + q.v * q.dy) / v }
+            }
+            // a - b floor(a / b)
+            Modulo => {
+                let q = (a[0].v / a[1].v).floor();
+                Dual { v: v, dx: a[0].dx - a[1].dx * q, dy: a[0].dy - a[1].dy * q }
+            }
+            // ln(x) / ln(b)
+            Logarithm => {
+                let (b, x) = (a[0], a[1]);
+                let ln = |d: Dual| d.chain(d.v.ln(), d.v.recip());
+                Dual { v: v, ..ln(x) / ln(b) }
+            }
+            // root(n, x) (x' / (n x) - ln|x| n' / n^2)
+            Root => {
+                let (n, x) = (a[0], a[1]);
+                let ln = x.v.abs().ln() / (n.v * n.v);
+                Dual {
+                    v: v,
+                    dx: v * (x.dx / (n.v * x.v) - ln * n.dx),
+                    dy: v * (x.dy / (n.v * x.v) - ln * n.dy),
+                }
+            }
+        }
+    }
+
+    /// Whether `a` is in the domain. `a.len()` must be within
+    /// `self.arity()`.
+    fn defined_at(&self, a: &[f64]) -> bool {
+        use self::MultiFunc::*;
+        match *self {
+            Modulo => a[1] != 0.0,
+            Logarithm => a[0] > 0.0 && a[0] != 1.0 && a[1] > 0.0,
+            Root => a[0] != 0.0 && !self.eval(a).is_nan(),
+            _ => !self.eval(a).is_nan(),
+        }
+    }
+
+    /// `a.len()` must be within `self.arity()`
+    fn eval(&self, a: &[f64]) -> f64 {
+        use self::MultiFunc::*;
+        match *self {
+            ArcTangent2 => a[0].atan2(a[1]),
+            Minimum => a.iter().cloned().fold(::std::f64::INFINITY, f64::min),
+            Maximum => a.iter().cloned().fold(::std::f64::NEG_INFINITY, f64::max),
+            Hypotenuse => a[0].hypot(a[1]),
+            // result takes the sign of the divisor
+            Modulo => a[0] - a[1] * (a[0] / a[1]).floor(),
+            Logarithm => a[1].ln() / a[0].ln(),
+            Root => {
+                let (n, x) = (a[0], a[1]);
+                // odd roots of negative numbers are real
+                if x < 0.0 && n.fract() == 0.0 && n % 2.0 != 0.0 {
+                    -(-x).powf(n.recip())
+                } else {
+                    x.powf(n.recip())
+                }
+            }
+            Clamp => a[0].max(a[1]).min(a[2]),
+        }
+    }
+}
+
+impl Debug for Equation {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        let Equation(ref l, ref r) = *self;
+        write!(fmt, "{:?} = {:?}", l, r)
+    }
+}
+
+impl<V: Debug> Debug for BaseExpr<V> {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        use self::BaseExpr::*;
+        match *self {
+            Add(ref a, ref b) => write!(fmt, "({:?} + {:?})", a, b),
+            Sub(ref a, ref b) => write!(fmt, "({:?} - {:?})", a, b),
+            Mul(ref a, ref b) => write!(fmt, "({:?} * {:?})", a, b),
+            Div(ref a, ref b) => write!(fmt, "({:?} / {:?})", a, b),
+            Pow(ref a, ref b) => write!(fmt, "({:?} ^ {:?})", a, b),
+            Func(ref f, ref a) => write!(fmt, "({}({:?}))", f.name(), a),
+            Call(ref f, ref es) => {
+                write!(fmt, "({}(", f.name())?;
+                for (i, e) in es.iter().enumerate() {
+                    if i != 0 {
+                        write!(fmt, ", ")?;
+                    }
+                    write!(fmt, "{:?}", e)?;
+                }
+                write!(fmt, "))")
+            }
+            Apply(ref f, ref es) => {
+                write!(fmt, "({}(", f)?;
+                for (i, e) in es.iter().enumerate() {
+                    if i != 0 {
+                        write!(fmt, ", ")?;
+                    }
+                    write!(fmt, "{:?}", e)?;
+                }
+                write!(fmt, "))")
+            }
+            Cmp(ref c, ref a, ref b) => write!(fmt, "({:?} {} {:?})", a, c.name(), b),
+            And(ref a, ref b) => write!(fmt, "({:?} && {:?})", a, b),
+            Or(ref a, ref b) => write!(fmt, "({:?} || {:?})", a, b),
+            Not(ref a) => write!(fmt, "(!{:?})", a),
+            If(ref c, ref a, ref b) => write!(fmt, "(if({:?}, {:?}, {:?}))", c, a, b),
+            Lit(a) => write!(fmt, "{:?}", a),
+            Var(ref a) => write!(fmt, "Var({:?})", a),
+            Imag => write!(fmt, "i"),
+        }
+    }
+}
+
+impl Display for Expr {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        self.0.fmt_prec(fmt, 0)
+    }
+}
+
+/// Displays an expression as LaTeX
+struct Latex<'a>(&'a BaseExpr<String>);
+
+impl<'a> Display for Latex<'a> {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        self.0.fmt_latex(fmt, 0)
+    }
+}
+
+impl ArityError {
+    /// An error if `got` arguments is outside `arity`
+    fn check(name: &'static str, arity: (usize, Option<usize>), got: usize) -> Result<(), ArityError> {
+        let (min, max) = arity;
+        if got < min || max.map_or(false, |max| got > max) {
+            Err(ArityError { name: name, arity: arity, got: got })
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl error::Error for ArityError {
+    fn description(&self) -> &str {
+        "wrong number of arguments"
+    }
+}
+
+impl Display for ArityError {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        let (min, max) = self.arity;
+        let expected = match max {
+            Some(max) if max == min => format!("{}", min),
+            Some(max) => format!("{} to {}", min, max),
+            None => format!("at least {}", min),
+        };
+        write!(fmt, "`{}` takes {} argument{} but {} {} given",
+               self.name,
+               expected,
+               if min == 1 && max.map_or(true, |max| max == 1) { "" } else { "s" },
+               self.got,
+               if self.got == 1 { "was" } else { "were" })
+    }
+}
+
+impl<'a> error::Error for EvalError<'a> {
+    fn description(&self) -> &str {
+        match *self {
+            EvalError::UnknownVar(_) => "unknown variable",
+            EvalError::UnknownFunc(_) => "unknown function",
+            EvalError::Undefined { .. } => "outside the domain of a function",
+            EvalError::DivideByZero(_) => "division by zero",
+        }
+    }
 }
 
 impl<'a> Display for EvalError<'a> {
@@ -327,6 +2674,430 @@ impl<'a> Display for EvalError<'a> {
         write!(fmt, "failed to evaluate expression: ")?;
         match *self {
             UnknownVar(v) => write!(fmt, "unknown variable: {}", v),
+            UnknownFunc(f) => write!(fmt, "unknown function: {}", f),
+            Undefined { ref expr, ref args } if args.is_empty() => write!(fmt, "`{}` isn't a real number", expr),
+            Undefined { ref expr, ref args } => {
+                write!(fmt, "`{}` is undefined at ", expr)?;
+                match **args {
+                    [a] => write!(fmt, "{}", a),
+                    _ => {
+                        let args: Vec<_> = args.iter().map(f64::to_string).collect();
+                        write!(fmt, "({})", args.join(", "))
+                    }
+                }
+            }
+            DivideByZero(ref expr) => write!(fmt, "`{}` divides by zero", expr),
+        }
+    }
+}
+
+impl error::Error for DefinitionError {
+    fn description(&self) -> &str {
+        use self::DefinitionError::*;
+        match *self {
+            Duplicate(_) => "duplicate definition",
+            Cycle(_) => "circular definitions",
+            Arity { .. } => "wrong number of arguments",
+            UnknownFunc(_) => "unknown function",
+            Reserved(_) => "definition of a coordinate",
+            Builtin(_) => "redefinition of a built-in name",
+        }
+    }
+}
+
+impl Display for DefinitionError {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        use self::DefinitionError::*;
+        match *self {
+            Duplicate(ref n) => write!(fmt, "`{}` is defined more than once", n),
+            Cycle(ref ns) => write!(fmt, "definitions are circular: {}", ns.join(" -> ")),
+            Arity { ref name, expected, got } => {
+                write!(fmt, "`{}` takes {} argument{} but {} {} given",
+                       name,
+                       expected,
+                       if expected == 1 { "" } else { "s" },
+                       got,
+                       if got == 1 { "was" } else { "were" })
+            }
+            UnknownFunc(ref f) => write!(fmt, "unknown function `{}`", f),
+            Reserved(ref n) => write!(fmt, "`{}` is a coordinate of the plot, so it can't be defined", n),
+            Builtin(ref n) => {
+                write!(fmt, "`{}` is a built-in {} and can't be redefined",
+                       n,
+                       if constant(n).is_some() { "constant" } else { "function" })
+            }
+        }
+    }
+}
+
+impl error::Error for DerivativeError {
+    fn description(&self) -> &str {
+        match *self {
+            DerivativeError::NoRule(_) => "derivative not known",
+            DerivativeError::UnknownFunc(_) => "unknown function",
+        }
+    }
+}
+
+impl Display for DerivativeError {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            DerivativeError::NoRule(f) => write!(fmt, "can't differentiate `{}`", f.name()),
+            DerivativeError::UnknownFunc(ref f) => write!(fmt, "unknown function `{}`", f),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn erf_matches_reference_values() {
+        assert_eq!(erf(0.0), 0.0);
+        let cases = [
+            (0.1, 0.112_462_916_018_284_9),
+            (0.5, 0.520_499_877_813_046_5),
+            (1.0, 0.842_700_792_949_714_9),
+            (2.0, 0.995_322_265_018_952_7),
+            (2.5, 0.999_593_047_982_555),
+            (3.0, 0.999_977_909_503_001_4),
+            (5.0, 0.999_999_999_998_462_5),
+        ];
+        for &(x, v) in &cases {
+            assert!((erf(x) - v).abs() < 1e-15, "erf({}) = {}, not {}", x, erf(x), v);
+            assert!((erf(-x) + v).abs() < 1e-15, "erf({}) = {}, not {}", -x, erf(-x), -v);
+        }
+        assert_eq!(erf(::std::f64::INFINITY), 1.0);
+        assert!(erf(::std::f64::NAN).is_nan());
+    }
+
+    #[test]
+    fn erf_agrees_with_the_complex_series() {
+        for i in -29..30 {
+            let x = f64::from(i) / 10.0;
+            let z = erf_complex(real(x));
+            assert!((erf(x) - z.re).abs() < 1e-12, "erf({}) = {}, but {} over the complex numbers", x, erf(x), z.re);
+        }
+    }
+
+    #[test]
+    fn enclosures_hold_every_value() {
+        let intervals = [(-3.0, -2.5), (-1.5, 0.5), (-0.2, 0.3), (0.1, 0.9), (0.5, 2.5), (1.0, 4.0), (2.0, 7.0)];
+        for f in KNOWN_FUNCS {
+            for &(lo, hi) in &intervals {
+                let r = f.enclose(Interval::new(lo, hi));
+                for i in 0..=200 {
+                    let v = lo + (hi - lo) * f64::from(i) / 200.0;
+                    let fv = f.eval(v);
+                    if fv.is_nan() {
+                        continue;
+                    }
+                    let slack = 1e-12 * fv.abs().max(1.0);
+                    assert!(r.contains(fv) || (r.lo - slack <= fv && fv <= r.hi + slack),
+                            "{}({}) = {} is outside {:?}", f.name(), v, fv, r);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn enclosures_are_tight() {
+        use self::KnownFunc::*;
+        let pi = consts::PI;
+        assert_eq!(Sine.enclose(Interval::new(0.0, pi)).hi, 1.0);
+        assert!(Sine.enclose(Interval::new(0.0, pi)).lo.abs() < 1e-15);
+        assert_eq!(Cosine.enclose(Interval::new(-1.0, 10.0)), Interval::new(-1.0, 1.0));
+        assert_eq!(SquareRoot.enclose(Interval::new(-1.0, 4.0)), Interval::new(0.0, 2.0));
+        assert_eq!(AbsoluteValue.enclose(Interval::new(-3.0, 2.0)), Interval::new(0.0, 3.0));
+        assert_eq!(Tangent.enclose(Interval::new(1.0, 2.0)), Interval::entire());
+        assert!(NaturalLogarithm.enclose(Interval::new(-2.0, -1.0)).is_empty());
+        let g = Gamma.enclose(Interval::new(1.0, 2.0));
+        assert!((g.lo - 0.885_603_194_410_888_7).abs() < 1e-12 && (g.hi - 1.0).abs() < 1e-12, "{:?}", g);
+        assert_eq!(ErrorFunction.enclose(Interval::new(0.0, ::std::f64::INFINITY)), Interval::new(0.0, 1.0));
+    }
+
+    #[test]
+    fn latex_of_the_new_functions() {
+        use expr_parser::ExprParser;
+        let latex = |s: &str| ExprParser::new().parse(s).unwrap().to_latex();
+        assert_eq!(latex("sqrt(x) + cbrt(y)"), "\\sqrt{x} + \\sqrt[3]{y}");
+        assert_eq!(latex("gamma(x) / erf(y)"), "\\frac{\\Gamma\\left(x\\right)}{\\operatorname{erf}\\left(y\\right)}");
+        assert_eq!(latex("log(2, x) * sign(x)"), "\\log_{2}\\left(x\\right) \\cdot \\operatorname{sgn}\\left(x\\right)");
+        assert_eq!(latex("(x + 1)^2"), "\\left(x + 1\\right)^{2}");
+        assert_eq!(latex("if(x < 0, -x, x)"), "\\begin{cases} -x & x < 0 \\\\ x & \\text{otherwise} \\end{cases}");
+    }
+
+    #[test]
+    fn digamma_matches_reference_values() {
+        let cases = [
+            (0.3, -3.502_524_222_200_133, 12.245_364_546_107_731),
+            (1.0, -0.577_215_664_901_532_9, 1.644_934_066_848_226_4),
+            (2.5, 0.703_156_640_645_243_2, 0.490_357_756_100_234_9),
+            (7.0, 1.872_784_335_098_467, 0.153_545_177_959_337_55),
+            (-1.5, 0.703_156_640_645_243_2, 9.379_246_644_989_124),
+            (25.0, 3.198_742_512_851_974, 0.040_810_663_257_225_58),
+        ];
+        for &(x, psi, psi1) in &cases {
+            assert!((digamma(x) - psi).abs() < 1e-13, "digamma({}) = {}, not {}", x, digamma(x), psi);
+            assert!((trigamma(x) - psi1).abs() < 1e-12 * psi1, "trigamma({}) = {}, not {}", x, trigamma(x), psi1);
+            let w = digamma_complex(real(x));
+            assert!((w.re - psi).abs() < 1e-13, "digamma({}) = {} over the complex numbers", x, w);
+        }
+        let w = digamma_complex(Complex64::new(1.0, 2.0));
+        assert!((w - Complex64::new(0.714_591_515_373_977_5, 1.320_807_282_642_230_2)).norm() < 1e-13, "{}", w);
+    }
+
+    #[test]
+    fn gamma_is_differentiated_with_digamma() {
+        use expr_parser::ExprParser;
+        let d = ExprParser::new().parse("gamma(x^2)").unwrap().derivative("x").unwrap();
+        assert_eq!(d.to_string(), "gamma(x^2) * digamma(x^2) * (x * 2)");
+        let env: HashMap<&str, f64> = [("x", 1.3)].iter().cloned().collect();
+        let slope = 2.0 * 1.3 * KnownFunc::Gamma.slope(1.69);
+        assert!((d.eval(&env).unwrap() - slope).abs() < 1e-12);
+    }
+
+    #[test]
+    fn constants_are_looked_up_by_name() {
+        use expr_parser::ExprParser;
+        let env: HashMap<&str, f64> = HashMap::new();
+        let eval = |s: &str| ExprParser::new().parse(s).unwrap().eval(&env).map_err(|e| e.to_string());
+        assert_eq!(eval("pi"), Ok(consts::PI));
+        assert_eq!(eval("π"), Ok(consts::PI));
+        assert_eq!(eval("tau / 2"), Ok(consts::PI));
+        assert_eq!(eval("2pi"), Ok(2.0 * consts::PI));
+        assert_eq!(eval("e^2"), Ok(consts::E * consts::E));
+        assert_eq!(eval("phi^2 - phi"), Ok(1.0));
+        // a longer name is a variable of its own
+        assert_eq!(eval("pie"), Err("failed to evaluate expression: unknown variable: pie".to_owned()));
+    }
+
+    #[test]
+    fn functions_of_several_arguments() {
+        use expr_parser::ExprParser;
+        let env: HashMap<&str, f64> = HashMap::new();
+        let eval = |s: &str| ExprParser::new().parse(s).unwrap().eval(&env).unwrap();
+        assert_eq!(eval("max(1, 3, 2)"), 3.0);
+        assert_eq!(eval("min(4, 2)"), 2.0);
+        assert_eq!(eval("atan2(1, -1)"), 0.75 * consts::PI);
+        assert_eq!(eval("hypot(3, 4)"), 5.0);
+        assert_eq!(eval("mod(-1, 3)"), 2.0);
+        assert_eq!(eval("log(2, 8)"), 3.0);
+        assert_eq!(eval("root(3, 27)"), 3.0);
+        assert_eq!(eval("clamp(5, 0, 1)"), 1.0);
+    }
+
+    #[test]
+    fn calls_with_the_wrong_number_of_arguments_are_rejected() {
+        use expr_parser::ExprParser;
+        use lalrpop_util::ParseError;
+        let arity = |s: &str| match ExprParser::new().parse(s) {
+            Err(ParseError::User { error }) => error.to_string(),
+            r => panic!("`{}` parsed as {:?}", s, r),
+        };
+        assert_eq!(arity("sin(1, 2)"), "`sin` takes 1 argument but 2 were given");
+        assert_eq!(arity("atan2(1)"), "`atan2` takes 2 arguments but 1 was given");
+        assert_eq!(arity("clamp(1, 2)"), "`clamp` takes 3 arguments but 2 were given");
+        assert_eq!(arity("if(1)"), "`if` takes 2 to 3 arguments but 1 was given");
+    }
+
+    #[test]
+    fn conditionals_and_booleans() {
+        use expr_parser::ExprParser;
+        let cases: &[(&str, f64, f64)] = &[
+            ("if(x < 0, x^2, x)", -2.0, 4.0),
+            ("if(x < 0, x^2, x)", 3.0, 3.0),
+            ("x > 1 && x <= 2", 2.0, 1.0),
+            ("x > 1 && x <= 2", 3.0, 0.0),
+            ("x < 1 || !(x > 2)", 2.0, 1.0),
+            ("x >= 3 || x < 1", 2.0, 0.0),
+            // a false side settles `&&` and a true side `||`, even if the
+            // other side is undefined
+            ("x < 0 && sqrt(x) > 1", 2.0, 0.0),
+            ("x > 0 || sqrt(-x) > 1", 2.0, 1.0),
+        ];
+        for &(s, x, v) in cases {
+            let e = ExprParser::new().parse(s).unwrap();
+            let env: HashMap<&str, f64> = [("x", x)].iter().cloned().collect();
+            assert_eq!(e.eval(&env).unwrap(), v, "{} at {}", s, x);
+            let mut c = e.compile(&Definitions::new()).unwrap();
+            c.set_var("x", x);
+            assert_eq!(c.eval(), v, "{} at {}, compiled", s, x);
+        }
+        let undefined = ["if(x > 0, 1)", "x < sqrt(-1)", "!(0/0)", "if(0/0, 1, 2)"];
+        for s in &undefined {
+            let e = ExprParser::new().parse(s).unwrap();
+            let env: HashMap<&str, f64> = [("x", -1.0)].iter().cloned().collect();
+            assert!(e.eval(&env).unwrap().is_nan(), "{}", s);
+            let mut c = e.compile(&Definitions::new()).unwrap();
+            c.set_var("x", -1.0);
+            assert!(c.eval().is_nan(), "{}, compiled", s);
+        }
+    }
+
+    /// Expressions that exercise every kind of node, for comparing evaluators
+    const SAMPLE_EXPRS: &[&str] = &[
+        "x^2 + y^2 - 1",
+        "sin(x) * cos(y) - x / (y + 2)",
+        "-x^3 + 2x*y - exp(-y) + ln(abs(x) + 1)",
+        "sqrt(x) + cbrt(y) - tan(x*y)",
+        "max(x, y, 0.5) - min(x, -y) + hypot(x, y) + atan2(y, x)",
+        "mod(x, 0.7) + log(3, abs(y) + 2) + root(3, x) + clamp(y, -1, 1)",
+        "if(x < y, x^2, y) + (x > 0 && y <= 1) - (x >= 2 || !(y < 0))",
+        "gamma(x + 3) * erf(y) + sign(x) * floor(y) + ceil(x)",
+        "pi * x - e^y + tau",
+    ];
+
+    fn sample_points() -> Vec<(f64, f64)> {
+        let mut points = Vec::new();
+        for i in -6..7 {
+            for j in -5..6 {
+                points.push((f64::from(i) * 0.37, f64::from(j) * 0.61));
+            }
+        }
+        points
+    }
+
+    #[test]
+    fn compiled_matches_the_tree_walk() {
+        use expr_parser::ExprParser;
+        for s in SAMPLE_EXPRS {
+            let e = ExprParser::new().parse(s).unwrap();
+            let mut compiled = e.clone().compile(&Definitions::new()).unwrap();
+            let bound = e.clone().compile(&Definitions::new()).unwrap().bind2_batch("x", "y");
+            for (x, y) in sample_points() {
+                let env: HashMap<&str, f64> = [("x", x), ("y", y)].iter().cloned().collect();
+                let expected = e.eval(&env).unwrap();
+                compiled.set_var("x", x);
+                compiled.set_var("y", y);
+                for &v in &[compiled.eval(), bound.eval(x, y)] {
+                    assert!(v == expected || (v.is_nan() && expected.is_nan()),
+                            "{} at ({}, {}) is {}, not {}", s, x, y, v, expected);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn batches_match_single_points() {
+        use expr_parser::ExprParser;
+        // more points than `LANES`, so the last batch is only partly full
+        let (xs, ys): (Vec<f64>, Vec<f64>) =
+            (0..600).map(|i| (f64::from(i) * 0.011 - 3.0, f64::from(i % 37) * 0.17 - 3.0)).unzip();
+        for s in SAMPLE_EXPRS {
+            let compiled = ExprParser::new().parse(s).unwrap().compile(&Definitions::new()).unwrap();
+            let mut swapped = vec![0.0; xs.len()];
+            compiled.eval_batch("y", "x", &ys, &xs, &mut swapped);
+            let bound = compiled.bind2_batch("x", "y");
+            let mut out = vec![0.0; xs.len()];
+            bound.eval_batch(&xs, &ys, &mut out);
+            for i in 0..xs.len() {
+                let expected = bound.eval(xs[i], ys[i]);
+                for &v in &[out[i], swapped[i]] {
+                    assert!(v == expected || (v.is_nan() && expected.is_nan()),
+                            "{} at ({}, {}) is {} in a batch, not {}", s, xs[i], ys[i], v, expected);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn checked_evaluation_says_what_left_its_domain() {
+        use expr_parser::ExprParser;
+        let env: HashMap<&str, f64> = [("x", 2.0)].iter().cloned().collect();
+        let checked = |s: &str| ExprParser::new().parse(s).unwrap().eval_checked(&env).map_err(|e| e.to_string());
+        assert_eq!(checked("ln(x - 3)"),
+                   Err("failed to evaluate expression: `ln(x - 3)` is undefined at -1".to_owned()));
+        assert_eq!(checked("arcsin(x)"),
+                   Err("failed to evaluate expression: `arcsin(x)` is undefined at 2".to_owned()));
+        assert_eq!(checked("1 + 3 / (x - 2)"),
+                   Err("failed to evaluate expression: `3 / (x - 2)` divides by zero".to_owned()));
+        assert_eq!(checked("sqrt(x - 1) + gamma(-x)"),
+                   Err("failed to evaluate expression: `gamma(-x)` is undefined at -2".to_owned()));
+        assert_eq!(checked("log(x - 2, 5)"),
+                   Err("failed to evaluate expression: `log(x - 2, 5)` is undefined at (0, 5)".to_owned()));
+        assert_eq!(checked("sqrt(x) * ln(x)"), Ok(2_f64.sqrt() * 2_f64.ln()));
+        // conditions only check the branch that's taken
+        assert_eq!(checked("if(x > 0, ln(x), ln(-x))"), Ok(2_f64.ln()));
+        assert!(ExprParser::new().parse("ln(x - 3)").unwrap().eval(&env).unwrap().is_nan());
+    }
+
+    #[test]
+    fn gradients_match_finite_differences() {
+        use expr_parser::ExprParser;
+        let mut sources: Vec<String> =
+            KNOWN_FUNCS.iter().map(|f| format!("{}(0.3x - 0.2y*x + 0.1)", f.name())).collect();
+        sources.extend(SAMPLE_EXPRS.iter().map(|s| s.to_string()));
+        let h = 1e-6;
+        for s in &sources {
+            let f = ExprParser::new().parse(s).unwrap().compile(&Definitions::new()).unwrap().bind2_batch("x", "y");
+            let mut checked = 0;
+            // off the axes, where `sqrt` and `cbrt` have infinite slopes
+            for (x, y) in sample_points().into_iter().map(|(x, y)| (x + 0.05, y + 0.05)) {
+                let d = f.eval_grad(x, y);
+                let v = f.eval(x, y);
+                assert!(d.v == v || (d.v.is_nan() && v.is_nan()), "{} at ({}, {}) is {}, not {}", s, x, y, d.v, v);
+                // central differences, as long as they agree with one-sided
+                // ones, which they don't across a jump or a kink
+                let slope = |a: f64, b: f64, c: f64| {
+                    let (back, mid, fwd) = ((b - a) / h, (c - a) / (2.0 * h), (c - b) / h);
+                    if (back - fwd).abs() <= 1e-3 * (1.0 + mid.abs()) { Some(mid) } else { None }
+                };
+                let dx = slope(f.eval(x - h, y), v, f.eval(x + h, y));
+                let dy = slope(f.eval(x, y - h), v, f.eval(x, y + h));
+                if let (Some(dx), Some(dy)) = (dx, dy) {
+                    for &(ad, fd, var) in &[(d.dx, dx, "x"), (d.dy, dy, "y")] {
+                        assert!((ad - fd).abs() <= 1e-5 * (1.0 + fd.abs()),
+                                "d/d{} of {} at ({}, {}) is {}, but about {} by differences", var, s, x, y, ad, fd);
+                    }
+                    checked += 1;
+                }
+            }
+            assert!(checked > 10, "{} could only be checked at {} points", s, checked);
+        }
+    }
+
+    #[test]
+    fn complex_evaluation() {
+        use expr_parser::ExprParser;
+        let complex = |s: &str| ExprParser::new().parse(s).unwrap().compile_complex(&Definitions::new()).unwrap();
+        let i = Complex64::i();
+        let cases: &[(&str, fn(Complex64) -> Complex64)] = &[
+            ("z^3 - 1", |z| z * z * z - 1.0),
+            ("exp(1/z)", |z| (1.0 / z).exp()),
+            ("sin(z) * cos(z) + tan(z)", |z| z.sin() * z.cos() + z.tan()),
+            ("ln(z) - sqrt(z)", |z| z.ln() - z.sqrt()),
+            ("i*z + x - 2y*i", |z| Complex64::i() * z + z.re - 2.0 * z.im * Complex64::i()),
+            ("z^(1/2) + 2^z", |z| z.powf(0.5) + Complex64::new(2.0, 0.0).powc(z)),
+        ];
+        let zs: Vec<_> = sample_points().into_iter().map(|(x, y)| Complex64::new(x + 0.05, y - 0.05)).collect();
+        for &(s, expected) in cases {
+            let f = complex(s);
+            let mut batch = vec![Complex64::new(0.0, 0.0); zs.len()];
+            f.eval_batch(&zs, &mut batch);
+            for (&z, &w) in zs.iter().zip(&batch) {
+                let (e, v) = (expected(z), f.eval(z));
+                assert!((v - e).norm() <= 1e-12 * (1.0 + e.norm()), "{} at {} is {}, not {}", s, z, v, e);
+                assert_eq!(w, v, "{} at {}, in a batch", s, z);
+            }
+        }
+        assert_eq!(complex("i^2").eval(i), Complex64::new(-1.0, 0.0));
+
+        // every function agrees with its real version on the real line, where
+        // that's defined, except that the principal cube root of a negative
+        // number isn't the real one
+        for f in KNOWN_FUNCS {
+            let g = complex(&format!("{}(z)", f.name()));
+            // within the range where `erf`'s series is accurate
+            for k in -12..13 {
+                let x = f64::from(k) * 0.23 + 0.01;
+                let (v, w) = (f.eval(x), g.eval(Complex64::new(x, 0.0)));
+                if v.is_finite() && !(f.name() == "cbrt" && x < 0.0) {
+                    assert!((w.re - v).abs() <= 1e-9 * (1.0 + v.abs()) && w.im.abs() <= 1e-9 * (1.0 + v.abs()),
+                            "{}({}) is {}, not {}", f.name(), x, w, v);
+                }
+            }
         }
     }
 }
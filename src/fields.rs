@@ -0,0 +1,476 @@
+#[cfg(feature = "render")]
+use cairo;
+use expr::{Bound2, Scratch};
+#[cfg(feature = "render")]
+use marching_squares;
+use marching_squares::{Point, Polyline, Segment, Style};
+use std::f64;
+use std::ops::Range;
+
+/// How fields are drawn
+pub const STYLE: Style = Style { color: (0.45, 0.45, 0.45), line_width: 1.0 };
+/// How trajectories through a field are drawn
+pub const TRAJECTORY_STYLE: Style = Style { color: (0.0, 0.5, 0.25), line_width: 1.5 };
+
+/// How long an arrow or slope mark may be, in cells
+const GLYPH_LENGTH: f64 = 0.8;
+/// How far streamlines move in a step, in cells
+const STREAM_STEP: f64 = 0.2;
+/// How many steps a streamline may run into its own recent steps, which are
+/// always close by
+const STREAM_NEARBY: i64 = 8;
+/// How far off each step of a trajectory may be, as a fraction of the view
+const TRAJECTORY_TOLERANCE: f64 = 1e-6;
+/// How far a trajectory may move in a step, as a fraction of the view, so it
+/// comes out smooth
+const TRAJECTORY_STEP: f64 = 0.01;
+/// How many steps a trajectory may take each way, as it might go round and
+/// round forever
+const TRAJECTORY_STEPS: usize = 5000;
+
+/// How a field `(P(x, y), Q(x, y))` is shown
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    /// an arrow at each point of a grid, scaled by the field's size there
+    Arrows,
+    /// curves that follow the field, spaced out evenly
+    Streamlines,
+    /// a mark at each point of a grid along the field's direction there, for
+    /// the slope field `(1, f(x, y))` of `dy/dx = f(x, y)`
+    Slopes,
+}
+
+impl Kind {
+    /// Recognizes a kind by its name, as given by `name`
+    pub fn from_name(name: &str) -> Option<Kind> {
+        match name {
+            "arrows" => Some(Kind::Arrows),
+            "streamlines" => Some(Kind::Streamlines),
+            "slopes" => Some(Kind::Slopes),
+            _ => None,
+        }
+    }
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Kind::Arrows => "arrows",
+            Kind::Streamlines => "streamlines",
+            Kind::Slopes => "slopes",
+        }
+    }
+}
+
+/// What to draw to show a field
+#[derive(Debug, Clone)]
+pub enum Glyphs {
+    /// from tail to head
+    Arrows(Vec<Segment>),
+    Streamlines(Vec<Polyline>),
+    Marks(Vec<Segment>),
+}
+
+/// Lays out glyphs showing `field.0` and `field.1` as the two components of
+/// a field over `xr` by `yr`, split into `across` by `down` cells which
+/// should look about square
+pub fn glyphs(field: &(Bound2, Bound2),
+              kind: Kind,
+              xr: &Range<f64>,
+              yr: &Range<f64>,
+              across: usize,
+              down: usize)
+              -> Glyphs {
+    let cells = Cells::new(xr, yr, across, down);
+    match kind {
+        Kind::Arrows => Glyphs::Arrows(arrows(field, &cells)),
+        Kind::Streamlines => Glyphs::Streamlines(streamlines(field, &cells)),
+        Kind::Slopes => Glyphs::Marks(marks(field, &cells)),
+    }
+}
+
+/// The view split into cells, and coordinates in cell widths from its corner
+struct Cells {
+    x0: f64,
+    y0: f64,
+    width: f64,
+    height: f64,
+    across: usize,
+    down: usize,
+}
+
+impl Cells {
+    fn new(xr: &Range<f64>, yr: &Range<f64>, across: usize, down: usize) -> Cells {
+        let (across, down) = (across.max(1), down.max(1));
+        Cells {
+            x0: xr.start,
+            y0: yr.start,
+            width: (xr.end - xr.start) / across as f64,
+            height: (yr.end - yr.start) / down as f64,
+            across: across,
+            down: down,
+        }
+    }
+    /// The middles of the cells
+    fn centres(&self) -> Vec<Point> {
+        let mut centres = Vec::with_capacity(self.across * self.down);
+        for i in 0..self.across {
+            for j in 0..self.down {
+                centres.push(self.to_plot((i as f64 + 0.5, j as f64 + 0.5)));
+            }
+        }
+        centres
+    }
+    fn to_plot(&self, (u, v): Point) -> Point {
+        (self.x0 + u * self.width, self.y0 + v * self.height)
+    }
+    /// `field` at `(u, v)`, in cells
+    fn at(&self, field: &(Bound2, Bound2), scratch: &mut Scratch, (u, v): Point) -> Point {
+        let (x, y) = self.to_plot((u, v));
+        let (p, q) = (field.0.eval_with(scratch, x, y), field.1.eval_with(scratch, x, y));
+        // a vertical slope
+        if p.is_finite() && q.is_infinite() {
+            return (0.0, q.signum());
+        }
+        (p / self.width, q / self.height)
+    }
+}
+
+fn arrows(field: &(Bound2, Bound2), cells: &Cells) -> Vec<Segment> {
+    let mut scratch = Scratch::new();
+    let vs: Vec<_> = cells.centres()
+        .into_iter()
+        .map(|c| (c, field.0.eval_with(&mut scratch, c.0, c.1), field.1.eval_with(&mut scratch, c.0, c.1)))
+        .collect();
+    // the longest arrow is as long as a glyph may be, and the others are
+    // scaled to match
+    let size = |p: f64, q: f64| (p / cells.width).hypot(q / cells.height);
+    let longest = vs.iter().map(|&(_, p, q)| size(p, q)).filter(|s| s.is_finite()).fold(0.0, f64::max);
+    if longest == 0.0 {
+        return Vec::new();
+    }
+    let k = GLYPH_LENGTH / longest / 2.0;
+    vs.into_iter()
+        .filter(|&(_, p, q)| size(p, q).is_finite() && size(p, q) > 0.0)
+        .map(|((x, y), p, q)| ((x - k * p, y - k * q), (x + k * p, y + k * q)))
+        .collect()
+}
+
+fn marks(field: &(Bound2, Bound2), cells: &Cells) -> Vec<Segment> {
+    let mut marks = Vec::new();
+    let mut scratch = Scratch::new();
+    for c in cells.centres() {
+        let u = ((c.0 - cells.x0) / cells.width, (c.1 - cells.y0) / cells.height);
+        let (du, dv) = cells.at(field, &mut scratch, u);
+        let len = du.hypot(dv);
+        if !len.is_finite() || len == 0.0 {
+            continue;
+        }
+        let (du, dv) = (du / len * GLYPH_LENGTH / 2.0, dv / len * GLYPH_LENGTH / 2.0);
+        marks.push((cells.to_plot((u.0 - du, u.1 - dv)), cells.to_plot((u.0 + du, u.1 + dv))));
+    }
+    marks
+}
+
+/// Traces streamlines from the middle of each cell that none has passed
+/// through yet, until they leave the view, stop, or come close to another
+fn streamlines(field: &(Bound2, Bound2), cells: &Cells) -> Vec<Polyline> {
+    // which streamline passed through each half cell, and at which step
+    let (cols, rows) = (2 * cells.across, 2 * cells.down);
+    let mut taken: Vec<Option<(usize, i64)>> = vec![None; cols * rows];
+    let slot = |(u, v): Point| -> Option<usize> {
+        if u < 0.0 || v < 0.0 || u >= cells.across as f64 || v >= cells.down as f64 {
+            return None;
+        }
+        Some((2.0 * v) as usize * cols + (2.0 * u) as usize)
+    };
+    // the field's direction, at unit speed
+    let mut scratch = Scratch::new();
+    let mut direction = |u: Point| -> Option<Point> {
+        let (du, dv) = cells.at(field, &mut scratch, u);
+        let len = du.hypot(dv);
+        if len.is_finite() && len > 0.0 {
+            Some((du / len, dv / len))
+        } else {
+            None
+        }
+    };
+    let max_steps = (4.0 * (cells.across + cells.down) as f64 / STREAM_STEP) as i64;
+
+    let mut lines = Vec::new();
+    let mut seeds = 0;
+    for i in 0..cells.across {
+        for j in 0..cells.down {
+            let seed = (i as f64 + 0.5, j as f64 + 0.5);
+            if slot(seed).map_or(true, |s| taken[s].is_some()) {
+                continue;
+            }
+            let id = seeds;
+            seeds += 1;
+            let mut halves = Vec::new();
+            // forwards, then backwards
+            for &sign in &[1.0, -1.0] {
+                let mut points = Vec::new();
+                let mut p = seed;
+                for n in 1..max_steps {
+                    let step = sign as i64 * n;
+                    let next = match rk4(&mut direction, p, sign * STREAM_STEP) {
+                        Some(next) => next,
+                        None => break,
+                    };
+                    let s = match slot(next) {
+                        Some(s) => s,
+                        None => break,
+                    };
+                    match taken[s] {
+                        Some((other, _)) if other != id => break,
+                        Some((_, at)) if (at - step).abs() > STREAM_NEARBY => break,
+                        _ => {}
+                    }
+                    taken[s] = Some((id, step));
+                    points.push(next);
+                    p = next;
+                }
+                halves.push(points);
+            }
+            let backwards = halves.pop().unwrap();
+            let forwards = halves.pop().unwrap();
+            // stubs squeezed in between other streamlines are just clutter
+            if ((forwards.len() + backwards.len()) as f64) * STREAM_STEP < 1.0 {
+                continue;
+            }
+            if let Some(s) = slot(seed) {
+                taken[s] = Some((id, 0));
+            }
+            let points = backwards.into_iter().rev()
+                .chain(Some(seed))
+                .chain(forwards)
+                .map(|u| cells.to_plot(u))
+                .collect();
+            lines.push(Polyline { points: points, closed: false });
+        }
+    }
+    lines
+}
+
+/// Follows the field `(field.0, field.1)` forwards and backwards from
+/// `start`, as the solution of `x' = field.0(x, y), y' = field.1(x, y)`
+/// through it, until it leaves `xr` by `yr`, stops, blows up or comes back
+/// round to `start`.
+///
+/// A slope field `(1, f(x, y))` gives the solution of `dy/dx = f(x, y)`.
+pub fn trajectory(field: &(Bound2, Bound2), start: Point, xr: &Range<f64>, yr: &Range<f64>) -> Polyline {
+    let (forwards, closed) = integrate(field, start, 1.0, xr, yr);
+    let mut points = if closed {
+        Vec::new()
+    } else {
+        let mut backwards = integrate(field, start, -1.0, xr, yr).0;
+        backwards.reverse();
+        backwards
+    };
+    points.push(start);
+    points.extend(forwards);
+    Polyline { points: points, closed: closed }
+}
+
+/// Integrates from `start` in the direction of `sign`, with adaptive steps
+/// of the Dormand-Prince method, leaving out `start` itself. Also says
+/// whether it came back round to `start`.
+fn integrate(field: &(Bound2, Bound2), start: Point, sign: f64, xr: &Range<f64>, yr: &Range<f64>)
+             -> (Vec<Point>, bool) {
+    let (width, height) = ((xr.end - xr.start).abs(), (yr.end - yr.start).abs());
+    let mut scratch = Scratch::new();
+    let mut f = |(x, y): Point| -> Option<Point> {
+        let (p, q) = (field.0.eval_with(&mut scratch, x, y), field.1.eval_with(&mut scratch, x, y));
+        if p.is_finite() && q.is_finite() {
+            Some((sign * p, sign * q))
+        } else {
+            None
+        }
+    };
+    // how far the error or a step is, as a fraction of the view
+    let size = |d: Point| (d.0 / width).abs().max((d.1 / height).abs());
+    let mut points = Vec::new();
+    let speed = match f(start) {
+        Some(v) if size(v) > 0.0 => size(v),
+        // it's a fixed point, or undefined
+        _ => return (points, false),
+    };
+    let mut h = TRAJECTORY_STEP / speed;
+    let min_h = h * 1e-12;
+    let mut p = start;
+    // whether it's been far enough from `start` to count as coming back
+    let mut left = false;
+    while points.len() < TRAJECTORY_STEPS && h > min_h {
+        let (next, err) = match dormand_prince(&mut f, p, h) {
+            Some(step) => step,
+            None => {
+                h /= 2.0;
+                continue;
+            }
+        };
+        let (err, moved) = (size(err), size((next.0 - p.0, next.1 - p.1)));
+        // the usual step size control, with the step kept short enough too
+        let mut factor = if err > 0.0 { 0.9 * (TRAJECTORY_TOLERANCE / err).powf(0.2) } else { 5.0 };
+        if moved > TRAJECTORY_STEP {
+            factor = factor.min(0.9 * TRAJECTORY_STEP / moved);
+        }
+        if err <= TRAJECTORY_TOLERANCE && moved <= TRAJECTORY_STEP {
+            p = next;
+            points.push(p);
+            let from_start = size((p.0 - start.0, p.1 - start.1));
+            left |= from_start > 2.0 * TRAJECTORY_STEP;
+            if left && from_start < TRAJECTORY_STEP {
+                return (points, true);
+            }
+            if !(xr.start.min(xr.end) <= p.0 && p.0 <= xr.start.max(xr.end) &&
+                 yr.start.min(yr.end) <= p.1 && p.1 <= yr.start.max(yr.end)) {
+                break;
+            }
+        }
+        h *= factor.max(0.2).min(5.0);
+    }
+    (points, false)
+}
+
+/// Takes a step of `h` from `p` along `f` by the Dormand-Prince method,
+/// giving where it ends up and an estimate of its error
+fn dormand_prince<F>(f: &mut F, p: Point, h: f64) -> Option<(Point, Point)>
+    where F: FnMut(Point) -> Option<Point>
+{
+    const A: [[f64; 6]; 6] = [[1.0 / 5.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+                              [3.0 / 40.0, 9.0 / 40.0, 0.0, 0.0, 0.0, 0.0],
+                              [44.0 / 45.0, -56.0 / 15.0, 32.0 / 9.0, 0.0, 0.0, 0.0],
+                              [19372.0 / 6561.0, -25360.0 / 2187.0, 64448.0 / 6561.0, -212.0 / 729.0, 0.0, 0.0],
+                              [9017.0 / 3168.0, -355.0 / 33.0, 46732.0 / 5247.0, 49.0 / 176.0, -5103.0 / 18656.0, 0.0],
+                              [35.0 / 384.0, 0.0, 500.0 / 1113.0, 125.0 / 192.0, -2187.0 / 6784.0, 11.0 / 84.0]];
+    // the fifth order weights are the last row of `A`, and these are the
+    // fourth order ones
+    const B4: [f64; 7] = [5179.0 / 57600.0, 0.0, 7571.0 / 16695.0, 393.0 / 640.0, -92097.0 / 339200.0,
+                          187.0 / 2100.0, 1.0 / 40.0];
+    let mut k = [(0.0, 0.0); 7];
+    k[0] = f(p)?;
+    for i in 0..6 {
+        let (mut dx, mut dy) = (0.0, 0.0);
+        for j in 0..i + 1 {
+            dx += A[i][j] * k[j].0;
+            dy += A[i][j] * k[j].1;
+        }
+        k[i + 1] = f((p.0 + h * dx, p.1 + h * dy))?;
+    }
+    // the last stage is at the fifth order solution
+    let (mut dx, mut dy) = (0.0, 0.0);
+    for j in 0..6 {
+        dx += A[5][j] * k[j].0;
+        dy += A[5][j] * k[j].1;
+    }
+    let (mut ex, mut ey) = (0.0, 0.0);
+    for j in 0..7 {
+        let b5 = if j < 6 { A[5][j] } else { 0.0 };
+        ex += (b5 - B4[j]) * k[j].0;
+        ey += (b5 - B4[j]) * k[j].1;
+    }
+    Some(((p.0 + h * dx, p.1 + h * dy), (h * ex, h * ey)))
+}
+
+/// Takes a classic Runge-Kutta step of `h` from `p` along `f`
+fn rk4<F>(f: &mut F, p: Point, h: f64) -> Option<Point>
+    where F: FnMut(Point) -> Option<Point>
+{
+    let k1 = f(p)?;
+    let k2 = f((p.0 + h / 2.0 * k1.0, p.1 + h / 2.0 * k1.1))?;
+    let k3 = f((p.0 + h / 2.0 * k2.0, p.1 + h / 2.0 * k2.1))?;
+    let k4 = f((p.0 + h * k3.0, p.1 + h * k3.1))?;
+    Some((p.0 + h / 6.0 * (k1.0 + 2.0 * k2.0 + 2.0 * k3.0 + k4.0),
+          p.1 + h / 6.0 * (k1.1 + 2.0 * k2.1 + 2.0 * k3.1 + k4.1)))
+}
+
+/// Draws `glyphs` onto `ctx` over whatever's there already, scaled so that
+/// `xr` by `yr` fills its clip region. Arrow heads are sized to their arrows,
+/// which are sized to the view.
+#[cfg(feature = "render")]
+pub fn draw(ctx: &cairo::Context, glyphs: &Glyphs, xr: &Range<f64>, yr: &Range<f64>) -> Result<(), cairo::Error> {
+    ctx.save()?;
+    // everything is laid out in plot coordinates but drawn in the caller's,
+    // so arrow heads keep their shape however the view is stretched
+    let unscaled = marching_squares::to_plot(ctx, xr, yr);
+    let device = |p: Point| ctx.user_to_device(p.0, p.1);
+    let lines: Vec<Vec<Point>> = match *glyphs {
+        Glyphs::Arrows(ref segs) | Glyphs::Marks(ref segs) => {
+            segs.iter().map(|&(a, b)| vec![device(a), device(b)]).collect()
+        }
+        Glyphs::Streamlines(ref lines) => {
+            lines.iter().map(|l| l.points.iter().map(|&p| device(p)).collect()).collect()
+        }
+    };
+    ctx.set_matrix(unscaled);
+    for line in &lines {
+        let line = line.iter().map(|&(x, y)| ctx.device_to_user(x, y)).collect::<Result<Vec<_>, _>>()?;
+        ctx.move_to(line[0].0, line[0].1);
+        for p in &line[1..] {
+            ctx.line_to(p.0, p.1);
+        }
+        match *glyphs {
+            Glyphs::Arrows(_) => {
+                let (tail, tip) = (line[0], line[1]);
+                let len = (tip.0 - tail.0).hypot(tip.1 - tail.1);
+                head(ctx, tip, ((tip.0 - tail.0) / len, (tip.1 - tail.1) / len), len * 0.3);
+            }
+            // one head halfway along shows which way it goes
+            Glyphs::Streamlines(_) => {
+                let mid = line.len() / 2;
+                let (a, b) = (line[mid - 1], line[mid]);
+                let len = (b.0 - a.0).hypot(b.1 - a.1);
+                if len > 0.0 {
+                    head(ctx, b, ((b.0 - a.0) / len, (b.1 - a.1) / len), 6.0);
+                }
+            }
+            Glyphs::Marks(_) => {}
+        }
+    }
+    ctx.set_source_rgb(STYLE.color.0, STYLE.color.1, STYLE.color.2);
+    ctx.set_line_width(STYLE.line_width);
+    ctx.stroke()?;
+    ctx.restore()
+}
+
+/// Adds the two barbs of an arrow head `size` long at `tip`, pointing along
+/// the unit vector `dir`
+#[cfg(feature = "render")]
+fn head(ctx: &cairo::Context, tip: Point, dir: Point, size: f64) {
+    // about 25 degrees either side of the shaft
+    let (cos, sin) = (0.906, 0.423);
+    for &s in &[sin, -sin] {
+        let back = (dir.0 * cos - dir.1 * s, dir.0 * s + dir.1 * cos);
+        ctx.move_to(tip.0, tip.1);
+        ctx.line_to(tip.0 - size * back.0, tip.1 - size * back.1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use compile_field_of_kind;
+
+    #[test]
+    fn rotation_comes_back_round() {
+        let field = compile_field_of_kind(Kind::Arrows, "-y", "x", "").unwrap();
+        let line = trajectory(&field, (1.0, 0.0), &(-2.0..2.0), &(-2.0..2.0));
+        assert!(line.closed);
+        for &(x, y) in &line.points {
+            assert!((x.hypot(y) - 1.0).abs() < 1e-6, "({}, {}) is off the unit circle", x, y);
+        }
+    }
+
+    #[test]
+    fn slopes_follow_the_solution() {
+        let field = compile_field_of_kind(Kind::Slopes, "y", "", "").unwrap();
+        let line = trajectory(&field, (0.0, 1.0), &(-2.0..2.0), &(-1.0..10.0));
+        assert!(!line.closed);
+        // all the way across the view, which it never leaves at the top
+        let first = line.points[0].0;
+        let last = line.points[line.points.len() - 1].0;
+        assert!(first < -1.99 && last > 1.99, "only goes from {} to {}", first, last);
+        for &(x, y) in &line.points {
+            assert!((y - x.exp()).abs() < 1e-4 * x.exp(), "({}, {}) is off y = e^x", x, y);
+        }
+    }
+}
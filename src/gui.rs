@@ -0,0 +1,1226 @@
+use std::error::Error;
+use std::rc::Rc;
+use std::cell::{Cell, RefCell};
+use std::ops::Range;
+use std::path::Path;
+use std::slice;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use fnv::FnvHashMap;
+use gtk;
+use gtk::cairo;
+use gtk::gdk;
+use gtk::glib;
+use gtk::prelude::*;
+use plotany::{data_export, domain_colouring, expr, export, fields, intersections, marching_squares, session, tiles, zero_sets};
+use plotany::{compile_complex, compile_curve, compile_field_of_kind, explain_undefined, trace_tile, trajectory_label,
+              undefined_in_view};
+
+macro_rules! get_objects_from_builder {
+    ($b:ident, $($n:ident : $t:ty),*) => {
+        $(
+            let $n : $t = $b.object(stringify!($n))
+                .expect(concat!("Failed to get `", stringify!($n), "`",
+                                " from `", stringify!($b), "`"));
+        )*
+    }
+}
+macro_rules! cloning {
+    ($($n:ident),+ => $body:expr) => {{
+        $( let $n = $n.clone(); )+
+        $body
+    }}
+}
+
+/// Runs the GUI, starting from the session at `session` if there is one
+pub fn main0(session: Option<&Path>) -> Result<(), Box<Error>> {
+    let session = match session {
+        Some(path) => Some(session::Session::load(path)?),
+        None => None,
+    };
+
+    // let eqn_string = "cos(x) + cos(y) = 1/2";
+    // let eqn = expr_parser::parse_Equation(eqn_string);
+    // let eqn = eqn.expect("aaaaaaa");
+    // println!("{:?}", eqn);
+
+    gtk::init().expect("failed to initialize GTK.");
+    let builder = gtk::Builder::from_string(include_str!("layout.glade"));
+    get_objects_from_builder!(builder,
+                              window: gtk::Window,
+                              drawing: gtk::DrawingArea,
+                              implicit_eqn_entry: gtk::Entry,
+                              definitions_view: gtk::TextView,
+                              field_combo: gtk::ComboBoxText,
+                              field_p_label: gtk::Label,
+                              field_p_entry: gtk::Entry,
+                              field_q_label: gtk::Label,
+                              field_q_entry: gtk::Entry,
+                              trajectories_view: gtk::TreeView,
+                              trajectories_store: gtk::ListStore,
+                              remove_trajectory_btn: gtk::Button,
+                              clear_trajectories_btn: gtk::Button,
+                              second_eqn_entry: gtk::Entry,
+                              intersect_btn: gtk::Button,
+                              copy_intersections_btn: gtk::Button,
+                              x_min_entry: gtk::SpinButton,
+                              x_max_entry: gtk::SpinButton,
+                              y_min_entry: gtk::SpinButton,
+                              y_max_entry: gtk::SpinButton,
+                              plot_btn: gtk::Button,
+                              live_check: gtk::CheckButton,
+                              complex_check: gtk::CheckButton,
+                              zero_sets_check: gtk::CheckButton,
+                              plot_spinner: gtk::Spinner,
+                              undefined_label: gtk::Label,
+                              info_bar: gtk::InfoBar,
+                              info_label: gtk::Label,
+                              info_bar_revealer: gtk::Revealer,
+                              open_item: gtk::MenuItem,
+                              save_item: gtk::MenuItem,
+                              export_item: gtk::MenuItem,
+                              copy_item: gtk::MenuItem,
+                              export_data_item: gtk::MenuItem);
+
+    window.connect_delete_event(|_, _| {
+        gtk::main_quit();
+        Inhibit(false)
+    });
+
+    // the curve's equation and domain, with the source they were compiled from
+    let eq: Rc<RefCell<Option<(String, Arc<(expr::Bound2, expr::Bound2)>)>>> = Default::default();
+    // or the function of `z` to colour the plane by, likewise
+    let complex: Rc<RefCell<Option<(String, Arc<expr::ComplexExpr>)>>> = Default::default();
+    // the last colouring worked out for it, painted until the view's is
+    let coloured: Rc<RefCell<Option<Coloured>>> = Default::default();
+    // where its real and imaginary parts are zero, in the tiles traced so far
+    // and all together in view, if they're wanted
+    let zero_tiles: Rc<RefCell<Plotted<zero_sets::ZeroSets>>> = Default::default();
+    let zeros: Rc<RefCell<Option<zero_sets::ZeroSets>>> = Default::default();
+    // the field drawn under the curve, and how
+    let field: Rc<RefCell<Option<(fields::Kind, Arc<(expr::Bound2, expr::Bound2)>)>>> = Default::default();
+    // its glyphs laid out over the view
+    let glyphs: Rc<RefCell<Option<fields::Glyphs>>> = Default::default();
+    // the points the field's trajectories go through, as listed in
+    // `trajectories_store`
+    let trajectories: Rc<RefCell<Vec<(f64, f64)>>> = Default::default();
+    // the points they were last traced through over the view, and the
+    // trajectories themselves
+    let traced_trajectories: Rc<RefCell<(Vec<(f64, f64)>, Vec<marching_squares::Polyline>)>> = Default::default();
+    // the curve to intersect the first with, likewise, and its tiles traced
+    // so far
+    let other: Rc<RefCell<Option<(String, Arc<(expr::Bound2, expr::Bound2)>)>>> = Default::default();
+    let other_plotted: Rc<RefCell<Plotted>> = Default::default();
+    // where the two curves were last found to meet
+    let meeting_points: Rc<RefCell<Vec<marching_squares::Point>>> = Default::default();
+    let x_range: Rc<RefCell<Range<f64>>> = Rc::new(RefCell::new(-10.0..10.0));
+    let y_range: Rc<RefCell<Range<f64>>> = Rc::new(RefCell::new(-10.0..10.0));
+    // the tiles traced so far
+    let plotted: Rc<RefCell<Plotted>> = Default::default();
+    let style: Rc<Cell<marching_squares::Style>> = Default::default();
+    // set to cancel the tracing currently going on, and likewise for
+    // trajectories
+    let job: Rc<RefCell<Arc<AtomicBool>>> = Default::default();
+    let trajectory_job: Rc<RefCell<Arc<AtomicBool>>> = Default::default();
+    // what's waiting for the tiles in view to be refined, such as exports
+    let waiting: Rc<RefCell<Vec<Box<Fn()>>>> = Default::default();
+    let (done_tx, done_rx) = glib::MainContext::channel::<Traced>(glib::PRIORITY_DEFAULT);
+
+    {
+        use std::f64::MAX;
+        x_min_entry.set_range(-MAX, MAX);
+        x_min_entry.set_value(-10.0);
+        x_max_entry.set_range(-MAX, MAX);
+        x_max_entry.set_value(10.0);
+        y_min_entry.set_range(-MAX, MAX);
+        y_min_entry.set_value(-10.0);
+        y_max_entry.set_range(-MAX, MAX);
+        y_max_entry.set_value(10.0);
+    }
+
+    let definitions_buffer = definitions_view.buffer().expect("`definitions_view` has no buffer");
+
+    // puts together the zero sets of the tiles in view traced so far
+    let gather_zeros = Rc::new(cloning!(complex, zero_tiles, zeros, zero_sets_check, x_range, y_range => move || {
+        let zero_tiles = zero_tiles.borrow();
+        let current = match *complex.borrow() {
+            Some((ref source, _)) => zero_tiles.source.as_ref() == Some(source),
+            None => false,
+        };
+        let keys = tiles::covering(&x_range.borrow(), &y_range.borrow());
+        *zeros.borrow_mut() = match keys.first() {
+            Some(&key) if current && zero_sets_check.is_active() => {
+                Some(zero_sets::merge(keys.iter().filter_map(|k| zero_tiles.tiles.get(k)).map(|t| &t.1), key))
+            }
+            _ => None,
+        };
+    }));
+
+    // lays the field's glyphs out over the view in about square cells, two
+    // dozen across
+    let reglyph = Rc::new(cloning!(field, glyphs, x_range, y_range, drawing => move || {
+        let (w, h) = (f64::from(drawing.allocated_width()), f64::from(drawing.allocated_height()));
+        *glyphs.borrow_mut() = match *field.borrow() {
+            // there's nowhere to put them until the plot has a size
+            Some((kind, ref f)) if w > 0.0 && h > 0.0 => {
+                let across = 24;
+                let down = (across as f64 * h / w).round().max(1.0) as usize;
+                Some(fields::glyphs(f, kind, &x_range.borrow(), &y_range.borrow(), across, down))
+            }
+            _ => None,
+        };
+    }));
+
+    // traces the field's trajectories over the view on a worker thread,
+    // abandoning any still being traced
+    let retrace = Rc::new(
+        cloning!(field, trajectories, traced_trajectories, trajectory_job, x_range, y_range, done_tx, drawing => move || {
+            trajectory_job.borrow().store(true, Ordering::Relaxed);
+            let cancelled = Arc::new(AtomicBool::new(false));
+            *trajectory_job.borrow_mut() = cancelled.clone();
+            let f = match *field.borrow() {
+                Some((_, ref f)) => f.clone(),
+                None => {
+                    *traced_trajectories.borrow_mut() = Default::default();
+                    drawing.queue_draw();
+                    return;
+                }
+            };
+            let starts = trajectories.borrow().clone();
+            let (xr, yr) = (x_range.borrow().clone(), y_range.borrow().clone());
+            let done_tx = done_tx.clone();
+            thread::spawn(move || {
+                let mut lines = Vec::with_capacity(starts.len());
+                for &start in &starts {
+                    if cancelled.load(Ordering::Relaxed) {
+                        return;
+                    }
+                    lines.push(fields::trajectory(&f, start, &xr, &yr));
+                }
+                let _ = done_tx.send(Traced::Trajectories(cancelled, starts, lines));
+            });
+        }));
+
+    // traces the tiles in view, and colours the plane if that's wanted, on a
+    // worker thread, abandoning any work still in progress
+    let start_plot = Rc::new(
+        cloning!(eq, complex, coloured, zero_tiles, gather_zeros, other, other_plotted, x_range, y_range, plotted, job, waiting, plot_spinner, undefined_label, implicit_eqn_entry, definitions_buffer, complex_check, zero_sets_check, drawing => move || {
+            job.borrow().store(true, Ordering::Relaxed);
+            let cancelled = Arc::new(AtomicBool::new(false));
+            *job.borrow_mut() = cancelled.clone();
+            drawing.queue_draw();
+            let (xr, yr) = (x_range.borrow().clone(), y_range.borrow().clone());
+            // one sample per device pixel, unless the view's already coloured
+            let scale = drawing.scale_factor();
+            let (width, height) = ((drawing.allocated_width() * scale) as usize,
+                                   (drawing.allocated_height() * scale) as usize);
+            let colouring = match *complex.borrow() {
+                Some((ref source, ref f)) if complex_check.is_active() && width > 0 && height > 0 => {
+                    let done = coloured.borrow().as_ref().map_or(false, |c| {
+                        c.source == *source && c.x_range == xr && c.y_range == yr &&
+                        (c.image.width() as usize, c.image.height() as usize) == (width, height)
+                    });
+                    if done { None } else { Some((source.clone(), f.clone())) }
+                }
+                _ => None,
+            };
+            let keys = tiles::covering(&xr, &yr);
+            // the tiles of the zero sets to trace, and how many cells across
+            // each has been traced on already
+            let zero_tracing = match *complex.borrow() {
+                Some((ref source, ref f)) if zero_sets_check.is_active() => {
+                    zero_tiles.borrow_mut().start(source, &keys).map(|had| (source.clone(), f.clone(), had))
+                }
+                _ => {
+                    *zero_tiles.borrow_mut() = Default::default();
+                    None
+                }
+            };
+            gather_zeros();
+            // likewise for the curve, which is also checked for being
+            // undefined everywhere in view, along with its source text to
+            // say why
+            let mut tracing = None;
+            let mut checking = None;
+            let mut plotted = plotted.borrow_mut();
+            match *eq.borrow() {
+                Some((ref source, ref eq)) => {
+                    let defs_text = definitions_text(&definitions_buffer);
+                    checking = Some((eq.clone(), implicit_eqn_entry.text().as_str().to_owned(), defs_text));
+                    tracing = plotted.start(source, &keys).map(|had| (source.clone(), eq.clone(), had));
+                }
+                None => {
+                    *plotted = Default::default();
+                    undefined_label.hide();
+                }
+            }
+            // and for the curve to intersect it with
+            let other_tracing = match *other.borrow() {
+                Some((ref source, ref g)) => {
+                    other_plotted.borrow_mut().start(source, &keys).map(|had| (source.clone(), g.clone(), had))
+                }
+                None => {
+                    *other_plotted.borrow_mut() = Default::default();
+                    None
+                }
+            };
+            if colouring.is_none() && zero_tracing.is_none() && checking.is_none() && tracing.is_none() &&
+               other_tracing.is_none() {
+                // everything's refined already, so there's no need to wait
+                drop(plotted);
+                plot_spinner.stop();
+                run_waiting(&waiting);
+                return;
+            }
+            let done_tx = done_tx.clone();
+            plot_spinner.start();
+            thread::spawn(move || {
+                // rather than leave the plot mysteriously empty, say why it is
+                if let Some((eq, text, defs_text)) = checking {
+                    let why = if undefined_in_view(&eq, &xr, &yr) {
+                        let (x, y) = ((xr.start + xr.end) / 2.0, (yr.start + yr.end) / 2.0);
+                        let what = "The equation is undefined everywhere in view";
+                        Some(match explain_undefined(&text, &defs_text, x, y) {
+                            Some(why) => format!("{}\n(in the middle, {})", what, why),
+                            None => what.to_owned(),
+                        })
+                    } else {
+                        None
+                    };
+                    if done_tx.send(Traced::Undefined(cancelled.clone(), why)).is_err() {
+                        return;
+                    }
+                }
+                if let Some((source, f)) = colouring {
+                    let pixels = match domain_colouring::sample(&f, &xr, width, &yr, height, &cancelled) {
+                        Some(values) => domain_colouring::pixels(&values),
+                        None => return,
+                    };
+                    // the window may have closed in the meantime
+                    if done_tx.send(Traced::Colouring(source, xr, yr, width, height, pixels)).is_err() {
+                        return;
+                    }
+                }
+                if let Some((source, f, had)) = zero_tracing {
+                    let traced = trace_tiles(&keys, &had, &done_tx, |key, cells| {
+                        zero_sets::trace_tile(&f, key, cells, &cancelled)
+                            .map(|sets| Traced::Zeros(source.clone(), key, cells, sets))
+                    });
+                    if !traced {
+                        return;
+                    }
+                }
+                if let Some((source, eq, had)) = tracing {
+                    let traced = trace_tiles(&keys, &had, &done_tx, |key, cells| {
+                        trace_tile(&eq, key, cells, &cancelled)
+                            .map(|segments| Traced::Tile(source.clone(), key, cells, segments))
+                    });
+                    if !traced {
+                        return;
+                    }
+                }
+                if let Some((source, g, had)) = other_tracing {
+                    let traced = trace_tiles(&keys, &had, &done_tx, |key, cells| {
+                        trace_tile(&g, key, cells, &cancelled)
+                            .map(|segments| Traced::OtherTile(source.clone(), key, cells, segments))
+                    });
+                    if !traced {
+                        return;
+                    }
+                }
+                let _ = done_tx.send(Traced::Finished(cancelled));
+            });
+        }));
+    // the glyphs' layout and the colouring's resolution follow the plot's size
+    drawing.connect_size_allocate(cloning!(reglyph, start_plot => move |_, _| {
+        reglyph();
+        start_plot();
+    }));
+
+    done_rx.attach(None, cloning!(plotted, other_plotted, complex, coloured, zero_tiles, gather_zeros, traced_trajectories, trajectory_job, job, waiting, plot_spinner, undefined_label, info_label, info_bar_revealer, drawing => move |traced| {
+        match traced {
+            Traced::Tile(source, key, cells, segments) => {
+                if plotted.borrow_mut().insert(&source, key, cells, segments) {
+                    drawing.queue_draw();
+                }
+            }
+            Traced::OtherTile(source, key, cells, segments) => {
+                if other_plotted.borrow_mut().insert(&source, key, cells, segments) {
+                    drawing.queue_draw();
+                }
+            }
+            Traced::Zeros(source, key, cells, sets) => {
+                // the borrow has to end before they're gathered up
+                let kept = zero_tiles.borrow_mut().insert(&source, key, cells, sets);
+                if kept {
+                    gather_zeros();
+                    drawing.queue_draw();
+                }
+            }
+            Traced::Colouring(source, xr, yr, width, height, pixels) => {
+                // a colouring of a function that's since been replaced is
+                // useless, but any of the same function replaces the last
+                if complex.borrow().as_ref().map_or(false, |c| c.0 == source) {
+                    match domain_colouring::image(&pixels, width, height) {
+                        Ok(image) => {
+                            *coloured.borrow_mut() = Some(Coloured {
+                                source: source,
+                                x_range: xr,
+                                y_range: yr,
+                                image: image,
+                            });
+                            drawing.queue_draw();
+                        }
+                        Err(e) => {
+                            info_label.set_text(&format!("couldn't colour the plane: {}", e));
+                            info_bar_revealer.set_reveal_child(true);
+                        }
+                    }
+                }
+            }
+            Traced::Trajectories(cancelled, starts, lines) => {
+                if Arc::ptr_eq(&cancelled, &*trajectory_job.borrow()) {
+                    *traced_trajectories.borrow_mut() = (starts, lines);
+                    drawing.queue_draw();
+                }
+            }
+            Traced::Undefined(cancelled, why) => {
+                if Arc::ptr_eq(&cancelled, &*job.borrow()) {
+                    match why {
+                        Some(why) => {
+                            undefined_label.set_text(&why);
+                            undefined_label.show();
+                        }
+                        None => undefined_label.hide(),
+                    }
+                }
+            }
+            Traced::Finished(cancelled) => {
+                if Arc::ptr_eq(&cancelled, &*job.borrow()) {
+                    plot_spinner.stop();
+                    run_waiting(&waiting);
+                }
+            }
+        }
+        glib::Continue(true)
+    }));
+
+    // whether every tile in view has been refined: of the curve, of the one
+    // to intersect it with, and of the zero sets if they're wanted
+    let refined = Rc::new(
+        cloning!(eq, other, complex, plotted, other_plotted, zero_tiles, zero_sets_check, x_range, y_range => move || {
+            let keys = tiles::covering(&x_range.borrow(), &y_range.borrow());
+            let curve = eq.borrow().as_ref().map_or(true, |f| plotted.borrow().fine(&f.0, &keys).is_some());
+            let other = other.borrow().as_ref().map_or(true, |g| other_plotted.borrow().fine(&g.0, &keys).is_some());
+            let zeros = match *complex.borrow() {
+                Some((ref source, _)) if zero_sets_check.is_active() => {
+                    zero_tiles.borrow().fine(source, &keys).is_some()
+                }
+                _ => true,
+            };
+            curve && other && zeros
+        }));
+    // runs `then` right away if the tiles in view are refined, and otherwise
+    // once the worker has refined them, over the view as it is then
+    let when_refined = Rc::new(cloning!(refined, waiting => move |then: Box<Fn()>| {
+        if refined() {
+            then();
+        } else {
+            waiting.borrow_mut().push(then);
+        }
+    }));
+
+    // the segments of the curve in view, and of the one to intersect it
+    // with, once they've been refined
+    let view_segments = Rc::new(cloning!(eq, plotted, x_range, y_range => move || {
+        let keys = tiles::covering(&x_range.borrow(), &y_range.borrow());
+        match *eq.borrow() {
+            Some((ref source, _)) => {
+                let plotted = plotted.borrow();
+                plotted.fine(source, &keys).map_or_else(Vec::new, |t| t.into_iter().flat_map(|s| s.to_vec()).collect())
+            }
+            None => Vec::new(),
+        }
+    }));
+    let view_other_segments = Rc::new(cloning!(other, other_plotted, x_range, y_range => move || {
+        let keys = tiles::covering(&x_range.borrow(), &y_range.borrow());
+        match *other.borrow() {
+            Some((ref source, _)) => {
+                let other_plotted = other_plotted.borrow();
+                other_plotted.fine(source, &keys).map_or_else(Vec::new, |t| t.into_iter().flat_map(|s| s.to_vec()).collect())
+            }
+            None => Vec::new(),
+        }
+    }));
+
+    // moves the view to `xr` by `yr` and plots what's there
+    let set_view = Rc::new(
+        cloning!(x_range, y_range, x_min_entry, x_max_entry, y_min_entry, y_max_entry, reglyph, retrace, start_plot => move |xr: Range<f64>, yr: Range<f64>| {
+            x_min_entry.set_value(xr.start);
+            x_max_entry.set_value(xr.end);
+            y_min_entry.set_value(yr.start);
+            y_max_entry.set_value(yr.end);
+            *x_range.borrow_mut() = xr;
+            *y_range.borrow_mut() = yr;
+            reglyph();
+            retrace();
+            start_plot();
+        }));
+
+    // re-parses the inputs and plots them. `live` re-plots happen while the
+    // user is still typing, so they keep the last valid plot on an error and
+    // don't re-plot if nothing's changed.
+    let replot = Rc::new(
+        cloning!(eq, complex, other, meeting_points, x_range, y_range, x_min_entry, x_max_entry, y_min_entry, y_max_entry, implicit_eqn_entry, second_eqn_entry, definitions_buffer, complex_check, zero_sets_check, info_bar_revealer, info_label, reglyph, retrace, start_plot => move |live: bool| {
+            // println!("{:?}", implicit_eqn_entry.get_text());
+            let changed = {
+                let defs_text = definitions_text(&definitions_buffer);
+                let text = implicit_eqn_entry.text();
+                let source = format!("{}\n{}", defs_text, text);
+                // only one of the two is plotted at a time
+                let new_eq = if complex_check.is_active() || zero_sets_check.is_active() {
+                    compile_complex(text.as_str(), &defs_text).map(|ce| (None, Some(Arc::new(ce))))
+                } else {
+                    compile_curve(text.as_str(), &defs_text).map(|ne| (Some(Arc::new(ne)), None))
+                };
+                let mut eq = eq.borrow_mut();
+                let mut complex = complex.borrow_mut();
+                match new_eq {
+                    Ok((ne, ce)) => {
+                        implicit_eqn_entry.set_icon_from_icon_name(gtk::EntryIconPosition::Secondary, None);
+                        info_bar_revealer.set_reveal_child(false);
+                        let same = eq.as_ref().map_or(false, |eq| eq.0 == source) == ne.is_some() &&
+                                   complex.as_ref().map_or(false, |c| c.0 == source) == ce.is_some();
+                        *eq = ne.map(|ne| (source.clone(), ne));
+                        *complex = ce.map(|ce| (source, ce));
+                        !same
+                    }
+                    Err(e) => {
+                        implicit_eqn_entry.set_icon_from_icon_name(gtk::EntryIconPosition::Secondary,
+                                                                   Some("dialog-error-symbolic"));
+                        implicit_eqn_entry.set_icon_tooltip_text(gtk::EntryIconPosition::Secondary, Some(&e));
+                        if live {
+                            false
+                        } else {
+                            info_label.set_text(&e);
+                            info_bar_revealer.set_reveal_child(true);
+                            *eq = None;
+                            *complex = None;
+                            true
+                        }
+                    }
+                }
+            };
+            // the curve to intersect with is only ever a help, so the last
+            // good one is kept until it's fixed
+            let other_changed = {
+                let defs_text = definitions_text(&definitions_buffer);
+                let text = second_eqn_entry.text();
+                let source = format!("{}\n{}", defs_text, text);
+                let new_other = if text.as_str().trim().is_empty() {
+                    Ok(None)
+                } else {
+                    compile_curve(text.as_str(), &defs_text).map(|g| Some((source, Arc::new(g))))
+                };
+                match new_other {
+                    Ok(g) => {
+                        second_eqn_entry.set_icon_from_icon_name(gtk::EntryIconPosition::Secondary, None);
+                        let mut other = other.borrow_mut();
+                        let same = other.as_ref().map(|o| &o.0) == g.as_ref().map(|g| &g.0);
+                        *other = g;
+                        !same
+                    }
+                    Err(e) => {
+                        second_eqn_entry.set_icon_from_icon_name(gtk::EntryIconPosition::Secondary,
+                                                                 Some("dialog-error-symbolic"));
+                        second_eqn_entry.set_icon_tooltip_text(gtk::EntryIconPosition::Secondary, Some(&e));
+                        false
+                    }
+                }
+            };
+            if changed || other_changed {
+                meeting_points.borrow_mut().clear();
+            }
+            let xr = x_min_entry.value() .. x_max_entry.value();
+            let yr = y_min_entry.value() .. y_max_entry.value();
+            let moved = *x_range.borrow() != xr || *y_range.borrow() != yr;
+            *x_range.borrow_mut() = xr;
+            *y_range.borrow_mut() = yr;
+            if moved {
+                reglyph();
+                retrace();
+            }
+            if changed || other_changed || moved || !live {
+                start_plot();
+            }
+        }));
+
+    // the pending live re-plot, if there is one
+    let pending: Rc<RefCell<Option<glib::SourceId>>> = Default::default();
+    // re-plots once the user stops typing for a moment, if they've asked to
+    let replot_soon = Rc::new(cloning!(pending, replot, live_check => move || {
+        if !live_check.is_active() {
+            return;
+        }
+        if let Some(id) = pending.borrow_mut().take() {
+            glib::source_remove(id);
+        }
+        let id = glib::timeout_add_local(Duration::from_millis(300), cloning!(pending, replot => move || {
+            // this source is about to go away by itself
+            pending.borrow_mut().take();
+            replot(true);
+            glib::Continue(false)
+        }));
+        *pending.borrow_mut() = Some(id);
+    }));
+
+    // re-parses the field and redraws it. Fields are quick to draw, so this
+    // happens as soon as anything about them changes.
+    let refield = Rc::new(
+        cloning!(field, reglyph, retrace, field_combo, field_p_label, field_p_entry, field_q_label, field_q_entry, definitions_buffer, drawing => move || {
+            let kind = field_combo.active_id().and_then(|id| fields::Kind::from_name(id.as_str()));
+            let slopes = kind == Some(fields::Kind::Slopes);
+            field_p_label.set_text(if slopes { "dy/dx=" } else { "P(x, y)=" });
+            field_q_label.set_visible(!slopes);
+            field_q_entry.set_visible(!slopes);
+            let kind = match kind {
+                Some(kind) => kind,
+                None => {
+                    *field.borrow_mut() = None;
+                    reglyph();
+                    retrace();
+                    drawing.queue_draw();
+                    return;
+                }
+            };
+            let defs_text = definitions_text(&definitions_buffer);
+            let (p, q) = (field_p_entry.text(), field_q_entry.text());
+            match compile_field_of_kind(kind, p.as_str(), q.as_str(), &defs_text) {
+                Ok(f) => {
+                    field_p_entry.set_icon_from_icon_name(gtk::EntryIconPosition::Secondary, None);
+                    *field.borrow_mut() = Some((kind, Arc::new(f)));
+                }
+                // keep the last good field until this one's fixed
+                Err(e) => {
+                    field_p_entry.set_icon_from_icon_name(gtk::EntryIconPosition::Secondary,
+                                                          Some("dialog-error-symbolic"));
+                    field_p_entry.set_icon_tooltip_text(gtk::EntryIconPosition::Secondary, Some(&e));
+                }
+            }
+            reglyph();
+            retrace();
+            drawing.queue_draw();
+        }));
+
+    intersect_btn.connect_clicked(cloning!(eq, other, plotted, other_plotted, meeting_points, x_range, y_range, when_refined, info_label, info_bar_revealer, drawing => move |_| {
+        if eq.borrow().is_none() || other.borrow().is_none() {
+            info_label.set_text("finding intersections needs two curves");
+            info_bar_revealer.set_reveal_child(true);
+            return;
+        }
+        when_refined(Box::new(cloning!(eq, other, plotted, other_plotted, meeting_points, x_range, y_range, info_label, info_bar_revealer, drawing => move || {
+            let (xr, yr) = (x_range.borrow().clone(), y_range.borrow().clone());
+            let keys = tiles::covering(&xr, &yr);
+            let (plotted, other_plotted) = (plotted.borrow(), other_plotted.borrow());
+            let found = match (eq.borrow().as_ref(), other.borrow().as_ref()) {
+                (Some(&(ref f_source, ref f)), Some(&(ref g_source, ref g))) => {
+                    match (plotted.fine(f_source, &keys), other_plotted.fine(g_source, &keys)) {
+                        (Some(fs), Some(gs)) => {
+                            let traced = keys.iter().zip(fs).zip(gs).map(|((&key, fs), gs)| {
+                                (key, &fs[..], &gs[..])
+                            });
+                            intersections::find(f, g, traced, &xr, &yr)
+                        }
+                        _ => return,
+                    }
+                }
+                // one of the curves has been cleared in the meantime
+                _ => return,
+            };
+            if found.is_empty() {
+                info_label.set_text("the curves don't meet anywhere in view");
+                info_bar_revealer.set_reveal_child(true);
+            }
+            *meeting_points.borrow_mut() = found;
+            drawing.queue_draw();
+        })));
+    }));
+    copy_intersections_btn.connect_clicked(cloning!(meeting_points, info_label, info_bar_revealer => move |_| {
+        let points = meeting_points.borrow();
+        if points.is_empty() {
+            info_label.set_text("there are no intersections to copy");
+            info_bar_revealer.set_reveal_child(true);
+            return;
+        }
+        // one point per line, in full rather than as labelled
+        let text: String = points.iter().map(|&(x, y)| format!("{}, {}\n", x, y)).collect();
+        gtk::Clipboard::get(&gdk::SELECTION_CLIPBOARD).set_text(&text);
+    }));
+
+    let add_trajectory = Rc::new(cloning!(trajectories, trajectories_store, retrace => move |start: (f64, f64)| {
+        trajectories.borrow_mut().push(start);
+        trajectories_store.insert_with_values(None, &[(0, &format!("{:.4}", start.0)), (1, &format!("{:.4}", start.1))]);
+        retrace();
+    }));
+    remove_trajectory_btn.connect_clicked(cloning!(trajectories, trajectories_view, trajectories_store, retrace => move |_| {
+        let (model, iter) = match trajectories_view.selection().selected() {
+            Some(selected) => selected,
+            None => return,
+        };
+        let i = model.path(&iter).expect("selected row has no path").indices()[0] as usize;
+        trajectories.borrow_mut().remove(i);
+        trajectories_store.remove(&iter);
+        retrace();
+    }));
+    clear_trajectories_btn.connect_clicked(cloning!(trajectories, trajectories_store, retrace => move |_| {
+        trajectories.borrow_mut().clear();
+        trajectories_store.clear();
+        retrace();
+    }));
+
+    plot_btn.connect_clicked(cloning!(replot => move |_| replot(false)));
+    field_combo.connect_changed(cloning!(refield => move |_| refield()));
+    field_p_entry.connect_changed(cloning!(refield => move |_| refield()));
+    field_q_entry.connect_changed(cloning!(refield => move |_| refield()));
+    implicit_eqn_entry.connect_changed(cloning!(replot_soon => move |_| replot_soon()));
+    second_eqn_entry.connect_changed(cloning!(replot_soon => move |_| replot_soon()));
+    complex_check.connect_toggled(cloning!(replot => move |_| replot(false)));
+    zero_sets_check.connect_toggled(cloning!(replot => move |_| replot(false)));
+    for spin in &[&x_min_entry, &x_max_entry, &y_min_entry, &y_max_entry] {
+        spin.connect_value_changed(cloning!(replot_soon => move |_| replot_soon()));
+    }
+    // definitions don't have a button of their own, so redraw whenever one
+    // changes, live or not
+    definitions_buffer.connect_changed(cloning!(replot, replot_soon, refield, live_check => move |_| {
+        refield();
+        if live_check.is_active() {
+            replot_soon()
+        } else {
+            replot(false)
+        }
+    }));
+
+    // puts the inputs back the way they were in `session`, and plots them
+    let restore = Rc::new(
+        cloning!(implicit_eqn_entry, second_eqn_entry, definitions_buffer, x_min_entry, x_max_entry, y_min_entry, y_max_entry, live_check, complex_check, zero_sets_check, field_combo, field_p_entry, field_q_entry, clear_trajectories_btn, add_trajectory, style, replot => move |session: session::Session| {
+            // the first curve is the main one and the second is the one to
+            // intersect it with, which has its own style. Any more are left
+            // out, there being nowhere to restore them into.
+            let mut curves = session.curves.into_iter();
+            let curve = curves.next();
+            implicit_eqn_entry.set_text(curve.as_ref().map_or("", |c| c.equation.as_str()));
+            style.set(curve.map_or_else(Default::default, |c| c.style));
+            second_eqn_entry.set_text(curves.next().as_ref().map_or("", |c| c.equation.as_str()));
+            definitions_buffer.set_text(&session.definitions);
+            x_min_entry.set_value(session.x_range.start);
+            x_max_entry.set_value(session.x_range.end);
+            y_min_entry.set_value(session.y_range.start);
+            y_max_entry.set_value(session.y_range.end);
+            live_check.set_active(session.live);
+            complex_check.set_active(session.complex);
+            zero_sets_check.set_active(session.zero_sets);
+            clear_trajectories_btn.clicked();
+            match session.field {
+                Some(saved) => {
+                    field_p_entry.set_text(&saved.p);
+                    field_q_entry.set_text(&saved.q);
+                    field_combo.set_active_id(Some(saved.kind.name()));
+                    for &start in &saved.trajectories {
+                        add_trajectory(start);
+                    }
+                }
+                None => {
+                    field_combo.set_active_id(Some("none"));
+                }
+            }
+            replot(false);
+        }));
+
+    open_item.connect_activate(cloning!(window, restore, info_label, info_bar_revealer => move |_| {
+        let dialog = gtk::FileChooserDialog::with_buttons(Some("Open Session"),
+                                                          Some(&window),
+                                                          gtk::FileChooserAction::Open,
+                                                          &[("_Cancel", gtk::ResponseType::Cancel),
+                                                            ("_Open", gtk::ResponseType::Accept)]);
+        let path = match dialog.run() {
+            gtk::ResponseType::Accept => dialog.filename(),
+            _ => None,
+        };
+        dialog.close();
+        match path.map(|path| session::Session::load(&path)) {
+            Some(Ok(session)) => restore(session),
+            Some(Err(e)) => {
+                info_label.set_text(&e.to_string());
+                info_bar_revealer.set_reveal_child(true);
+            }
+            None => {}
+        }
+    }));
+
+    save_item.connect_activate(cloning!(window, implicit_eqn_entry, second_eqn_entry, definitions_buffer, x_range, y_range, live_check, complex_check, zero_sets_check, field_combo, field_p_entry, field_q_entry, trajectories, style, info_label, info_bar_revealer => move |_| {
+        let dialog = gtk::FileChooserDialog::with_buttons(Some("Save Session"),
+                                                          Some(&window),
+                                                          gtk::FileChooserAction::Save,
+                                                          &[("_Cancel", gtk::ResponseType::Cancel),
+                                                            ("_Save", gtk::ResponseType::Accept)]);
+        dialog.set_do_overwrite_confirmation(true);
+        dialog.set_current_name("session.json");
+        let path = match dialog.run() {
+            gtk::ResponseType::Accept => dialog.filename(),
+            _ => None,
+        };
+        dialog.close();
+        let mut curves = vec![session::SavedCurve {
+            equation: implicit_eqn_entry.text().as_str().to_owned(),
+            style: style.get(),
+        }];
+        let second = second_eqn_entry.text();
+        if !second.as_str().trim().is_empty() {
+            curves.push(session::SavedCurve {
+                equation: second.as_str().to_owned(),
+                style: intersections::OTHER_STYLE,
+            });
+        }
+        let session = session::Session {
+            curves: curves,
+            definitions: definitions_text(&definitions_buffer),
+            x_range: x_range.borrow().clone(),
+            y_range: y_range.borrow().clone(),
+            live: live_check.is_active(),
+            complex: complex_check.is_active(),
+            zero_sets: zero_sets_check.is_active(),
+            field: field_combo.active_id().and_then(|id| fields::Kind::from_name(id.as_str())).map(|kind| {
+                session::SavedField {
+                    kind: kind,
+                    p: field_p_entry.text().as_str().to_owned(),
+                    q: field_q_entry.text().as_str().to_owned(),
+                    trajectories: trajectories.borrow().clone(),
+                }
+            }),
+        };
+        if let Some(Err(e)) = path.map(|path| session.save(&path)) {
+            info_label.set_text(&e.to_string());
+            info_bar_revealer.set_reveal_child(true);
+        }
+    }));
+
+    // the zero sets in view, once they've been refined, if they're wanted
+    let view_zeros = Rc::new(cloning!(complex, zero_tiles, zero_sets_check, x_range, y_range => move || {
+        let source = match *complex.borrow() {
+            Some((ref source, _)) if zero_sets_check.is_active() => source.clone(),
+            _ => return None,
+        };
+        let keys = tiles::covering(&x_range.borrow(), &y_range.borrow());
+        let zero_tiles = zero_tiles.borrow();
+        let traced = zero_tiles.fine(&source, &keys)?;
+        keys.first().map(|&key| zero_sets::merge(traced, key))
+    }));
+
+    // calls `then` with everything drawn on the plot, to export it
+    let with_layers = Rc::new(
+        cloning!(complex, complex_check, glyphs, traced_trajectories, view_segments, style, view_other_segments, view_zeros, meeting_points, x_range, y_range => move |then: &mut FnMut(&export::Layers)| {
+            let complex = complex.borrow();
+            let glyphs = glyphs.borrow();
+            let traced_trajectories = traced_trajectories.borrow();
+            let zeros = view_zeros();
+            let meeting_points = meeting_points.borrow();
+            then(&export::Layers {
+                x_range: x_range.borrow().clone(),
+                y_range: y_range.borrow().clone(),
+                colouring: match *complex {
+                    Some((_, ref f)) if complex_check.is_active() => Some(&**f),
+                    _ => None,
+                },
+                glyphs: glyphs.as_ref(),
+                trajectories: &traced_trajectories.1,
+                segments: &view_segments(),
+                style: &style.get(),
+                other_segments: &view_other_segments(),
+                zeros: zeros.as_ref(),
+                meeting_points: &meeting_points,
+            });
+        }));
+
+    export_item.connect_activate(cloning!(window, drawing, with_layers, when_refined, info_label, info_bar_revealer => move |_| {
+        let dialog = gtk::FileChooserDialog::with_buttons(Some("Export Plot"),
+                                                          Some(&window),
+                                                          gtk::FileChooserAction::Save,
+                                                          &[("_Cancel", gtk::ResponseType::Cancel),
+                                                            ("_Export", gtk::ResponseType::Accept)]);
+        dialog.set_do_overwrite_confirmation(true);
+        dialog.set_current_name("plot.png");
+        // the size to export at, which starts out as the size on screen
+        let width = gtk::SpinButton::with_range(1.0, 100000.0, 1.0);
+        width.set_value(f64::from(drawing.allocated_width()));
+        let height = gtk::SpinButton::with_range(1.0, 100000.0, 1.0);
+        height.set_value(f64::from(drawing.allocated_height()));
+        let dpi = gtk::SpinButton::with_range(1.0, 10000.0, 1.0);
+        dpi.set_value(96.0);
+        let size_grid = gtk::Grid::new();
+        size_grid.set_column_spacing(6);
+        for (i, &(label, spin)) in [("Width", &width), ("Height", &height), ("PNG DPI", &dpi)].iter().enumerate() {
+            size_grid.attach(&gtk::Label::new(Some(label)), 2 * i as i32, 0, 1, 1);
+            size_grid.attach(spin, 2 * i as i32 + 1, 0, 1, 1);
+        }
+        size_grid.show_all();
+        dialog.set_extra_widget(&size_grid);
+        let path = match dialog.run() {
+            gtk::ResponseType::Accept => dialog.filename(),
+            _ => None,
+        };
+        let (width, height, dpi) = (width.value(), height.value(), dpi.value());
+        dialog.close();
+        let path = match path {
+            Some(path) => path,
+            None => return,
+        };
+        let format = match path.extension().and_then(|ext| ext.to_str()).and_then(export::Format::from_extension) {
+            Some(format) => format,
+            None => {
+                info_label.set_text("couldn't export the plot: the file name should end in .png, .svg or .pdf");
+                info_bar_revealer.set_reveal_child(true);
+                return;
+            }
+        };
+        let options = export::Options { format: format, width: width, height: height, dpi: dpi };
+        when_refined(Box::new(cloning!(with_layers, info_label, info_bar_revealer => move || {
+            let mut result = Ok(());
+            with_layers(&mut |layers| result = export::export(&path, layers, &options));
+            if let Err(e) = result {
+                info_label.set_text(&format!("couldn't export the plot: {}", e));
+                info_bar_revealer.set_reveal_child(true);
+            }
+        })));
+    }));
+
+    copy_item.connect_activate(cloning!(drawing, with_layers, when_refined, info_label, info_bar_revealer => move |_| {
+        let (width, height) = (drawing.allocated_width(), drawing.allocated_height());
+        when_refined(Box::new(cloning!(with_layers, info_label, info_bar_revealer => move || {
+            let mut image = None;
+            with_layers(&mut |layers| {
+                image = export::render_image(layers, f64::from(width), f64::from(height), 96.0).ok();
+            });
+            match image.and_then(|image| gdk::pixbuf_get_from_surface(&image, 0, 0, width, height)) {
+                Some(pixbuf) => gtk::Clipboard::get(&gdk::SELECTION_CLIPBOARD).set_image(&pixbuf),
+                None => {
+                    info_label.set_text("couldn't copy the plot");
+                    info_bar_revealer.set_reveal_child(true);
+                }
+            }
+        })));
+    }));
+
+    export_data_item.connect_activate(cloning!(window, implicit_eqn_entry, second_eqn_entry, view_segments, view_other_segments, view_zeros, field, traced_trajectories, field_p_entry, field_q_entry, when_refined, info_label, info_bar_revealer => move |_| {
+        let dialog = gtk::FileChooserDialog::with_buttons(Some("Export Curve Data"),
+                                                          Some(&window),
+                                                          gtk::FileChooserAction::Save,
+                                                          &[("_Cancel", gtk::ResponseType::Cancel),
+                                                            ("_Export", gtk::ResponseType::Accept)]);
+        dialog.set_do_overwrite_confirmation(true);
+        dialog.set_current_name("curve.geojson");
+        let path = match dialog.run() {
+            gtk::ResponseType::Accept => dialog.filename(),
+            _ => None,
+        };
+        dialog.close();
+        let path = match path {
+            Some(path) => path,
+            None => return,
+        };
+        when_refined(Box::new(cloning!(implicit_eqn_entry, second_eqn_entry, view_segments, view_other_segments, view_zeros, field, traced_trajectories, field_p_entry, field_q_entry, info_label, info_bar_revealer => move || {
+            let result = data_export::DataFormat::from_path(&path).map_err(Into::into).and_then(|format| {
+                let polylines = marching_squares::polylines(&view_segments());
+                let (p, q) = (field_p_entry.text(), field_q_entry.text());
+                let traced = traced_trajectories.borrow();
+                let labels: Vec<_> = match *field.borrow() {
+                    Some((kind, _)) => {
+                        traced.0.iter().map(|&start| trajectory_label(kind, p.as_str(), q.as_str(), start)).collect()
+                    }
+                    None => Vec::new(),
+                };
+                let others = marching_squares::polylines(&view_other_segments());
+                let (equation, second) = (implicit_eqn_entry.text(), second_eqn_entry.text());
+                // the equation is a function of `z` if it has zero sets
+                let zeros = view_zeros().map(|sets| data_export::ZeroSetData::new(equation.as_str(), &sets));
+                let mut curves = match zeros {
+                    Some(ref zeros) => zeros.curves(),
+                    None => {
+                        vec![data_export::CurveData {
+                            equation: equation.as_str(),
+                            polylines: &polylines,
+                            points: &[],
+                        }]
+                    }
+                };
+                if !others.is_empty() {
+                    curves.push(data_export::CurveData {
+                        equation: second.as_str(),
+                        polylines: &others,
+                        points: &[],
+                    });
+                }
+                curves.extend(labels.iter().zip(&traced.1).map(|(label, line)| data_export::CurveData {
+                    equation: label,
+                    polylines: slice::from_ref(line),
+                    points: &[],
+                }));
+                data_export::export_data(&path, format, &curves)
+            });
+            if let Err(e) = result {
+                info_label.set_text(&format!("couldn't export the curve: {}", e));
+                info_bar_revealer.set_reveal_child(true);
+            }
+        })));
+    }));
+
+    info_bar.connect_response(cloning!(info_bar_revealer => move |_, _| {
+        info_bar_revealer.set_reveal_child(false);
+    }));
+
+
+    // dragging pans the view, scrolling zooms it about the pointer, and
+    // clicking traces the trajectory through a point of the field
+    drawing.add_events(gdk::EventMask::BUTTON_PRESS_MASK |
+                       gdk::EventMask::BUTTON_RELEASE_MASK |
+                       gdk::EventMask::BUTTON1_MOTION_MASK |
+                       gdk::EventMask::SCROLL_MASK);
+    // where the pointer was when the view last followed it
+    let drag_from: Rc<Cell<(f64, f64)>> = Default::default();
+    // where the button went down, until the pointer moves far enough for it
+    // to be a drag rather than a click
+    let click_at: Rc<Cell<Option<(f64, f64)>>> = Default::default();
+    drawing.connect_button_press_event(cloning!(drag_from, click_at => move |_, ev| {
+        drag_from.set(ev.position());
+        click_at.set(Some(ev.position()));
+        Inhibit(false)
+    }));
+    drawing.connect_button_release_event(cloning!(click_at, field, x_range, y_range, add_trajectory => move |drawing, ev| {
+        let (px, py) = match click_at.take() {
+            Some(at) if ev.button() == 1 && field.borrow().is_some() => at,
+            _ => return Inhibit(false),
+        };
+        let (w, h) = (drawing.allocated_width() as f64, drawing.allocated_height() as f64);
+        let (xr, yr) = (x_range.borrow().clone(), y_range.borrow().clone());
+        add_trajectory((xr.start + px / w * (xr.end - xr.start), yr.end - py / h * (yr.end - yr.start)));
+        Inhibit(true)
+    }));
+    drawing.connect_motion_notify_event(cloning!(drag_from, click_at, x_range, y_range, set_view => move |drawing, ev| {
+        let (x0, y0) = drag_from.replace(ev.position());
+        let (x1, y1) = ev.position();
+        if let Some((cx, cy)) = click_at.get() {
+            if (x1 - cx).hypot(y1 - cy) > 4.0 {
+                click_at.set(None);
+            }
+        }
+        let (w, h) = (drawing.allocated_width() as f64, drawing.allocated_height() as f64);
+        let (xr, yr) = (x_range.borrow().clone(), y_range.borrow().clone());
+        let dx = (x0 - x1) / w * (xr.end - xr.start);
+        let dy = (y1 - y0) / h * (yr.end - yr.start);
+        set_view(xr.start + dx..xr.end + dx, yr.start + dy..yr.end + dy);
+        Inhibit(false)
+    }));
+    drawing.connect_scroll_event(cloning!(x_range, y_range, set_view => move |drawing, ev| {
+        let factor = match ev.direction() {
+            gdk::ScrollDirection::Up => 0.8,
+            gdk::ScrollDirection::Down => 1.25,
+            _ => return Inhibit(false),
+        };
+        let (px, py) = ev.position();
+        let (w, h) = (drawing.allocated_width() as f64, drawing.allocated_height() as f64);
+        let (xr, yr) = (x_range.borrow().clone(), y_range.borrow().clone());
+        // the point under the pointer stays put
+        let x = xr.start + px / w * (xr.end - xr.start);
+        let y = yr.end - py / h * (yr.end - yr.start);
+        set_view(x + (xr.start - x) * factor..x + (xr.end - x) * factor,
+                 y + (yr.start - y) * factor..y + (yr.end - y) * factor);
+        Inhibit(true)
+    }));
+
+    drawing.connect_draw(cloning!(plotted, complex, coloured, zeros, other_plotted, meeting_points, glyphs, traced_trajectories, complex_check, style, x_range, y_range, info_label, info_bar_revealer => move |_, ctx| {
+        let (xr, yr) = (x_range.borrow(), y_range.borrow());
+        // the same error comes up on every redraw, so only change the text
+        // if it's new, which would otherwise queue another
+        let report = |text: String| {
+            if info_label.text().as_str() != text.as_str() {
+                info_label.set_text(&text);
+            }
+            info_bar_revealer.set_reveal_child(true);
+        };
+        marching_squares::draw(ctx, &[], &style.get(), &xr, &yr);
+        // the colouring covers the whole plot, axes and all. Until the view's
+        // is worked out, the last one of the same function stands in for it
+        // wherever it was.
+        match (complex.borrow().as_ref(), coloured.borrow().as_ref()) {
+            (Some(&(ref source, _)), Some(c)) if complex_check.is_active() && c.source == *source => {
+                if let Err(e) = domain_colouring::paint(ctx, &c.image, &c.x_range, &c.y_range, &xr, &yr) {
+                    report(format!("couldn't colour the plane: {}", e));
+                }
+            }
+            _ => {}
+        }
+        // under the curve, so solutions stand out against slope fields
+        if let Some(ref glyphs) = *glyphs.borrow() {
+            if let Err(e) = fields::draw(ctx, glyphs, &xr, &yr) {
+                report(format!("couldn't draw the field: {}", e));
+            }
+        }
+        let steps: Vec<_> = traced_trajectories.borrow().1.iter().flat_map(marching_squares::Polyline::segments).collect();
+        marching_squares::stroke(ctx, &steps, &fields::TRAJECTORY_STYLE, &xr, &yr);
+        let plotted = plotted.borrow();
+        let segments = tiles::covering(&xr, &yr).into_iter()
+            .filter_map(|k| plotted.tiles.get(&k))
+            .flat_map(|t| &t.1);
+        marching_squares::stroke(ctx, segments, &style.get(), &xr, &yr);
+        let other_plotted = other_plotted.borrow();
+        let segments = tiles::covering(&xr, &yr).into_iter()
+            .filter_map(|k| other_plotted.tiles.get(&k))
+            .flat_map(|t| &t.1);
+        marching_squares::stroke(ctx, segments, &intersections::OTHER_STYLE, &xr, &yr);
+        if let Some(ref sets) = *zeros.borrow() {
+            if let Err(e) = zero_sets::draw(ctx, sets, &xr, &yr) {
+                report(format!("couldn't draw the zero sets: {}", e));
+            }
+        }
+        if let Err(e) = intersections::draw(ctx, &meeting_points.borrow(), &xr, &yr) {
+            report(format!("couldn't mark the intersections: {}", e));
+        }
+        Inhibit(false)
+    }));
+
+    if let Some(session) = session {
+        restore(session);
+    }
+
+    window.show();
+    // `drawing`'s size_request is set in `layout.glade`
+    // but for some reason that causes weirdness with resizing the infobar
+    // so we let the size allocation process thing happen once with
+    // the size request set so that when we start `drawing` is a sensible size,
+    // then we unset them here so resizing the infobar works
+    drawing.set_size_request(-1, -1);
+    gtk::main();
+
+    Ok(())
+}
+
+/// The definitions list's text
+fn definitions_text(buffer: &gtk::TextBuffer) -> String {
+    buffer.text(&buffer.start_iter(), &buffer.end_iter(), false).map_or_else(String::new, |t| t.as_str().to_owned())
+}
+
+/// The tiles traced so far, all of the same curve or function: by default,
+/// their segments
+#[derive(Default)]
+struct Plotted<T = Vec<marching_squares::Segment>> {
+    /// the source of the curve, if there's a valid one
+    source: Option<String>,
+    /// each tile, and how many cells across it was sampled on
+    tiles: FnvHashMap<tiles::TileKey, (usize, T)>,
+}
+
+impl<T> Plotted<T> {
+    /// Gets ready to trace `keys` of the curve with `source`, starting afresh
+    /// if it's a different curve and forgetting tiles too far zoomed from
+    /// them to be useful again soon. Gives how many cells across each of
+    /// `keys` has been traced on so far, unless they're all refined already.
+    fn start(&mut self, source: &str, keys: &[tiles::TileKey]) -> Option<Vec<usize>> {
+        if self.source.as_ref().map_or(true, |s| s.as_str() != source) {
+            *self = Plotted { source: Some(source.to_owned()), tiles: FnvHashMap::default() };
+        }
+        let (zx, zy) = match keys.first() {
+            Some(k) => (k.zx, k.zy),
+            None => return None,
+        };
+        self.tiles.retain(|k, _| (k.zx - zx).abs() <= 1 && (k.zy - zy).abs() <= 1);
+        let had: Vec<_> = keys.iter().map(|k| self.tiles.get(k).map_or(0, |t| t.0)).collect();
+        if had.iter().all(|&cells| cells >= tiles::FINE_CELLS) {
+            None
+        } else {
+            Some(had)
+        }
+    }
+
+    /// Keeps `tile`, sampled on `cells` cells across, as tile `key` of the
+    /// curve with `source` if it's better than what's there, and says
+    /// whether it did. Tiles of a curve that's since been replaced are
+    /// useless, but ones of the same curve are worth keeping even if the
+    /// view has moved on.
+    fn insert(&mut self, source: &str, key: tiles::TileKey, cells: usize, tile: T) -> bool {
+        let better = self.tiles.get(&key).map_or(true, |t| t.0 < cells);
+        if self.source.as_ref().map_or(false, |s| s.as_str() == source) && better {
+            self.tiles.insert(key, (cells, tile));
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Tiles `keys` of the curve with `source`, if they've all been refined
+    fn fine(&self, source: &str, keys: &[tiles::TileKey]) -> Option<Vec<&T>> {
+        if self.source.as_ref().map_or(true, |s| s.as_str() != source) {
+            return None;
+        }
+        keys.iter().map(|key| match self.tiles.get(key) {
+            Some(&(cells, ref tile)) if cells >= tiles::FINE_CELLS => Some(tile),
+            _ => None,
+        }).collect()
+    }
+}
+
+/// Traces those of `keys` that haven't been on as many cells across as the
+/// pass calls for, going by `had`, with `trace`, sharing one set of threads
+/// between both passes: a quick one so something shows up everywhere soon,
+/// then a refined one. Sends each tile on as it's done, and gives up,
+/// returning false, once `trace` does or the window has closed.
+fn trace_tiles<F>(keys: &[tiles::TileKey], had: &[usize], done_tx: &glib::Sender<Traced>, trace: F) -> bool
+    where F: Fn(tiles::TileKey, usize) -> Option<Traced> + Sync
+{
+    let mut work = Vec::new();
+    for &cells in &[tiles::COARSE_CELLS, tiles::FINE_CELLS] {
+        work.extend(keys.iter().zip(had).filter(|&(_, &had)| had < cells).map(|(&key, _)| (key, cells)));
+    }
+    tiles::par_map(&work, |&(key, cells)| trace(key, cells).and_then(|traced| done_tx.send(traced).ok())).is_some()
+}
+
+/// Runs everything that was `waiting` for the tiles in view to be refined
+fn run_waiting(waiting: &RefCell<Vec<Box<Fn()>>>) {
+    // taken out first, since any of them might wait again
+    for then in waiting.replace(Vec::new()) {
+        then();
+    }
+}
+
+/// A colouring of the plane by a complex function
+struct Coloured {
+    /// the source of the function
+    source: String,
+    x_range: Range<f64>,
+    y_range: Range<f64>,
+    image: cairo::ImageSurface,
+}
+
+/// Sent from the worker thread as it traces tiles
+enum Traced {
+    /// a tile of the curve with the given source, sampled on that many cells
+    /// across
+    Tile(String, tiles::TileKey, usize, Vec<marching_squares::Segment>),
+    /// the pixels, as from `domain_colouring::pixels`, of a width by height
+    /// colouring over the ranges by the function with the given source
+    Colouring(String, Range<f64>, Range<f64>, usize, usize, Vec<u32>),
+    /// the trajectories of the job with this cancellation flag, and the
+    /// points they go through
+    Trajectories(Arc<AtomicBool>, Vec<(f64, f64)>, Vec<marching_squares::Polyline>),
+    /// a tile of the curve to intersect with, likewise
+    OtherTile(String, tiles::TileKey, usize, Vec<marching_squares::Segment>),
+    /// a tile of the zero sets of the function with the given source,
+    /// likewise
+    Zeros(String, tiles::TileKey, usize, zero_sets::ZeroSets),
+    /// why the curve of the job with this cancellation flag is undefined
+    /// everywhere in view, if it is
+    Undefined(Arc<AtomicBool>, Option<String>),
+    /// the job with this cancellation flag has refined all of its tiles
+    Finished(Arc<AtomicBool>),
+}
@@ -0,0 +1,223 @@
+#[cfg(feature = "render")]
+use cairo;
+use expr::{Bound2, Dual, Scratch};
+use fnv::FnvHashMap;
+#[cfg(feature = "render")]
+use marching_squares;
+use marching_squares::{Point, Segment, Style};
+#[cfg(feature = "render")]
+use std::f64::consts;
+use std::ops::Range;
+use tiles::{self, TileKey};
+
+/// How the curve to intersect with is drawn
+pub const OTHER_STYLE: Style = Style { color: (0.0, 0.35, 0.8), line_width: 1.5 };
+
+/// How many steps of Newton's method to take at most. It only converges
+/// linearly where the curves touch rather than cross, so this is generous.
+const NEWTON_STEPS: usize = 60;
+
+/// Finds where the curves with equations `f.0` and `g.0` and domains `f.1`
+/// and `g.1` meet in `xr` by `yr`, left to right, from `traced`: the tiles
+/// covering it, each with the segments of both curves there, as traced on
+/// `tiles::FINE_CELLS` cells across.
+///
+/// Cells that both curves pass through are where they might meet, and where
+/// they do is pinned down from there by Newton's method.
+pub fn find<'a, I>(f: &(Bound2, Bound2), g: &(Bound2, Bound2), traced: I, xr: &Range<f64>, yr: &Range<f64>) -> Vec<Point>
+    where I: IntoIterator<Item = (TileKey, &'a [Segment], &'a [Segment])>
+{
+    let cells = tiles::FINE_CELLS;
+    let mut found = Vec::new();
+    let mut cell_size = (0.0, 0.0);
+    let mut scratch = Scratch::new();
+    for (key, fs, gs) in traced {
+        let (kx, ky) = (key.x_range(), key.y_range());
+        let x_step = (kx.end - kx.start) / (cells as f64);
+        let y_step = (ky.end - ky.start) / (cells as f64);
+        cell_size = (x_step, y_step);
+        pair_nearby(fs, gs, &kx, &ky, cells, |s, (i, j), near| {
+            // where the segments cross is the best place to start, but curves
+            // that only touch might not cross at all
+            let mut starts: Vec<_> = near.iter().filter_map(|&t| crossing(s, t)).collect();
+            if starts.is_empty() {
+                starts.push((((s.0).0 + (s.1).0) / 2.0, ((s.0).1 + (s.1).1) / 2.0));
+            }
+            let bounds = (kx.start + x_step * (i as f64 - 1.0)..kx.start + x_step * (i as f64 + 2.0),
+                          ky.start + y_step * (j as f64 - 1.0)..ky.start + y_step * (j as f64 + 2.0));
+            found.extend(starts.into_iter().filter_map(|p| newton(f, g, &mut scratch, p, &bounds)));
+        });
+    }
+
+    let mut scratch = Scratch::new();
+    found.retain(|&(x, y)| {
+        let inside = |r: &Range<f64>, v: f64| r.start.min(r.end) <= v && v <= r.start.max(r.end);
+        let mut allowed = |d: &Bound2| {
+            let v = d.eval_with(&mut scratch, x, y);
+            v != 0.0 && !v.is_nan()
+        };
+        inside(xr, x) && inside(yr, y) && allowed(&f.1) && allowed(&g.1)
+    });
+    // every start near the same point ends up at just about exactly the same
+    // place, so only points this close could be separate
+    dedup(&mut found, cell_size.0 / 100.0, cell_size.1 / 100.0);
+    found.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    found
+}
+
+/// Solves `f.0(x, y) = 0, g.0(x, y) = 0` by Newton's method from `p`, as
+/// long as it stays within `bounds`
+fn newton(f: &(Bound2, Bound2),
+          g: &(Bound2, Bound2),
+          scratch: &mut Scratch<Dual>,
+          p: Point,
+          bounds: &(Range<f64>, Range<f64>))
+          -> Option<Point> {
+    let tolerance = (bounds.0.end - bounds.0.start).abs().max((bounds.1.end - bounds.1.start).abs()) * 1e-12;
+    let mut p = p;
+    for _ in 0..NEWTON_STEPS {
+        let (a, b) = (f.0.eval_grad_with(scratch, p.0, p.1), g.0.eval_grad_with(scratch, p.0, p.1));
+        if a.v == 0.0 && b.v == 0.0 {
+            return Some(p);
+        }
+        let det = a.dx * b.dy - a.dy * b.dx;
+        if det == 0.0 || !det.is_finite() {
+            return None;
+        }
+        let step = ((a.dy * b.v - b.dy * a.v) / det, (b.dx * a.v - a.dx * b.v) / det);
+        p = (p.0 + step.0, p.1 + step.1);
+        if !(bounds.0.contains(&p.0) && bounds.1.contains(&p.1)) {
+            return None;
+        }
+        if step.0.hypot(step.1) <= tolerance {
+            return Some(p);
+        }
+    }
+    None
+}
+
+/// Where segments `a` and `b` cross, if they do
+pub fn crossing(a: Segment, b: Segment) -> Option<Point> {
+    let cross = |u: Point, v: Point| u.0 * v.1 - u.1 * v.0;
+    let r = ((a.1).0 - (a.0).0, (a.1).1 - (a.0).1);
+    let s = ((b.1).0 - (b.0).0, (b.1).1 - (b.0).1);
+    let denom = cross(r, s);
+    if denom == 0.0 {
+        return None;
+    }
+    let qp = ((b.0).0 - (a.0).0, (b.0).1 - (a.0).1);
+    let t = cross(qp, s) / denom;
+    let u = cross(qp, r) / denom;
+    if t >= 0.0 && t <= 1.0 && u >= 0.0 && u <= 1.0 {
+        Some(((a.0).0 + t * r.0, (a.0).1 + t * r.1))
+    } else {
+        None
+    }
+}
+
+/// The cell `p` is in, of `cells` by `cells` cells over `xr` by `yr`,
+/// counting points outside as in the nearest one
+pub fn cell_of(p: Point, xr: &Range<f64>, yr: &Range<f64>, cells: usize) -> (usize, usize) {
+    let (x_step, y_step) = ((xr.end - xr.start) / cells as f64, (yr.end - yr.start) / cells as f64);
+    let last = cells as f64 - 1.0;
+    let i = ((p.0 - xr.start) / x_step).floor().max(0.0).min(last);
+    let j = ((p.1 - yr.start) / y_step).floor().max(0.0).min(last);
+    (i as usize, j as usize)
+}
+
+/// Calls `near` with each of `a` that's near any of `b`, the cell its middle
+/// is in, and those of `b` near it, out of `cells` by `cells` cells over `xr`
+/// by `yr`. Segments are near if their middles are in the same cell or, since
+/// a segment along the edge of a cell may have been put on either side of it,
+/// neighbouring ones.
+pub fn pair_nearby<F>(a: &[Segment], b: &[Segment], xr: &Range<f64>, yr: &Range<f64>, cells: usize, mut near: F)
+    where F: FnMut(Segment, (usize, usize), &[Segment])
+{
+    let mid = |s: &Segment| (((s.0).0 + (s.1).0) / 2.0, ((s.0).1 + (s.1).1) / 2.0);
+    let mut b_cells = FnvHashMap::default();
+    for s in b {
+        b_cells.entry(cell_of(mid(s), xr, yr, cells)).or_insert_with(Vec::new).push(*s);
+    }
+    let mut nearby = Vec::new();
+    for s in a {
+        let (i, j) = cell_of(mid(s), xr, yr, cells);
+        nearby.clear();
+        for ni in i.saturating_sub(1)..i + 2 {
+            for nj in j.saturating_sub(1)..j + 2 {
+                nearby.extend(b_cells.get(&(ni, nj)).into_iter().flat_map(|v| v));
+            }
+        }
+        if !nearby.is_empty() {
+            near(*s, (i, j), &nearby);
+        }
+    }
+}
+
+/// Drops any of `points` within `dx` by `dy` of an earlier one
+pub fn dedup(points: &mut Vec<Point>, dx: f64, dy: f64) {
+    let mut kept: Vec<Point> = Vec::with_capacity(points.len());
+    for &p in points.iter() {
+        if !kept.iter().any(|q| (p.0 - q.0).abs() < dx && (p.1 - q.1).abs() < dy) {
+            kept.push(p);
+        }
+    }
+    *points = kept;
+}
+
+/// How `p` is labelled on the plot
+pub fn label(p: Point) -> String {
+    format!("({:.4}, {:.4})", p.0, p.1)
+}
+
+/// Marks `points` on `ctx` over whatever's there already, each labelled with
+/// its coordinates, scaled so that `xr` by `yr` fills its clip region
+#[cfg(feature = "render")]
+pub fn draw(ctx: &cairo::Context, points: &[Point], xr: &Range<f64>, yr: &Range<f64>) -> Result<(), cairo::Error> {
+    ctx.save()?;
+    let unscaled = marching_squares::to_plot(ctx, xr, yr);
+    let centres: Vec<_> = points.iter().map(|&(x, y)| ctx.user_to_device(x, y)).collect();
+    ctx.set_matrix(unscaled);
+    ctx.select_font_face("sans-serif", cairo::FontSlant::Normal, cairo::FontWeight::Normal);
+    ctx.set_font_size(11.0);
+    for (&p, &(dx, dy)) in points.iter().zip(&centres) {
+        let (x, y) = ctx.device_to_user(dx, dy)?;
+        ctx.new_sub_path();
+        ctx.arc(x, y, 4.0, 0.0, 2.0 * consts::PI);
+        ctx.set_source_rgb(1.0, 1.0, 1.0);
+        ctx.fill_preserve()?;
+        ctx.set_source_rgb(0.0, 0.0, 0.0);
+        ctx.set_line_width(1.5);
+        ctx.stroke()?;
+        // up and to the right of the marker
+        ctx.move_to(x + 6.0, y - 6.0);
+        ctx.show_text(&label(p))?;
+    }
+    ctx.restore()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use compile_curve;
+    use std::sync::atomic::AtomicBool;
+    use trace_tile;
+
+    #[test]
+    fn circle_meets_diagonal_twice() {
+        let f = compile_curve("x^2 + y^2 = 1", "").unwrap();
+        let g = compile_curve("y = x", "").unwrap();
+        let (xr, yr) = (-2.0..2.0, -2.0..2.0);
+        let never = AtomicBool::new(false);
+        let traced: Vec<_> = tiles::covering(&xr, &yr).into_iter().map(|key| {
+            (key,
+             trace_tile(&f, key, tiles::FINE_CELLS, &never).unwrap(),
+             trace_tile(&g, key, tiles::FINE_CELLS, &never).unwrap())
+        }).collect();
+        let found = find(&f, &g, traced.iter().map(|t| (t.0, &t.1[..], &t.2[..])), &xr, &yr);
+        let r = 0.5_f64.sqrt();
+        assert_eq!(found.len(), 2, "found {:?}", found);
+        for (&(x, y), &(ex, ey)) in found.iter().zip(&[(-r, -r), (r, r)]) {
+            assert!((x - ex).abs() < 1e-12 && (y - ey).abs() < 1e-12, "found ({}, {})", x, y);
+        }
+    }
+}
@@ -0,0 +1,315 @@
+#![cfg_attr(feature = "cargo-clippy", warn(clippy,clippy_pedantic))]
+#![cfg_attr(feature = "cargo-clippy", allow(missing_docs_in_private_items))]
+// #![feature(test)]
+// extern crate test;
+extern crate ndarray;
+#[cfg(feature = "render")]
+extern crate cairo;
+extern crate fnv;
+extern crate num_complex;
+#[macro_use]
+extern crate lalrpop_util;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+
+pub mod expr;
+#[cfg_attr(feature = "cargo-clippy", allow(clippy,clippy_pedantic))]
+lalrpop_mod!(pub expr_parser);
+pub mod marching_squares;
+pub mod tiles;
+pub mod session;
+pub mod repl;
+pub mod domain_colouring;
+pub mod zero_sets;
+pub mod fields;
+pub mod intersections;
+pub mod data_export;
+#[cfg(feature = "render")]
+pub mod export;
+
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::ops::Range;
+use std::sync::atomic::AtomicBool;
+
+use lalrpop_util::ParseError;
+
+/// Compiles `equation` into its equation and domain, given one definition
+/// per line of `definitions`
+pub fn compile_curve(equation: &str, definitions: &str) -> Result<(expr::Bound2, expr::Bound2), String> {
+    let defs = parse_definitions(definitions)?;
+    let (ne, nd) = expr_parser::CurveParser::new()
+        .parse(equation)
+        .map_err(|e| parse_error_message(equation, e))?
+        .split();
+    let cne = ne.compile(&defs).map_err(|e| e.to_string())?;
+    let cnd = nd.compile(&defs).map_err(|e| e.to_string())?;
+    if cne.vars().iter().chain(&cnd.vars()).all(|v| v == &"x" || v == &"y") {
+        Ok((cne.bind2_batch("x", "y"), cnd.bind2_batch("x", "y")))
+    } else {
+        Err("free variables".to_owned())
+    }
+}
+
+/// Compiles `expression` as a function of `z = x + i*y`, given one definition
+/// per line of `definitions`. An equation stands for the difference of its
+/// two sides, which is zero where it holds.
+pub fn compile_complex(expression: &str, definitions: &str) -> Result<expr::ComplexExpr, String> {
+    let defs = parse_definitions(definitions)?;
+    let e = match expr_parser::EquationParser::new().parse(expression) {
+        Ok(eqn) => eqn.to_diff(),
+        Err(_) => expr_parser::ExprParser::new().parse(expression).map_err(|e| parse_error_message(expression, e))?,
+    };
+    let ce = e.compile_complex(&defs).map_err(|e| e.to_string())?;
+    if ce.vars().iter().all(|v| v == &"z" || v == &"x" || v == &"y") {
+        Ok(ce)
+    } else {
+        Err("free variables".to_owned())
+    }
+}
+
+/// Compiles `p` and `q` into the two components of a field, given one
+/// definition per line of `definitions`
+pub fn compile_field(p: &str, q: &str, definitions: &str) -> Result<(expr::Bound2, expr::Bound2), String> {
+    let defs = parse_definitions(definitions)?;
+    let mut bound = Vec::new();
+    for text in &[p, q] {
+        let e = expr_parser::ExprParser::new().parse(text).map_err(|e| parse_error_message(text, e))?;
+        let ce = e.compile(&defs).map_err(|e| e.to_string())?;
+        if !ce.vars().iter().all(|v| v == &"x" || v == &"y") {
+            return Err("free variables".to_owned());
+        }
+        bound.push(ce.bind2_batch("x", "y"));
+    }
+    let q = bound.pop().unwrap();
+    Ok((bound.pop().unwrap(), q))
+}
+
+/// Compiles the field of `kind` given by `p` and `q`, where a slope field
+/// only has `p`
+pub fn compile_field_of_kind(kind: fields::Kind, p: &str, q: &str, definitions: &str)
+                             -> Result<(expr::Bound2, expr::Bound2), String> {
+    // a slope field is the field along which `y` changes by that much for
+    // each step in `x`
+    match kind {
+        fields::Kind::Slopes => compile_field("1", p, definitions),
+        _ => compile_field(p, q, definitions),
+    }
+}
+
+/// Describes the trajectory through `start` of the field of `kind` given by
+/// `p` and `q`, in place of an equation for exported data
+pub fn trajectory_label(kind: fields::Kind, p: &str, q: &str, start: (f64, f64)) -> String {
+    match kind {
+        fields::Kind::Slopes => format!("dy/dx = {} through ({}, {})", p, start.0, start.1),
+        _ => format!("(dx/dt, dy/dt) = ({}, {}) through ({}, {})", p, q, start.0, start.1),
+    }
+}
+
+/// Traces the curve with equation `eq.0` and domain `eq.1` over `xr` by `yr`,
+/// as polylines
+pub fn trace_view(eq: &(expr::Bound2, expr::Bound2), xr: &Range<f64>, yr: &Range<f64>)
+                  -> Vec<marching_squares::Polyline> {
+    let never = AtomicBool::new(false);
+    let traced = tiles::par_map(&tiles::covering(xr, yr), |&key| trace_tile(eq, key, tiles::FINE_CELLS, &never));
+    marching_squares::polylines(&traced.unwrap().concat())
+}
+
+/// Traces tile `key` of the curve with equation `eq.0` and domain `eq.1`
+pub fn trace_tile(eq: &(expr::Bound2, expr::Bound2),
+                  key: tiles::TileKey,
+                  cells: usize,
+                  cancelled: &AtomicBool)
+                  -> Option<Vec<marching_squares::Segment>> {
+    let (ref f, ref domain) = *eq;
+    let mut scratch = expr::Scratch::new();
+    tiles::trace(&|xs: &[f64], ys: &[f64], out: &mut [f64]| f.eval_batch(xs, ys, out),
+                 |x, y| {
+                     let v = domain.eval_with(&mut scratch, x, y);
+                     v != 0.0 && !v.is_nan()
+                 },
+                 key,
+                 cells,
+                 cancelled)
+}
+
+/// Whether the curve with equation `eq.0` is undefined everywhere in `xr` by
+/// `yr`, judging by a coarse grid of samples
+pub fn undefined_in_view(eq: &(expr::Bound2, expr::Bound2), xr: &Range<f64>, yr: &Range<f64>) -> bool {
+    const SAMPLES: usize = 32;
+    let at = |r: &Range<f64>, i: usize| r.start + (r.end - r.start) * (i as f64 + 0.5) / SAMPLES as f64;
+    let mut xs = Vec::with_capacity(SAMPLES * SAMPLES);
+    let mut ys = Vec::with_capacity(SAMPLES * SAMPLES);
+    for i in 0..SAMPLES {
+        for j in 0..SAMPLES {
+            xs.push(at(xr, i));
+            ys.push(at(yr, j));
+        }
+    }
+    let mut out = vec![0.0; xs.len()];
+    eq.0.eval_batch(&xs, &ys, &mut out);
+    out.iter().all(|v| v.is_nan())
+}
+
+/// Says why `equation` is undefined at `(x, y)`, if it's because some
+/// function is applied outside its domain there
+pub fn explain_undefined(equation: &str, definitions: &str, x: f64, y: f64) -> Option<String> {
+    let defs = parse_definitions(definitions).ok()?;
+    let (ne, _) = expr_parser::CurveParser::new().parse(equation).ok()?.split();
+    let ne = ne.expand(&defs).ok()?;
+    let env: HashMap<&str, f64> = [("x", x), ("y", y)].iter().cloned().collect();
+    ne.eval_checked(&env).err().map(|e| e.to_string())
+}
+
+/// Parses one definition per non-blank line of `text`
+pub fn parse_definitions(text: &str) -> Result<expr::Definitions, String> {
+    let mut defs = expr::Definitions::new();
+    for (i, line) in text.lines().enumerate().filter(|&(_, l)| !l.trim().is_empty()) {
+        let def = expr_parser::DefinitionParser::new().parse(line)
+            .map_err(|e| format!("definition on line {}: {}", i + 1, parse_error_message(line, e)))?;
+        defs.insert(def).map_err(|e| e.to_string())?;
+    }
+    defs.check().map_err(|e| e.to_string())?;
+    Ok(defs)
+}
+
+/// Says what went wrong parsing `text`, and where
+pub fn parse_error_message<T, E: Display>(text: &str, e: ParseError<usize, T, E>) -> String {
+    let column = |i: usize| text[..i].chars().count() + 1;
+    match e {
+        ParseError::User { error } => error.to_string(),
+        ParseError::InvalidToken { location } => {
+            let c = text[location..].chars().next().unwrap_or(' ');
+            format!("`{}` at column {} isn't allowed here", c, column(location))
+        }
+        ParseError::UnrecognizedEOF { .. } => "the input ends too soon".to_owned(),
+        ParseError::UnrecognizedToken { token: (l, _, r), .. } |
+        ParseError::ExtraToken { token: (l, _, r) } => {
+            format!("`{}` at column {} isn't allowed here", &text[l..r], column(l))
+        }
+    }
+}
+
+// #[test]
+// fn parser() {
+//     assert!(expr_parser::parse_Expr("-a^-b").is_err());
+//     assert!(expr_parser::parse_Expr("-a^(-b)").is_ok());
+//     assert!(expr_parser::parse_Expr("a * -b").is_ok());
+//     assert!(expr_parser::parse_Expr("5b").is_ok());
+//     assert!(expr_parser::parse_Expr(
+//         "abs(floor(ceil(exp(ln(sin(cos(tan(sec(csc(cot(arcsin(arccos(arctan(arcsec(\
+//          arccsc(arccot(1)))))))))))))))))"
+//     ).is_ok());
+//     assert!(expr_parser::parse_Equation("x=22").is_ok());
+//     assert!(expr_parser::parse_Equation("x=((22)").is_err());
+//     assert!(expr_parser::parse_Equation("cos(x) + cos(y) = 1/2").is_ok());
+// }
+
+// `my_comp_expr_eval_bench` lives in `benches/eval.rs`
+
+// #[bench]
+// fn plot_bench(b: &mut test::Bencher) {
+//     let surf = cairo::ImageSurface::create(cairo::Format::Rgb24, 500, 500);
+//     let ctx = cairo::Context::new(&surf);
+
+//     let eq = expr_parser::parse_Equation("x^2 + y^2 = 5^2").unwrap();
+//     let mut f = eq.to_diff().compile().bind2("x", "z");
+
+//     b.iter(move || {
+//         // 4.138ms +/- 0.223
+//         marching_squares::marching_squares(&ctx, &mut *f, &(-10.0..10.0), 256, &(-10.0..10.0), 256);
+//     });
+// }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn definitions_are_inlined() {
+        let (f, _) = compile_curve("y = f(x)", "f(t) = t^2 + c\nc = 1").unwrap();
+        assert_eq!(f.eval(2.0, 5.0), 0.0);
+        // a name a function would have is free for a plain definition
+        let (f, _) = compile_curve("y = max + max(x, 0)", "max = 2").unwrap();
+        assert_eq!(f.eval(-1.0, 2.0), 0.0);
+    }
+
+    #[test]
+    fn bad_definitions_are_rejected() {
+        let error = |text: &str| parse_definitions(text).err().expect(text);
+        assert_eq!(error("f(x) = x^2\nf(x) = 2x"), "`f` is defined more than once");
+        assert_eq!(error("a = b\nb = c\nc = a"), "definitions are circular: a -> b -> c -> a");
+        assert_eq!(error("f(a, b) = a*b\nk = f(2)"), "`f` takes 2 arguments but 1 was given");
+        assert_eq!(error("x = 2"), "`x` is a coordinate of the plot, so it can't be defined");
+        assert_eq!(error("e = 3"), "`e` is a built-in constant and can't be redefined");
+        assert_eq!(error("sin(t) = t"), "`sin` is a built-in function and can't be redefined");
+        assert_eq!(error("f(pi) = pi"), "`pi` is a built-in constant and can't be redefined");
+        assert_eq!(error("a = 1\nb = (2"), "definition on line 2: the input ends too soon");
+    }
+
+    #[test]
+    fn parse_errors_say_where() {
+        let error = |text: &str| compile_curve(text, "").err().expect(text);
+        assert_eq!(error("y = 1 + * 2"), "`*` at column 9 isn't allowed here");
+        assert_eq!(error("y = x $ 2"), "`$` at column 7 isn't allowed here");
+        assert_eq!(error("y = sin(x"), "the input ends too soon");
+        assert_eq!(error("y = sin(x, 2)"), "`sin` takes 1 argument but 2 were given");
+    }
+
+    #[test]
+    fn no_curve_where_it_is_undefined() {
+        let eq = compile_curve("y = if(x > 0, x)", "").unwrap();
+        let lines = trace_view(&eq, &(-2.0..2.0), &(-2.0..2.0));
+        assert!(!lines.is_empty());
+        for line in &lines {
+            for &(x, y) in &line.points {
+                assert!(x >= 0.0 && (x - y).abs() < 1e-9, "({}, {}) is off the curve", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn restrictions_clip_the_curve() {
+        let eq = compile_curve("x^2 + y^2 = 1 {x > 0, y < 0.5}", "").unwrap();
+        assert_eq!(eq.0.eval(1.0, 0.0), 0.0);
+        assert_eq!(eq.1.eval(1.0, 0.0), 1.0);
+        assert_eq!(eq.1.eval(-1.0, 0.0), 0.0);
+        assert_eq!(eq.1.eval(0.5, 1.0), 0.0);
+        let lines = trace_view(&eq, &(-2.0..2.0), &(-2.0..2.0));
+        let points: Vec<_> = lines.iter().flat_map(|l| l.points.iter().cloned()).collect();
+        for &(x, y) in &points {
+            assert!(x >= 0.0 && y <= 0.5, "({}, {}) is outside the restrictions", x, y);
+        }
+        // segments are cut where they cross the boundary, not at whole cells
+        let top = points.iter().map(|p| p.1).fold(::std::f64::NEG_INFINITY, f64::max);
+        assert!((top - 0.5).abs() < 1e-9, "the curve stops at y = {}", top);
+    }
+
+    #[test]
+    fn tracing_in_parallel_matches_tracing_in_turn() {
+        let eq = compile_curve("sin(x^2) + cos(3y) = x / 4 {y < 1.5}", "").unwrap();
+        let (xr, yr) = (-3.0..3.5, -2.0..2.0);
+        let never = AtomicBool::new(false);
+        let keys = tiles::covering(&xr, &yr);
+        let in_turn: Vec<_> = keys.iter().map(|&key| trace_tile(&eq, key, tiles::FINE_CELLS, &never).unwrap()).collect();
+        let in_parallel = tiles::par_map(&keys, |&key| trace_tile(&eq, key, tiles::FINE_CELLS, &never)).unwrap();
+        assert!(in_turn.iter().any(|segments| !segments.is_empty()));
+        assert_eq!(in_parallel, in_turn);
+    }
+
+    #[test]
+    fn curves_undefined_over_the_whole_view() {
+        let (xr, yr) = (-1.0..1.0, -1.0..1.0);
+        assert!(undefined_in_view(&compile_curve("y = ln(x - 3)", "").unwrap(), &xr, &yr));
+        assert!(!undefined_in_view(&compile_curve("y = ln(x)", "").unwrap(), &xr, &yr));
+        assert!(!undefined_in_view(&compile_curve("y = ln(x - 3)", "").unwrap(), &(2.0..4.0), &yr));
+
+        assert_eq!(explain_undefined("ln(x - 3) = y", "", 2.0, 0.0).unwrap(),
+                   "failed to evaluate expression: `ln(x - 3)` is undefined at -1");
+        assert_eq!(explain_undefined("y = f(x)", "f(t) = 1 / (t - 2)", 2.0, 0.0).unwrap(),
+                   "failed to evaluate expression: `1 / (x - 2)` divides by zero");
+        assert_eq!(explain_undefined("y = x", "", 2.0, 0.0), None);
+    }
+}
@@ -1,35 +1,126 @@
+#[cfg(feature = "render")]
 use cairo;
+use fnv::FnvHashMap;
 use ndarray::Array2;
+use std::f64;
 use std::ops::Range;
+use std::sync::atomic::{AtomicBool, Ordering};
 
-pub fn marching_squares<F>(ctx: &cairo::Context,
-                           mut f: F,
-                           xr: &Range<f64>,
-                           x_len: usize,
-                           yr: &Range<f64>,
-                           y_len: usize)
-                           -> ()
-    where F: FnMut(f64, f64) -> f64
+pub type Point = (f64, f64);
+pub type Segment = (Point, Point);
+
+/// A run of segments joined end to end
+#[derive(Debug, Clone)]
+pub struct Polyline {
+    pub points: Vec<Point>,
+    /// whether the last point joins back up with the first
+    pub closed: bool,
+}
+
+impl Polyline {
+    /// Splits back up into segments
+    pub fn segments(&self) -> Vec<Segment> {
+        let mut segments: Vec<_> = self.points.windows(2).map(|w| (w[0], w[1])).collect();
+        if self.closed && self.points.len() > 2 {
+            segments.push((self.points[self.points.len() - 1], self.points[0]));
+        }
+        segments
+    }
+}
+
+/// How a curve is stroked
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Style {
+    /// red, green and blue, each from 0 to 1
+    pub color: (f64, f64, f64),
+    /// in pixels
+    pub line_width: f64,
+}
+
+impl Default for Style {
+    fn default() -> Style {
+        Style { color: (1.0, 0.0, 0.0), line_width: 1.5 }
+    }
+}
+
+/// The values of a function sampled at the corners of a grid of cells
+#[derive(Debug, Clone)]
+pub struct Grid {
+    xr: Range<f64>,
+    yr: Range<f64>,
+    vs: Array2<f64>,
+}
+
+impl Grid {
+    /// A grid of `vs.dim()` samples, less one cell each way, over `xr` by
+    /// `yr`, where `vs[(i, j)]` is the sample at the `i`th `x` and `j`th `y`
+    pub fn new(xr: Range<f64>, yr: Range<f64>, vs: Array2<f64>) -> Grid {
+        assert!(vs.dim().0 >= 3 && vs.dim().1 >= 3, "too few cells: {:?}", vs.dim());
+        Grid { xr: xr, yr: yr, vs: vs }
+    }
+}
+
+/// Samples `f` at the corners of `x_len` by `y_len` cells over `xr` by `yr`.
+/// `f` is given a row of points at once, as in `Bound2::eval_batch`.
+///
+/// Gives up and returns `None` as soon as it notices `cancelled` is set.
+pub fn sample<F: ?Sized>(f: &F,
+                         xr: &Range<f64>,
+                         x_len: usize,
+                         yr: &Range<f64>,
+                         y_len: usize,
+                         cancelled: &AtomicBool)
+                         -> Option<Grid>
+    where F: Fn(&[f64], &[f64], &mut [f64])
 {
     assert!(x_len >= 2, "too few x cells: {}", x_len);
     assert!(y_len >= 2, "too few y cells: {}", y_len);
     let x_step = (xr.end - xr.start) / (x_len as f64);
     let y_step = (yr.end - yr.start) / (y_len as f64);
-    let arr = Array2::from_shape_fn((x_len + 1, y_len + 1), |(i, j)| {
-        let x = xr.start + x_step * (i as f64);
-        let y = yr.start + y_step * (j as f64);
-        ((x, y), f(x, y))
-    });
-    // println!("{:6.1?}", arr);
+    let shape = (x_len + 1, y_len + 1);
+    let xs = Array2::from_shape_fn(shape, |(i, _)| xr.start + x_step * (i as f64));
+    let ys = Array2::from_shape_fn(shape, |(_, j)| yr.start + y_step * (j as f64));
+    let mut vs = Array2::from_elem(shape, f64::NAN);
 
+    // a row at a time, so cancelling doesn't wait for the whole grid
+    for ((xs, ys), mut vs) in xs.outer_iter().zip(ys.outer_iter()).zip(vs.outer_iter_mut()) {
+        if cancelled.load(Ordering::Relaxed) {
+            return None;
+        }
+        f(xs.as_slice().unwrap(), ys.as_slice().unwrap(), vs.as_slice_mut().unwrap());
+    }
+    Some(Grid { xr: xr.clone(), yr: yr.clone(), vs: vs })
+}
+
+/// Traces the zero set sampled in `grid`, keeping only the parts where
+/// `keep` holds.
+///
+/// Gives up and returns `None` as soon as it notices `cancelled` is set.
+pub fn marching_squares<G>(grid: &Grid, mut keep: G, cancelled: &AtomicBool) -> Option<Vec<Segment>>
+    where G: FnMut(f64, f64) -> bool
+{
+    let segments = contour(grid);
+    let mut clipped = Vec::with_capacity(segments.len());
+    for seg in segments {
+        if cancelled.load(Ordering::Relaxed) {
+            return None;
+        }
+        clipped.extend(clip(&mut keep, seg));
+    }
+    Some(clipped)
+}
+
+/// Draws the axes and `segments` onto `ctx` in `style`, scaled so that `xr`
+/// by `yr` fills its clip region
+#[cfg(feature = "render")]
+pub fn draw<'a, I>(ctx: &cairo::Context, segments: I, style: &Style, xr: &Range<f64>, yr: &Range<f64>)
+    where I: IntoIterator<Item = &'a Segment>
+{
     ctx.set_antialias(cairo::Antialias::Best);
     ctx.set_fill_rule(cairo::FillRule::Winding);
-    let (ox, oy, ex, ey) = ctx.clip_extents().expect("no clip");
-
-    // canvas coordinates to plot coordinates
-    ctx.scale((ex - ox) / (xr.end - xr.start),
-              -(ey - oy) / (yr.end - yr.start));
-    ctx.translate(-xr.start, -yr.end);
+    ctx.save();
+    let unscaled = to_plot(ctx, xr, yr);
 
     // clear
     ctx.set_source_rgb(1.0, 1.0, 1.0);
@@ -41,13 +132,76 @@ pub fn marching_squares<F>(ctx: &cairo::Context,
     ctx.move_to(0.0, yr.start);
     ctx.line_to(0.0, yr.end);
     ctx.save();
-    ctx.identity_matrix();
+    ctx.set_matrix(unscaled);
     ctx.set_source_rgb(0.0, 0.0, 0.0);
     ctx.set_line_width(1.0);
     ctx.stroke();
     ctx.restore();
 
+    stroke_in_plot(ctx, unscaled, segments, style);
+    ctx.restore();
+}
+
+/// Draws `segments` onto `ctx` in `style` over whatever's there already,
+/// scaled so that `xr` by `yr` fills its clip region
+#[cfg(feature = "render")]
+pub fn stroke<'a, I>(ctx: &cairo::Context, segments: I, style: &Style, xr: &Range<f64>, yr: &Range<f64>)
+    where I: IntoIterator<Item = &'a Segment>
+{
+    ctx.save();
+    let unscaled = to_plot(ctx, xr, yr);
+    stroke_in_plot(ctx, unscaled, segments, style);
+    ctx.restore();
+}
+
+/// Scales `ctx` from canvas coordinates to plot coordinates, so that `xr` by
+/// `yr` fills its clip region, and returns the matrix from before
+#[cfg(feature = "render")]
+pub fn to_plot(ctx: &cairo::Context, xr: &Range<f64>, yr: &Range<f64>) -> cairo::Matrix {
+    let (ox, oy, ex, ey) = ctx.clip_extents().expect("no clip");
+    // line widths are in the caller's units, not plot units
+    let unscaled = ctx.matrix();
+    ctx.scale((ex - ox) / (xr.end - xr.start),
+              -(ey - oy) / (yr.end - yr.start));
+    ctx.translate(-xr.start, -yr.end);
+    unscaled
+}
+
+#[cfg(feature = "render")]
+fn stroke_in_plot<'a, I>(ctx: &cairo::Context, unscaled: cairo::Matrix, segments: I, style: &Style)
+    where I: IntoIterator<Item = &'a Segment>
+{
+    for &(p0, p1) in segments {
+        ctx.move_to(p0.0, p0.1);
+        ctx.line_to(p1.0, p1.1);
+    }
+
+    ctx.save();
+    ctx.set_matrix(unscaled);
+    ctx.set_source_rgb(style.color.0, style.color.1, style.color.2);
+    ctx.set_line_width(style.line_width);
+    ctx.stroke();
+    ctx.restore();
+}
+
+/// Finds line segments approximating the zero set sampled in `grid`
+fn contour(grid: &Grid) -> Vec<Segment> {
+    let Grid { ref xr, ref yr, ref vs } = *grid;
+    let (x_len, y_len) = (vs.dim().0 - 1, vs.dim().1 - 1);
+    let x_step = (xr.end - xr.start) / (x_len as f64);
+    let y_step = (yr.end - yr.start) / (y_len as f64);
+    let point = |(i, j): (usize, usize)| {
+        (xr.start + x_step * (i as f64), yr.start + y_step * (j as f64))
+    };
+    let arr = Array2::from_shape_fn(vs.dim(), |ij| (point(ij), vs[ij]));
+    // println!("{:6.1?}", arr);
+
+    let mut segments = Vec::new();
     for square in arr.windows((2, 2)) {
+        // the curve is undefined here, not crossing zero
+        if square.iter().any(|e| e.1.is_nan()) {
+            continue;
+        }
         let k = square.iter().fold(0_u8, |acc, &e| (acc << 1) | (e.1.is_sign_positive() as u8));
         // [[8, 4],
         //  [2, 1]]
@@ -64,55 +218,136 @@ pub fn marching_squares<F>(ctx: &cairo::Context,
             0b1111 | 0b0000 => {}
             // one corner
             0b1000 | 0b0111 => {
-                ctx.move_to(x0 + x_step * inv_lerp_0(v00, v10), y0);
-                ctx.line_to(x0, y0 + y_step * inv_lerp_0(v00, v01));
+                segments.push(((x0 + x_step * inv_lerp_0(v00, v10), y0),
+                               (x0, y0 + y_step * inv_lerp_0(v00, v01))));
             }
             0b0100 | 0b1011 => {
-                ctx.move_to(x0 + x_step * inv_lerp_0(v01, v11), y1);
-                ctx.line_to(x0, y0 + y_step * inv_lerp_0(v00, v01));
+                segments.push(((x0 + x_step * inv_lerp_0(v01, v11), y1),
+                               (x0, y0 + y_step * inv_lerp_0(v00, v01))));
             }
             0b0010 | 0b1101 => {
-                ctx.move_to(x0 + x_step * inv_lerp_0(v00, v10), y0);
-                ctx.line_to(x1, y0 + y_step * inv_lerp_0(v10, v11));
+                segments.push(((x0 + x_step * inv_lerp_0(v00, v10), y0),
+                               (x1, y0 + y_step * inv_lerp_0(v10, v11))));
             }
             0b0001 | 0b1110 => {
-                ctx.move_to(x0 + x_step * inv_lerp_0(v01, v11), y1);
-                ctx.line_to(x1, y0 + y_step * inv_lerp_0(v10, v11));
+                segments.push(((x0 + x_step * inv_lerp_0(v01, v11), y1),
+                               (x1, y0 + y_step * inv_lerp_0(v10, v11))));
             }
             // line
             0b1010 | 0b0101 => {
-                ctx.move_to(x0, y0 + y_step * inv_lerp_0(v00, v01));
-                ctx.line_to(x1, y0 + y_step * inv_lerp_0(v10, v11));
+                segments.push(((x0, y0 + y_step * inv_lerp_0(v00, v01)),
+                               (x1, y0 + y_step * inv_lerp_0(v10, v11))));
             }
             0b0011 | 0b1100 => {
-                ctx.move_to(x0 + x_step * inv_lerp_0(v00, v10), y0);
-                ctx.line_to(x0 + x_step * inv_lerp_0(v01, v11), y1);
+                segments.push(((x0 + x_step * inv_lerp_0(v00, v10), y0),
+                               (x0 + x_step * inv_lerp_0(v01, v11), y1)));
             }
             // saddle
             0b0110 | 0b1001 => {
                 let center = (v00 + v01 + v10 + v11) / 4.0;
                 if (center.is_sign_positive()) == (v00.is_sign_positive()) {
-                    ctx.move_to(x0 + x_step * inv_lerp_0(v01, v11), y1);
-                    ctx.line_to(x0, y0 + y_step * inv_lerp_0(v00, v01));
-                    ctx.move_to(x0 + x_step * inv_lerp_0(v00, v10), y0);
-                    ctx.line_to(x1, y0 + y_step * inv_lerp_0(v10, v11));
+                    segments.push(((x0 + x_step * inv_lerp_0(v01, v11), y1),
+                                   (x0, y0 + y_step * inv_lerp_0(v00, v01))));
+                    segments.push(((x0 + x_step * inv_lerp_0(v00, v10), y0),
+                                   (x1, y0 + y_step * inv_lerp_0(v10, v11))));
                 } else {
-                    ctx.move_to(x0 + x_step * inv_lerp_0(v00, v10), y0);
-                    ctx.line_to(x0, y0 + y_step * inv_lerp_0(v00, v01));
-                    ctx.move_to(x0 + x_step * inv_lerp_0(v01, v11), y1);
-                    ctx.line_to(x1, y0 + y_step * inv_lerp_0(v10, v11));
+                    segments.push(((x0 + x_step * inv_lerp_0(v00, v10), y0),
+                                   (x0, y0 + y_step * inv_lerp_0(v00, v01))));
+                    segments.push(((x0 + x_step * inv_lerp_0(v01, v11), y1),
+                                   (x1, y0 + y_step * inv_lerp_0(v10, v11))));
                 }
             }
             _ => unreachable!(),
         }
     }
+    segments
+}
 
-    ctx.save();
-    ctx.identity_matrix();
-    ctx.set_source_rgb(1.0, 0.0, 0.0);
-    ctx.set_line_width(1.5);
-    ctx.stroke();
-    ctx.restore();
+/// Joins up segments that share endpoints into as few polylines as possible.
+///
+/// Neighbouring cells, and tiles, find exactly the same point where the curve
+/// crosses the edge between them, so matching is exact.
+pub fn polylines(segments: &[Segment]) -> Vec<Polyline> {
+    let key = |p: Point| (p.0.to_bits(), p.1.to_bits());
+    let segments: Vec<_> = segments.iter().cloned().filter(|s| key(s.0) != key(s.1)).collect();
+    let mut ends = FnvHashMap::default();
+    for (i, s) in segments.iter().enumerate() {
+        ends.entry(key(s.0)).or_insert_with(Vec::new).push(i);
+        ends.entry(key(s.1)).or_insert_with(Vec::new).push(i);
+    }
+    let mut used = vec![false; segments.len()];
+    // follows an unused segment from `p`, to its other end
+    let next = |p: Point, used: &mut Vec<bool>| {
+        let i = ends.get(&key(p))?.iter().cloned().find(|&i| !used[i])?;
+        used[i] = true;
+        let (a, b) = segments[i];
+        Some(if key(a) == key(p) { b } else { a })
+    };
+
+    let mut lines = Vec::new();
+    for i in 0..segments.len() {
+        if used[i] {
+            continue;
+        }
+        used[i] = true;
+        let (first, second) = segments[i];
+        let mut points = vec![first, second];
+        let mut closed = false;
+        while let Some(p) = next(*points.last().unwrap(), &mut used) {
+            if key(p) == key(first) {
+                closed = true;
+                break;
+            }
+            points.push(p);
+        }
+        if !closed {
+            // it might carry on the other way too
+            let mut before = Vec::new();
+            while let Some(p) = next(*before.last().unwrap_or(&first), &mut used) {
+                before.push(p);
+            }
+            before.reverse();
+            before.extend(points);
+            points = before;
+        }
+        lines.push(Polyline { points: points, closed: closed });
+    }
+    lines
+}
+
+/// Cuts `seg` down to the part where `keep` holds, assuming that changes at
+/// most once along it
+fn clip<G>(keep: &mut G, seg: Segment) -> Option<Segment>
+    where G: FnMut(f64, f64) -> bool
+{
+    let (p0, p1) = seg;
+    match (keep(p0.0, p0.1), keep(p1.0, p1.1)) {
+        (true, true) => Some(seg),
+        (false, false) => None,
+        (true, false) => Some((p0, boundary(keep, p0, p1))),
+        (false, true) => Some((boundary(keep, p1, p0), p1)),
+    }
+}
+
+/// Bisects the segment from `inside` to `outside` for where `keep` stops
+/// holding
+fn boundary<G>(keep: &mut G, inside: Point, outside: Point) -> Point
+    where G: FnMut(f64, f64) -> bool
+{
+    let at = |t: f64| {
+        (inside.0 + t * (outside.0 - inside.0), inside.1 + t * (outside.1 - inside.1))
+    };
+    let (mut lo, mut hi) = (0.0, 1.0);
+    for _ in 0..32 {
+        let mid = (lo + hi) / 2.0;
+        let p = at(mid);
+        if keep(p.0, p.1) {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+    at(lo)
 }
 
 /// Returns the value `t` such that `a + t * (b - a) = 0`
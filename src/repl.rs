@@ -0,0 +1,174 @@
+use expr::{self, Expr};
+use expr_parser;
+use parse_error_message;
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+
+const HELP: &str = "\
+Enter an expression to evaluate it, or `name = value` to set a variable.
+The last result is kept as `ans`.
+  :simplify EXPR   show EXPR with constants folded and identities dropped
+  :diff VAR EXPR   show the derivative of EXPR with respect to VAR
+  :latex EXPR      show EXPR written as LaTeX
+  :vars            list the variables that are set
+  :checked on|off  report functions used outside their domain instead of
+                   giving NaN or infinity (on by default)
+  :help            show this message
+  :quit            leave (as does the end of the input)";
+
+/// Evaluates each line of `input` and writes the result to `output`, keeping
+/// the variables assigned along the way. If `prompt` is set, a prompt is
+/// written before reading each line.
+pub fn run<R: BufRead, W: Write>(input: R, mut output: W, prompt: bool) -> io::Result<()> {
+    let mut vars = HashMap::new();
+    let mut checked = true;
+    let mut lines = input.lines();
+    loop {
+        if prompt {
+            write!(output, "> ")?;
+            output.flush()?;
+        }
+        let line = match lines.next() {
+            Some(line) => line?,
+            None => break,
+        };
+        let line = line.trim();
+        match line {
+            "" => continue,
+            ":quit" | ":q" => break,
+            _ => {}
+        }
+        match respond(&mut vars, &mut checked, line) {
+            Ok(reply) => writeln!(output, "{}", reply)?,
+            Err(e) => writeln!(output, "error: {}", e)?,
+        }
+    }
+    Ok(())
+}
+
+fn respond(vars: &mut HashMap<String, f64>, checked: &mut bool, line: &str) -> Result<String, String> {
+    if line.starts_with(':') {
+        let (cmd, rest) = split_word(&line[1..]);
+        return match cmd {
+            "simplify" | "s" => Ok(parse(rest)?.simplify().to_string()),
+            "diff" | "d" => {
+                let (var, rest) = split_word(rest);
+                if var.is_empty() {
+                    return Err("usage: :diff VAR EXPR".to_owned());
+                }
+                parse(rest)?.derivative(var).map(|d| d.to_string()).map_err(|e| e.to_string())
+            }
+            "latex" | "l" => Ok(parse(rest)?.to_latex()),
+            "vars" => {
+                let mut names: Vec<_> = vars.iter().collect();
+                names.sort_by(|a, b| a.0.cmp(b.0));
+                Ok(names.iter().map(|&(n, v)| format!("{} = {}", n, v)).collect::<Vec<_>>().join("\n"))
+            }
+            "checked" => {
+                match rest {
+                    "on" => *checked = true,
+                    "off" => *checked = false,
+                    "" => {}
+                    _ => return Err("usage: :checked on|off".to_owned()),
+                }
+                Ok(format!("checked evaluation is {}", if *checked { "on" } else { "off" }))
+            }
+            "help" | "h" => Ok(HELP.to_owned()),
+            _ => Err(format!("unknown command `:{}`; try `:help`", cmd)),
+        };
+    }
+    if is_assignment(line) {
+        let def = expr_parser::DefinitionParser::new().parse(line).map_err(|e| parse_error_message(line, e))?;
+        if !def.params.is_empty() {
+            return Err("only variables can be assigned here, not functions".to_owned());
+        }
+        if expr::constant(&def.name).is_some() {
+            return Err(expr::DefinitionError::Builtin(def.name).to_string());
+        }
+        let v = eval(vars, *checked, &def.body)?;
+        vars.insert(def.name.clone(), v);
+        Ok(format!("{} = {}", def.name, v))
+    } else {
+        let v = eval(vars, *checked, &parse(line)?)?;
+        vars.insert("ans".to_owned(), v);
+        Ok(v.to_string())
+    }
+}
+
+fn parse(text: &str) -> Result<Expr, String> {
+    expr_parser::ExprParser::new().parse(text).map_err(|e| parse_error_message(text, e))
+}
+
+fn eval(vars: &HashMap<String, f64>, checked: bool, e: &Expr) -> Result<f64, String> {
+    let env: HashMap<&str, f64> = vars.iter().map(|(n, &v)| (n.as_str(), v)).collect();
+    let v = if checked { e.eval_checked(&env) } else { e.eval(&env) };
+    v.map_err(|e| e.to_string())
+}
+
+/// Whether `line` looks like `name = ...` or `name(params) = ...`, as
+/// opposed to an expression that happens to contain `<=` or `>=`
+fn is_assignment(line: &str) -> bool {
+    match line.find('=') {
+        Some(i) => {
+            let lhs = line[..i].trim();
+            !lhs.is_empty() &&
+            lhs.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '(' || c == ')' || c == ',' || c == ' ')
+        }
+        None => false,
+    }
+}
+
+/// Splits off the first whitespace-separated word of `s`
+fn split_word(s: &str) -> (&str, &str) {
+    let s = s.trim_start();
+    match s.find(char::is_whitespace) {
+        Some(i) => (&s[..i], s[i..].trim_start()),
+        None => (s, ""),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What `run` writes given `input`, without prompts
+    fn transcript(input: &str) -> String {
+        let mut output = Vec::new();
+        run(input.as_bytes(), &mut output, false).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn variables_are_kept_between_lines() {
+        assert_eq!(transcript("a = 3\nb = a^2 + 1\n\n  a * b  \nans / 2\n:vars\n"),
+                   "a = 3\nb = 10\n30\n15\na = 3\nans = 15\nb = 10\n");
+    }
+
+    #[test]
+    fn commands() {
+        assert_eq!(transcript(":simplify 0*x + 1*y\n:diff x x^3\n:latex x / 2\n:nope\n:diff\n"),
+                   "y\nx^2 * 3\n\\frac{x}{2}\nerror: unknown command `:nope`; try `:help`\n\
+                    error: usage: :diff VAR EXPR\n");
+    }
+
+    #[test]
+    fn errors_are_reported_and_skipped() {
+        assert_eq!(transcript("ln(-1)\n:checked off\nln(-1)\n:checked\nf(t) = t\npi = 3\nq + 1\n1 +\n2\n"),
+                   "error: failed to evaluate expression: `ln(-1)` is undefined at -1\n\
+                    checked evaluation is off\n\
+                    NaN\n\
+                    checked evaluation is off\n\
+                    error: only variables can be assigned here, not functions\n\
+                    error: `pi` is a built-in constant and can't be redefined\n\
+                    error: failed to evaluate expression: unknown variable: q\n\
+                    error: the input ends too soon\n\
+                    2\n");
+    }
+
+    #[test]
+    fn prompts_until_quit() {
+        let mut output = Vec::new();
+        run("1 + 1\n:quit\n2 + 2\n".as_bytes(), &mut output, true).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "> 2\n> ");
+    }
+}
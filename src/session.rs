@@ -0,0 +1,170 @@
+use fields::Kind;
+use marching_squares::Style;
+use serde_json;
+use std::error;
+use std::fmt::{self, Display, Formatter};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::ops::Range;
+use std::path::Path;
+
+/// Everything needed to pick plotting up again where it was left off
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Session {
+    pub curves: Vec<SavedCurve>,
+    /// the contents of the definitions list
+    #[serde(default)]
+    pub definitions: String,
+    pub x_range: Range<f64>,
+    pub y_range: Range<f64>,
+    /// whether to plot as you type
+    #[serde(default)]
+    pub live: bool,
+    /// whether the equation is a function of `z` to colour the plane by
+    #[serde(default)]
+    pub complex: bool,
+    /// whether to trace where the real and imaginary parts of that function
+    /// are zero
+    #[serde(default)]
+    pub zero_sets: bool,
+    /// the field drawn under the curves, if there is one
+    #[serde(default)]
+    pub field: Option<SavedField>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SavedCurve {
+    pub equation: String,
+    #[serde(default)]
+    pub style: Style,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SavedField {
+    pub kind: Kind,
+    /// the first component, or the slope for a slope field
+    pub p: String,
+    #[serde(default)]
+    pub q: String,
+    /// the points that trajectories were traced through
+    #[serde(default)]
+    pub trajectories: Vec<(f64, f64)>,
+}
+
+impl Session {
+    pub fn load(path: &Path) -> Result<Session, SessionError> {
+        let file = File::open(path)?;
+        Ok(serde_json::from_reader(BufReader::new(file))?)
+    }
+    pub fn save(&self, path: &Path) -> Result<(), SessionError> {
+        let mut file = BufWriter::new(File::create(path)?);
+        serde_json::to_writer_pretty(&mut file, self)?;
+        Ok(file.flush()?)
+    }
+}
+
+#[derive(Debug)]
+pub enum SessionError {
+    Io(io::Error),
+    Format(serde_json::Error),
+}
+
+impl From<io::Error> for SessionError {
+    fn from(e: io::Error) -> SessionError {
+        SessionError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for SessionError {
+    fn from(e: serde_json::Error) -> SessionError {
+        SessionError::Format(e)
+    }
+}
+
+impl error::Error for SessionError {
+    fn description(&self) -> &str {
+        match *self {
+            SessionError::Io(_) => "couldn't read or write session file",
+            SessionError::Format(_) => "invalid session file",
+        }
+    }
+}
+
+impl Display for SessionError {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            SessionError::Io(ref e) => write!(fmt, "{}", e),
+            SessionError::Format(ref e) => write!(fmt, "not a valid session file: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    #[test]
+    fn sessions_come_back_as_saved() {
+        let style = Style { color: (0.2, 0.4, 0.6), line_width: 2.5 };
+        let session = Session {
+            curves: vec![SavedCurve { equation: "y = f(x)".to_owned(), style: style },
+                         SavedCurve { equation: "x^2 + y^2 = 1 {x > 0}".to_owned(), style: Style::default() }],
+            definitions: "f(t) = t^2\nc = 3".to_owned(),
+            x_range: -2.5..7.0,
+            y_range: 1.0..1.5,
+            live: true,
+            complex: false,
+            zero_sets: true,
+            field: Some(SavedField {
+                kind: Kind::Slopes,
+                p: "x - y".to_owned(),
+                q: String::new(),
+                trajectories: vec![(0.0, 1.0), (-1.5, 0.25)],
+            }),
+        };
+        let path = env::temp_dir().join(format!("plotany-session-{}.json", process::id()));
+        session.save(&path).unwrap();
+        let loaded = Session::load(&path);
+        fs::remove_file(&path).unwrap();
+        let loaded = loaded.unwrap();
+
+        assert_eq!(loaded.curves.len(), 2);
+        for (a, b) in loaded.curves.iter().zip(&session.curves) {
+            assert_eq!((&a.equation, a.style), (&b.equation, b.style));
+        }
+        assert_eq!(loaded.definitions, session.definitions);
+        assert_eq!((loaded.x_range, loaded.y_range), (session.x_range, session.y_range));
+        assert_eq!((loaded.live, loaded.complex, loaded.zero_sets), (true, false, true));
+        let (a, b) = (loaded.field.unwrap(), session.field.unwrap());
+        assert_eq!((a.kind, a.p, a.q, a.trajectories), (b.kind, b.p, b.q, b.trajectories));
+    }
+
+    #[test]
+    fn missing_options_are_left_off() {
+        let text = r#"{"curves": [{"equation": "y = x"}], "x_range": {"start": -1, "end": 1},
+                       "y_range": {"start": 0, "end": 2}}"#;
+        let session: Session = serde_json::from_str(text).unwrap();
+        assert_eq!(session.curves[0].style, Style::default());
+        assert_eq!(session.definitions, "");
+        assert!(!session.live && !session.complex && !session.zero_sets && session.field.is_none());
+    }
+
+    #[test]
+    fn sessions_that_cant_be_read() {
+        let path = env::temp_dir().join(format!("plotany-not-a-session-{}.json", process::id()));
+        fs::write(&path, "[1, 2, 3]").unwrap();
+        let garbled = Session::load(&path);
+        fs::remove_file(&path).unwrap();
+        match garbled {
+            Err(e @ SessionError::Format(_)) => assert!(e.to_string().starts_with("not a valid session file: ")),
+            r => panic!("loaded {:?}", r),
+        }
+        match Session::load(&path) {
+            Err(SessionError::Io(_)) => {}
+            r => panic!("loaded {:?}", r),
+        }
+    }
+}
@@ -0,0 +1,152 @@
+use marching_squares::{self, Segment};
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+
+/// How many tiles should span the view along each axis, give or take a factor
+/// of two
+const TILES_ACROSS: f64 = 4.0;
+
+/// How many cells across a tile is sampled on in the quick first pass
+pub const COARSE_CELLS: usize = 16;
+/// How many cells across a tile is sampled on once it's refined
+pub const FINE_CELLS: usize = 64;
+
+/// Identifies a tile of the plane: tile `(x, y)` at zoom `(zx, zy)` covers
+/// `x * 2^zx .. (x + 1) * 2^zx` by `y * 2^zy .. (y + 1) * 2^zy`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TileKey {
+    pub zx: i32,
+    pub zy: i32,
+    pub x: i64,
+    pub y: i64,
+}
+
+impl TileKey {
+    pub fn x_range(&self) -> Range<f64> {
+        span(self.zx, self.x)
+    }
+    pub fn y_range(&self) -> Range<f64> {
+        span(self.zy, self.y)
+    }
+}
+
+/// Tile `i` along an axis at zoom `z`
+fn span(z: i32, i: i64) -> Range<f64> {
+    let size = 2_f64.powi(z);
+    (i as f64) * size..((i + 1) as f64) * size
+}
+
+/// The zoom level at which a few tiles span `r`
+fn zoom(r: &Range<f64>) -> i32 {
+    ((r.end - r.start).abs() / TILES_ACROSS).log2().floor() as i32
+}
+
+/// Finds the tiles covering `xr` by `yr`, nearest the middle first
+pub fn covering(xr: &Range<f64>, yr: &Range<f64>) -> Vec<TileKey> {
+    let (zx, zy) = (zoom(xr), zoom(yr));
+    let indices = |z: i32, r: &Range<f64>| {
+        let size = 2_f64.powi(z);
+        (r.start / size).floor() as i64..(r.end / size).ceil() as i64
+    };
+    let mut keys = Vec::new();
+    for x in indices(zx, xr) {
+        for y in indices(zy, yr) {
+            keys.push(TileKey { zx: zx, zy: zy, x: x, y: y });
+        }
+    }
+    let mid = ((xr.start + xr.end) / 2.0, (yr.start + yr.end) / 2.0);
+    let dist = |k: &TileKey| {
+        let (kx, ky) = (k.x_range(), k.y_range());
+        let dx = ((kx.start + kx.end) / 2.0 - mid.0) / (kx.end - kx.start);
+        let dy = ((ky.start + ky.end) / 2.0 - mid.1) / (ky.end - ky.start);
+        dx * dx + dy * dy
+    };
+    keys.sort_by(|a, b| dist(a).partial_cmp(&dist(b)).unwrap());
+    keys
+}
+
+/// Traces the zero set of `f` over tile `key` on a grid of `cells` by `cells`
+/// cells, keeping only the parts where `keep` holds.
+///
+/// Tile sizes and cell counts are powers of two, so neighbouring tiles sample
+/// exactly the same points along their shared edge and their curves meet.
+pub fn trace<F: ?Sized, G>(f: &F, keep: G, key: TileKey, cells: usize, cancelled: &AtomicBool)
+                           -> Option<Vec<Segment>>
+    where F: Fn(&[f64], &[f64], &mut [f64]),
+          G: FnMut(f64, f64) -> bool
+{
+    let grid = marching_squares::sample(f, &key.x_range(), cells, &key.y_range(), cells, cancelled)?;
+    marching_squares::marching_squares(&grid, keep, cancelled)
+}
+
+/// Calls `trace` with each of `work`, such as tiles to trace, on one thread
+/// per core. Each thread takes the next one still to do in turn, so they're
+/// done about in order. Gives the results in the same order as `work`, or
+/// `None` as soon as any is `None`.
+pub fn par_map<T, U, F>(work: &[T], trace: F) -> Option<Vec<U>>
+    where T: Sync,
+          U: Send,
+          F: Fn(&T) -> Option<U> + Sync
+{
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let threads = thread::available_parallelism().map_or(1, |n| n.get()).min(work.len());
+    let mut done: Vec<(usize, U)> = Vec::with_capacity(work.len());
+    thread::scope(|s| {
+        let workers: Vec<_> = (0..threads).map(|_| {
+            s.spawn(|| {
+                let mut done = Vec::new();
+                while !failed.load(Ordering::Relaxed) {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    let w = match work.get(i) {
+                        Some(w) => w,
+                        None => break,
+                    };
+                    match trace(w) {
+                        Some(u) => done.push((i, u)),
+                        None => failed.store(true, Ordering::Relaxed),
+                    }
+                }
+                done
+            })
+        }).collect();
+        for w in workers {
+            done.extend(w.join().expect("tracing panicked"));
+        }
+    });
+    if failed.load(Ordering::Relaxed) {
+        return None;
+    }
+    done.sort_by_key(|d| d.0);
+    Some(done.into_iter().map(|d| d.1).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tiles_cover_the_view_middle_first() {
+        let (xr, yr) = (-3.0..5.0, 0.5..2.5);
+        let keys = covering(&xr, &yr);
+        let (x0, y0) = (keys[0].x_range(), keys[0].y_range());
+        assert!(x0.start <= 1.0 && 1.0 <= x0.end && y0.start <= 1.5 && 1.5 <= y0.end, "{:?}", keys[0]);
+        let covers = |r: Range<f64>, v: f64| r.start <= v && v <= r.end;
+        for i in 0..17 {
+            for j in 0..17 {
+                let p = (xr.start + 0.5 * f64::from(i), yr.start + 0.125 * f64::from(j));
+                assert!(keys.iter().any(|k| covers(k.x_range(), p.0) && covers(k.y_range(), p.1)),
+                        "no tile covers {:?}", p);
+            }
+        }
+    }
+
+    #[test]
+    fn par_map_keeps_the_order() {
+        let work: Vec<u64> = (0..1000).collect();
+        assert_eq!(par_map(&work, |&w| Some(w * w)), Some(work.iter().map(|w| w * w).collect()));
+        assert_eq!(par_map(&work, |&w| if w == 500 { None } else { Some(w) }), None);
+        assert_eq!(par_map(&[] as &[u64], |&w| Some(w)), Some(Vec::new()));
+    }
+}
@@ -0,0 +1,206 @@
+#[cfg(feature = "render")]
+use cairo;
+use expr::{ComplexExpr, Scratch};
+use intersections;
+use marching_squares::{self, Grid, Point, Segment, Style};
+use ndarray::Array2;
+use num_complex::Complex64;
+#[cfg(feature = "render")]
+use std::f64::consts;
+use std::f64;
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tiles::{self, TileKey};
+
+/// How the zero set of the real part is drawn
+pub const RE_STYLE: Style = Style { color: (0.0, 0.35, 0.8), line_width: 1.5 };
+/// How the zero set of the imaginary part is drawn, which is also dashed
+pub const IM_STYLE: Style = Style { color: (0.9, 0.45, 0.0), line_width: 1.5 };
+
+/// Where the real and imaginary parts of a complex function are zero, and
+/// where both are, which are its roots
+#[derive(Debug, Clone, Default)]
+pub struct ZeroSets {
+    pub re: Vec<Segment>,
+    pub im: Vec<Segment>,
+    pub roots: Vec<Point>,
+}
+
+/// Traces where the real and imaginary parts of `f` are zero over `xr` by
+/// `yr`, and finds its roots there
+pub fn trace_view(f: &ComplexExpr, xr: &Range<f64>, yr: &Range<f64>) -> ZeroSets {
+    let keys = tiles::covering(xr, yr);
+    let never = AtomicBool::new(false);
+    let traced = tiles::par_map(&keys, |&key| trace_tile(f, key, tiles::FINE_CELLS, &never)).unwrap();
+    match keys.first() {
+        Some(&key) => merge(&traced, key),
+        None => ZeroSets::default(),
+    }
+}
+
+/// The zero sets of tiles the size of `key` all together, counting once the
+/// roots found in both tiles either side of an edge
+pub fn merge<'a, I>(traced: I, key: TileKey) -> ZeroSets
+    where I: IntoIterator<Item = &'a ZeroSets>
+{
+    let mut sets = ZeroSets::default();
+    for t in traced {
+        sets.re.extend_from_slice(&t.re);
+        sets.im.extend_from_slice(&t.im);
+        sets.roots.extend_from_slice(&t.roots);
+    }
+    let (kx, ky) = (key.x_range(), key.y_range());
+    let cells = tiles::FINE_CELLS as f64;
+    intersections::dedup(&mut sets.roots, (kx.end - kx.start) / cells / 2.0, (ky.end - ky.start) / cells / 2.0);
+    sets
+}
+
+/// Traces where the real and imaginary parts of `f` are zero over tile `key`
+/// on a grid of `cells` by `cells` cells, both from the same samples, and
+/// finds where they cross.
+///
+/// Gives up and returns `None` as soon as it notices `cancelled` is set.
+pub fn trace_tile(f: &ComplexExpr, key: TileKey, cells: usize, cancelled: &AtomicBool) -> Option<ZeroSets> {
+    let (xr, yr) = (key.x_range(), key.y_range());
+    let x_step = (xr.end - xr.start) / (cells as f64);
+    let y_step = (yr.end - yr.start) / (cells as f64);
+    let mut ws = Array2::from_elem((cells + 1, cells + 1), Complex64::new(f64::NAN, f64::NAN));
+    let mut zs = Vec::with_capacity(cells + 1);
+    for (i, mut row) in ws.outer_iter_mut().enumerate() {
+        if cancelled.load(Ordering::Relaxed) {
+            return None;
+        }
+        let x = xr.start + x_step * (i as f64);
+        zs.clear();
+        zs.extend((0..cells + 1).map(|j| Complex64::new(x, yr.start + y_step * (j as f64))));
+        f.eval_batch(&zs, row.as_slice_mut().unwrap());
+    }
+    let always = |_, _| true;
+    let re = Grid::new(xr.clone(), yr.clone(), ws.map(|w| w.re));
+    let re = marching_squares::marching_squares(&re, always, cancelled)?;
+    let im = Grid::new(xr.clone(), yr.clone(), ws.map(|w| w.im));
+    let im = marching_squares::marching_squares(&im, always, cancelled)?;
+
+    let mut roots = Vec::new();
+    let mut scratch = Scratch::new();
+    intersections::pair_nearby(&im, &re, &xr, &yr, cells, |s, _, near| {
+        for r in near {
+            let p = match intersections::crossing(*r, s) {
+                Some(p) => p,
+                None => continue,
+            };
+            let (ci, cj) = intersections::cell_of(p, &xr, &yr, cells);
+            let corners = [ws[(ci, cj)], ws[(ci + 1, cj)], ws[(ci, cj + 1)], ws[(ci + 1, cj + 1)]];
+            let bounds = (xr.start + x_step * (ci as f64 - 1.0)..xr.start + x_step * (ci as f64 + 2.0),
+                          yr.start + y_step * (cj as f64 - 1.0)..yr.start + y_step * (cj as f64 + 2.0));
+            if let Some(root) = refine(f, &mut scratch, p, &corners, &bounds) {
+                roots.push(root);
+            }
+        }
+    });
+    intersections::dedup(&mut roots, x_step / 2.0, y_step / 2.0);
+    Some(ZeroSets { re: re, im: im, roots: roots })
+}
+
+/// Polishes `p`, where the zero sets cross within the cell with values
+/// `corners`, with a few steps of Newton's method, staying within `bounds`.
+/// Both zero sets also cross at poles, where `f` is much bigger than at the
+/// corners rather than smaller, so those are left out.
+fn refine(f: &ComplexExpr,
+          scratch: &mut Scratch<Complex64>,
+          p: Point,
+          corners: &[Complex64],
+          bounds: &(Range<f64>, Range<f64>))
+          -> Option<Point> {
+    let h = (bounds.0.end - bounds.0.start) * 1e-6;
+    let mut f = |z: Complex64| f.eval_with(scratch, z);
+    let start = Complex64::new(p.0, p.1);
+    let mut z = start;
+    for _ in 0..8 {
+        let w = f(z);
+        if w.norm() == 0.0 {
+            break;
+        }
+        // `f` is usually analytic, so the derivative in any direction will do
+        let dw = (f(z + h) - w) / h;
+        let next = z - w / dw;
+        if !(bounds.0.contains(&next.re) && bounds.1.contains(&next.im)) {
+            // Newton's method is getting nowhere, so go with where the
+            // segments cross
+            z = start;
+            break;
+        }
+        z = next;
+    }
+    if f(z).norm() > f(start).norm() {
+        z = start;
+    }
+    let w = f(z).norm();
+    let least = corners.iter().map(|c| c.norm()).fold(f64::INFINITY, f64::min);
+    let most = corners.iter().map(|c| c.norm()).fold(0.0, f64::max);
+    if w <= least || w <= most * 1e-9 {
+        Some((z.re, z.im))
+    } else {
+        None
+    }
+}
+
+/// Draws `sets` onto `ctx` over whatever's there already, scaled so that
+/// `xr` by `yr` fills its clip region: the real part's zero set solid, the
+/// imaginary part's dashed, and the roots as rings
+#[cfg(feature = "render")]
+pub fn draw(ctx: &cairo::Context, sets: &ZeroSets, xr: &Range<f64>, yr: &Range<f64>) -> Result<(), cairo::Error> {
+    marching_squares::stroke(ctx, &sets.re, &RE_STYLE, xr, yr);
+    ctx.save()?;
+    ctx.set_dash(&[6.0, 4.0], 0.0);
+    marching_squares::stroke(ctx, &sets.im, &IM_STYLE, xr, yr);
+    ctx.restore()?;
+
+    ctx.save()?;
+    let unscaled = marching_squares::to_plot(ctx, xr, yr);
+    let centres: Vec<_> = sets.roots.iter().map(|&(x, y)| ctx.user_to_device(x, y)).collect();
+    ctx.set_matrix(unscaled);
+    for &(dx, dy) in &centres {
+        let (x, y) = ctx.device_to_user(dx, dy)?;
+        ctx.new_sub_path();
+        ctx.arc(x, y, 4.0, 0.0, 2.0 * consts::PI);
+    }
+    ctx.set_source_rgb(1.0, 1.0, 1.0);
+    ctx.fill_preserve()?;
+    ctx.set_source_rgb(0.0, 0.0, 0.0);
+    ctx.set_line_width(1.5);
+    ctx.stroke()?;
+    ctx.restore()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use compile_complex;
+
+    fn roots(function: &str) -> Vec<Point> {
+        let f = compile_complex(function, "").unwrap();
+        let mut roots = trace_view(&f, &(-2.0..2.0), &(-2.0..2.0)).roots;
+        roots.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        roots
+    }
+
+    #[test]
+    fn roots_are_polished() {
+        let found = roots("z^2 + 1");
+        assert_eq!(found.len(), 2, "found {:?}", found);
+        for (&(x, y), &ey) in found.iter().zip(&[-1.0, 1.0]) {
+            assert!(x.abs() < 1e-12 && (y - ey).abs() < 1e-12, "found ({}, {})", x, y);
+        }
+        // a root on the edge between tiles is only counted once
+        assert_eq!(roots("z").len(), 1);
+    }
+
+    #[test]
+    fn poles_are_not_roots() {
+        assert_eq!(roots("1 / z"), []);
+        let found = roots("(z - 1) / (z + 1)");
+        assert_eq!(found.len(), 1, "found {:?}", found);
+        assert!((found[0].0 - 1.0).abs() < 1e-12 && found[0].1.abs() < 1e-12, "found {:?}", found);
+    }
+}